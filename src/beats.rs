@@ -0,0 +1,59 @@
+/// Counts bars and beats across time signature changes. 4/4 is assumed until
+/// the first time signature.
+///
+/// Every time signature starts a new bar at its tick. If it comes in the
+/// middle of a bar, that bar is cut short and counted on its own, so a pickup
+/// written as a short bar with its own time signature is bar 1.
+#[derive(Debug)]
+pub struct BeatGrid {
+    ppqn:      u64,
+    /// Tick and bar number of the last time signature
+    start:     u64,
+    start_bar: u64,
+    /// Ticks per beat
+    beat:      u64,
+    /// Beats per bar
+    beats:     u64,
+}
+
+impl BeatGrid {
+    pub fn new(ppqn: u16) -> Self {
+        let ppqn = ppqn.max(1) as u64;
+        Self {
+            ppqn,
+            start: 0,
+            start_bar: 1,
+            beat: ppqn,
+            beats: 4,
+        }
+    }
+
+    /// Start counting in a time signature of `numerator / 2^denominator` at
+    /// `tick`. Ticks before the last change are not supported
+    pub fn time_signature(&mut self, tick: u64, numerator: u8, denominator: u8) {
+        let elapsed = tick.saturating_sub(self.start);
+        let bars = elapsed.div_ceil(self.bar());
+
+        self.start_bar += bars;
+        self.start = tick;
+        self.beat = (self.ppqn * 4)
+            .checked_shr(denominator as u32)
+            .unwrap_or(0)
+            .max(1);
+        self.beats = numerator.max(1) as u64;
+    }
+
+    /// Bar and beat (both counted from 1) and tick within the beat
+    pub fn position(&self, tick: u64) -> (u64, u64, u64) {
+        let elapsed = tick.saturating_sub(self.start);
+        let in_bar = elapsed % self.bar();
+        (
+            self.start_bar + elapsed / self.bar(),
+            in_bar / self.beat + 1,
+            in_bar % self.beat,
+        )
+    }
+
+    /// Ticks per bar
+    fn bar(&self) -> u64 { self.beat * self.beats }
+}
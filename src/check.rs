@@ -0,0 +1,206 @@
+use midly::{MetaMessage, MidiMessage, TrackEvent, TrackEventKind};
+
+use crate::model::LiveMessage;
+
+/// A channel message data byte with its top bit set.
+///
+/// midly refuses to read such an event and, unless built with `strict`, stops
+/// reading the track there without reporting anything. Everything from this
+/// event on is missing from the output.
+#[derive(Debug, Clone, Copy)]
+pub struct InvalidDataByte {
+    pub track:  usize,
+    /// Index of the broken event in its track
+    pub event:  usize,
+    /// Offset of the byte in the file
+    pub offset: usize,
+    pub status: u8,
+    pub byte:   u8,
+}
+
+/// Find out-of-range data bytes in note, velocity, controller and other
+/// channel message values. Only the first one of every track is reported, as
+/// the rest of the track cannot be read past it.
+pub fn find_invalid_data_bytes(file: &[u8]) -> Vec<InvalidDataByte> {
+    let mut found = Vec::new();
+    let mut pos = 0;
+    let mut track = 0;
+
+    while file.len() - pos >= 8 {
+        let id = &file[pos..pos + 4];
+        let len = u32::from_be_bytes([file[pos + 4], file[pos + 5], file[pos + 6], file[pos + 7]]);
+        let start = pos + 8;
+        // midly uses the rest of the file for truncated chunks
+        let end = start.saturating_add(len as usize).min(file.len());
+        pos = end;
+
+        if id == b"MTrk" {
+            if let Some(invalid) = check_track(&file[start..end], start, track) {
+                found.push(invalid);
+            }
+            track += 1;
+        }
+    }
+
+    found
+}
+
+fn check_track(data: &[u8], base: usize, track: usize) -> Option<InvalidDataByte> {
+    let mut pos = 0;
+    let mut running_status = None;
+    let mut event = 0;
+
+    while pos < data.len() {
+        read_varlen(data, &mut pos)?;
+
+        let mut status = *data.get(pos)?;
+        if status < 0x80 {
+            status = running_status?;
+        } else {
+            pos += 1;
+        }
+
+        match status {
+            0x80..=0xEF => {
+                running_status = Some(status);
+                let len = match status >> 4 {
+                    0xC | 0xD => 1,
+                    _ => 2,
+                };
+                for _ in 0..len {
+                    let byte = *data.get(pos)?;
+                    if byte >= 0x80 {
+                        return Some(InvalidDataByte {
+                            track,
+                            event,
+                            offset: base + pos,
+                            status,
+                            byte,
+                        });
+                    }
+                    pos += 1;
+                }
+            },
+            0xFF => {
+                running_status = None;
+                // meta type
+                pos += 1;
+                let len = read_varlen(data, &mut pos)?;
+                pos = pos.checked_add(len as usize)?;
+            },
+            0xF0 | 0xF7 => {
+                running_status = None;
+                let len = read_varlen(data, &mut pos)?;
+                pos = pos.checked_add(len as usize)?;
+            },
+            // midly gives up on these for other reasons
+            _ => return None,
+        }
+
+        event += 1;
+    }
+
+    None
+}
+
+fn read_varlen(data: &[u8], pos: &mut usize) -> Option<u32> {
+    let mut value = 0u32;
+    for _ in 0..4 {
+        let byte = *data.get(*pos)?;
+        *pos += 1;
+        value = (value << 7) | (byte & 0x7F) as u32;
+        if byte < 0x80 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+/// Check if a decoded event has a value outside of what the SMF specification
+/// allows. Channel messages are always in range once decoded, the data bytes
+/// midly cannot decode are found by [`find_invalid_data_bytes`]. Out of spec
+/// are:
+///
+/// - tempo events of 0 microseconds per quarter note
+/// - time signatures with a numerator or clocks per click of 0, or a
+///   denominator beyond a 64th
+/// - key signatures with more than 7 sharps or flats
+/// - SysEx events with a data byte of 128 or more, other than a final `F7`
+pub fn is_out_of_spec(kind: &TrackEventKind) -> bool {
+    match *kind {
+        TrackEventKind::Meta(MetaMessage::Tempo(tempo)) => tempo == 0,
+        TrackEventKind::Meta(MetaMessage::TimeSignature(numerator, denominator, clocks, _)) => {
+            numerator == 0 || clocks == 0 || denominator > 6
+        },
+        TrackEventKind::Meta(MetaMessage::KeySignature(sharps, _)) => !(-7..=7).contains(&sharps),
+        TrackEventKind::SysEx(data) => match data.split_last() {
+            Some((last, rest)) => rest
+                .iter()
+                .chain((*last != 0xF7).then_some(last))
+                .any(|b| *b >= 0x80),
+            None => false,
+        },
+        _ => false,
+    }
+}
+
+/// A short description of an event for messages, like `controller event on
+/// channel 3` or `track_name meta event`, named like their `type` in the
+/// output
+pub fn describe(kind: &TrackEventKind) -> String {
+    match *kind {
+        TrackEventKind::Midi { channel, message } => {
+            let name = match message {
+                MidiMessage::NoteOff { .. } => "note_off",
+                MidiMessage::NoteOn { .. } => "note_on",
+                MidiMessage::Aftertouch { .. } => "aftertouch",
+                MidiMessage::Controller { .. } => "controller",
+                MidiMessage::ProgramChange { .. } => "program_change",
+                MidiMessage::ChannelAftertouch { .. } => "channel_aftertouch",
+                MidiMessage::PitchBend { .. } => "pitch_bend",
+            };
+            format!("{} event on channel {}", name, channel)
+        },
+        TrackEventKind::SysEx(_) => "sysex event".to_string(),
+        TrackEventKind::Escape(_) => "escape event".to_string(),
+        TrackEventKind::Meta(message) => {
+            let name = match message {
+                MetaMessage::TrackNumber(_) => "track_number",
+                MetaMessage::Text(_) => "text",
+                MetaMessage::Copyright(_) => "copyright",
+                MetaMessage::TrackName(_) => "track_name",
+                MetaMessage::InstrumentName(_) => "instrument_name",
+                MetaMessage::Lyric(_) => "lyric",
+                MetaMessage::Marker(_) => "marker",
+                MetaMessage::CuePoint(_) => "cue_point",
+                MetaMessage::ProgramName(_) => "program_name",
+                MetaMessage::DeviceName(_) => "device_name",
+                MetaMessage::MidiChannel(_) => "midi_channel",
+                MetaMessage::MidiPort(_) => "midi_port",
+                MetaMessage::EndOfTrack => "end_of_track",
+                MetaMessage::Tempo(_) => "tempo",
+                MetaMessage::SmpteOffset(_) => "smpte_offset",
+                MetaMessage::TimeSignature(..) => "time_signature",
+                MetaMessage::KeySignature(..) => "key_signature",
+                MetaMessage::SequencerSpecific(_) => "sequencer_specific",
+                MetaMessage::Unknown(..) => "unknown",
+            };
+            format!("{} meta event", name)
+        },
+    }
+}
+
+/// Count the realtime and system common messages stored in escape events.
+/// The SMF format has no place for them, so some files smuggle them in this way
+pub fn count_live_messages<'smf, I>(tracks: impl IntoIterator<Item = I>) -> usize
+where
+    I: IntoIterator<Item = TrackEvent<'smf>>,
+{
+    tracks
+        .into_iter()
+        .flatten()
+        .filter(|event| {
+            matches!(event.kind, TrackEventKind::Escape(data) if LiveMessage::from_escape(data).is_some())
+        })
+        .count()
+}
@@ -0,0 +1,83 @@
+/// Settings of the metronome click added to the drum channel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClickOptions {
+    /// Drum note played on every beat
+    pub note:     u8,
+    pub velocity: u8,
+}
+
+impl Default for ClickOptions {
+    /// A side stick at velocity 100
+    fn default() -> Self {
+        Self {
+            note:     37,
+            velocity: 100,
+        }
+    }
+}
+
+/// The beat grid of a metronome. Beats follow the denominator of the time
+/// signature in effect (4/4 until the first one), every time signature starts
+/// a new grid at its tick.
+#[derive(Debug)]
+pub struct Metronome {
+    options:     ClickOptions,
+    ppqn:        u64,
+    /// Ticks per beat
+    beat:        u64,
+    next_on:     u64,
+    pending_off: Option<u64>,
+}
+
+impl Metronome {
+    pub fn new(options: ClickOptions, ppqn: u16) -> Self {
+        Self {
+            options,
+            ppqn: ppqn as u64,
+            beat: ppqn.max(1) as u64,
+            next_on: 0,
+            pending_off: None,
+        }
+    }
+
+    pub fn options(&self) -> ClickOptions { self.options }
+
+    /// Start a new grid at `tick` with the beat length of a time signature
+    /// with a denominator of `2^denominator`
+    pub fn time_signature(&mut self, tick: u64, denominator: u8) {
+        self.beat = (self.ppqn * 4)
+            .checked_shr(denominator as u32)
+            .unwrap_or(0)
+            .max(1);
+        self.next_on = tick;
+    }
+
+    /// The next click before the tick `until`, as its tick and whether it is
+    /// a note on. Without an end only the pending note off is left, so no
+    /// clicks are played after the file ended
+    pub fn next(&mut self, until: Option<u64>) -> Option<(u64, bool)> {
+        let on = until
+            .filter(|until| self.next_on < *until)
+            .map(|_| self.next_on);
+        let off = self
+            .pending_off
+            .filter(|off| until.is_none_or(|until| *off < until));
+
+        match (on, off) {
+            (Some(on), Some(off)) if on < off => self.play_on(on),
+            (_, Some(off)) => {
+                self.pending_off = None;
+                Some((off, false))
+            },
+            (Some(on), None) => self.play_on(on),
+            (None, None) => None,
+        }
+    }
+
+    /// Clicks last half a beat
+    fn play_on(&mut self, tick: u64) -> Option<(u64, bool)> {
+        self.pending_off = Some(tick + (self.beat / 2).max(1));
+        self.next_on = tick + self.beat;
+        Some((tick, true))
+    }
+}
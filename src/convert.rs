@@ -0,0 +1,199 @@
+use anyhow::Context;
+
+use crate::{
+    model::{self, PlayerResult, SerializeOptions},
+    player::{DeltaViolation, MidiPlayer, PlayerOptions},
+};
+
+/// Events reserved for when the number of events is not known up front
+pub const DEFAULT_CAPACITY: usize = 4096;
+
+/// Settings for [`convert_bytes`]
+#[derive(Debug, Default, Clone)]
+pub struct ConvertOptions {
+    pub player:      PlayerOptions,
+    /// Label written as `source_file`
+    pub source_file: String,
+    /// Time the output was generated at, left out if `None`. There is no clock
+    /// access here, so the caller has to provide it
+    pub generated:   Option<String>,
+    pub pretty:      bool,
+    pub serialize:   SerializeOptions,
+    /// Number of events to reserve room for, see [`collect_track`]
+    pub capacity:    Option<usize>,
+}
+
+/// Convert the bytes of a midi file to json without touching the file system
+/// or stdio, for embedding (like in a browser through wasm)
+pub fn convert_bytes(data: &[u8], options: ConvertOptions) -> anyhow::Result<String> {
+    let smf = midly::Smf::parse(data).context("failed to parse midi file")?;
+    let emitted_meta = options.player.emit_meta || options.player.conductor_only;
+    let player = MidiPlayer::new(&smf, options.player)?;
+    let (track, _) = collect_track(
+        player,
+        options.source_file,
+        options.generated,
+        emitted_meta,
+        options.capacity,
+    );
+
+    let track = options.serialize.wrap(&track);
+    if options.pretty {
+        serde_json::to_string_pretty(&track)
+    } else {
+        serde_json::to_string(&track)
+    }
+    .context("failed to serialize data")
+}
+
+/// Run the player to completion and collect its events.
+///
+/// Room for `capacity` events is reserved up front. By default this is the
+/// number of events the player will process, which includes events that are
+/// not emitted. Lazily parsed tracks do not know their length,
+/// [`DEFAULT_CAPACITY`] is used for them.
+pub fn collect_track(
+    player: MidiPlayer,
+    source_file: String,
+    generated: Option<String>,
+    emitted_meta: bool,
+    capacity: Option<usize>,
+) -> (model::Track, Vec<DeltaViolation>) {
+    collect_track_with(
+        player,
+        source_file,
+        generated,
+        emitted_meta,
+        capacity,
+        |_, _| {},
+    )
+}
+
+/// Like [`collect_track`], but shows every emitted event to `inspect`
+/// together with the player right after it was played
+pub fn collect_track_with(
+    player: MidiPlayer,
+    source_file: String,
+    generated: Option<String>,
+    emitted_meta: bool,
+    capacity: Option<usize>,
+    mut inspect: impl FnMut(&model::Event, &MidiPlayer),
+) -> (model::Track, Vec<DeltaViolation>) {
+    let mut player = player.into_iter();
+    let capacity = capacity.unwrap_or(match player.size_hint() {
+        (0, _) => DEFAULT_CAPACITY,
+        (hint, _) => hint,
+    });
+    let (mut p, mut e, mut ev) = (0, 0, Vec::with_capacity(capacity));
+
+    while let Some(ne) = player.next() {
+        p += 1;
+        if let PlayerResult::Event(v) = ne {
+            e += 1;
+            inspect(&v, player.player());
+            ev.push(v);
+        }
+    }
+
+    let track = model::Track {
+        info:   model::TrackInfo {
+            generated,
+            source_file,
+            source_sha256: None,
+            header: player.player().header().clone(),
+            events_processed: p,
+            events_emitted: e,
+            unknown_events: player.player().unknown_events(),
+            invalid_events: player.player().invalid_events(),
+            emitted_meta,
+            duration: player.player().elapsed(),
+            program_map: None,
+            snapshot: player.player().snapshot(),
+        },
+        events: ev,
+    };
+
+    (track, player.player().violations().to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use midly::{Format, Header, MidiMessage, Smf, Timing, TrackEvent, TrackEventKind};
+
+    fn midi(delta: u32, channel: u8, message: MidiMessage) -> TrackEvent<'static> {
+        TrackEvent {
+            delta: delta.into(),
+            kind:  TrackEventKind::Midi {
+                channel: channel.into(),
+                message,
+            },
+        }
+    }
+
+    /// Two notes on channel 0 and a controller on channel 1
+    fn file() -> Vec<u8> {
+        let on = |delta, key: u8| {
+            midi(delta, 0, MidiMessage::NoteOn {
+                key: key.into(),
+                vel: 100.into(),
+            })
+        };
+        let off = |delta, key: u8| {
+            midi(delta, 0, MidiMessage::NoteOff {
+                key: key.into(),
+                vel: 64.into(),
+            })
+        };
+        let smf = Smf {
+            header: Header::new(Format::SingleTrack, Timing::Metrical(480.into())),
+            tracks: vec![vec![
+                on(0, 60),
+                off(480, 60),
+                on(0, 62),
+                off(480, 62),
+                midi(0, 1, MidiMessage::Controller {
+                    controller: 7.into(),
+                    value:      100.into(),
+                }),
+                TrackEvent {
+                    delta: 0.into(),
+                    kind:  TrackEventKind::Meta(midly::MetaMessage::EndOfTrack),
+                },
+            ]],
+        };
+        let mut bytes = Vec::new();
+        smf.write_std(&mut bytes).unwrap();
+        bytes
+    }
+
+    fn convert(options: PlayerOptions) -> serde_json::Value {
+        let json = convert_bytes(&file(), ConvertOptions {
+            player: options,
+            ..Default::default()
+        })
+        .unwrap();
+        serde_json::from_str(&json).unwrap()
+    }
+
+    #[test]
+    fn counts() {
+        let track = convert(PlayerOptions::default());
+        assert_eq!(track["events_processed"], 6);
+        assert_eq!(track["events_emitted"], 5);
+        assert_eq!(track["events"].as_array().unwrap().len(), 5);
+    }
+
+    #[test]
+    fn counts_with_everything_filtered() {
+        let track = convert(PlayerOptions {
+            channels: Some(vec![15]),
+            ..Default::default()
+        });
+        assert_eq!(track["events_processed"], 6);
+        assert_eq!(track["events_emitted"], 0);
+        assert_eq!(track["events"], serde_json::json!([]));
+        // the time of the last emitted event
+        assert_eq!(track["duration"]["tick"], 0);
+    }
+}
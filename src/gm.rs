@@ -0,0 +1,180 @@
+use std::collections::BTreeMap;
+
+use crate::model::{Event, MidiEvent};
+
+/// The General MIDI channel reserved for percussion
+pub const DRUM_CHANNEL: u8 = 9;
+
+/// General MIDI Level 1 instrument names by program number
+const INSTRUMENTS: [&str; 128] = [
+    "Acoustic Grand Piano",
+    "Bright Acoustic Piano",
+    "Electric Grand Piano",
+    "Honky-tonk Piano",
+    "Electric Piano 1",
+    "Electric Piano 2",
+    "Harpsichord",
+    "Clavi",
+    "Celesta",
+    "Glockenspiel",
+    "Music Box",
+    "Vibraphone",
+    "Marimba",
+    "Xylophone",
+    "Tubular Bells",
+    "Dulcimer",
+    "Drawbar Organ",
+    "Percussive Organ",
+    "Rock Organ",
+    "Church Organ",
+    "Reed Organ",
+    "Accordion",
+    "Harmonica",
+    "Tango Accordion",
+    "Acoustic Guitar (nylon)",
+    "Acoustic Guitar (steel)",
+    "Electric Guitar (jazz)",
+    "Electric Guitar (clean)",
+    "Electric Guitar (muted)",
+    "Overdriven Guitar",
+    "Distortion Guitar",
+    "Guitar Harmonics",
+    "Acoustic Bass",
+    "Electric Bass (finger)",
+    "Electric Bass (pick)",
+    "Fretless Bass",
+    "Slap Bass 1",
+    "Slap Bass 2",
+    "Synth Bass 1",
+    "Synth Bass 2",
+    "Violin",
+    "Viola",
+    "Cello",
+    "Contrabass",
+    "Tremolo Strings",
+    "Pizzicato Strings",
+    "Orchestral Harp",
+    "Timpani",
+    "String Ensemble 1",
+    "String Ensemble 2",
+    "Synth Strings 1",
+    "Synth Strings 2",
+    "Choir Aahs",
+    "Voice Oohs",
+    "Synth Voice",
+    "Orchestra Hit",
+    "Trumpet",
+    "Trombone",
+    "Tuba",
+    "Muted Trumpet",
+    "French Horn",
+    "Brass Section",
+    "Synth Brass 1",
+    "Synth Brass 2",
+    "Soprano Sax",
+    "Alto Sax",
+    "Tenor Sax",
+    "Baritone Sax",
+    "Oboe",
+    "English Horn",
+    "Bassoon",
+    "Clarinet",
+    "Piccolo",
+    "Flute",
+    "Recorder",
+    "Pan Flute",
+    "Blown Bottle",
+    "Shakuhachi",
+    "Whistle",
+    "Ocarina",
+    "Lead 1 (square)",
+    "Lead 2 (sawtooth)",
+    "Lead 3 (calliope)",
+    "Lead 4 (chiff)",
+    "Lead 5 (charang)",
+    "Lead 6 (voice)",
+    "Lead 7 (fifths)",
+    "Lead 8 (bass + lead)",
+    "Pad 1 (new age)",
+    "Pad 2 (warm)",
+    "Pad 3 (polysynth)",
+    "Pad 4 (choir)",
+    "Pad 5 (bowed)",
+    "Pad 6 (metallic)",
+    "Pad 7 (halo)",
+    "Pad 8 (sweep)",
+    "FX 1 (rain)",
+    "FX 2 (soundtrack)",
+    "FX 3 (crystal)",
+    "FX 4 (atmosphere)",
+    "FX 5 (brightness)",
+    "FX 6 (goblins)",
+    "FX 7 (echoes)",
+    "FX 8 (sci-fi)",
+    "Sitar",
+    "Banjo",
+    "Shamisen",
+    "Koto",
+    "Kalimba",
+    "Bag pipe",
+    "Fiddle",
+    "Shanai",
+    "Tinkle Bell",
+    "Agogo",
+    "Steel Drums",
+    "Woodblock",
+    "Taiko Drum",
+    "Melodic Tom",
+    "Synth Drum",
+    "Reverse Cymbal",
+    "Guitar Fret Noise",
+    "Breath Noise",
+    "Seashore",
+    "Bird Tweet",
+    "Telephone Ring",
+    "Helicopter",
+    "Applause",
+    "Gunshot",
+];
+
+/// The General MIDI name of a program on a channel. Programs on the drum
+/// channel select a drum kit instead of an instrument
+pub fn instrument_name(chan: u8, program: u8) -> &'static str {
+    if chan == DRUM_CHANNEL {
+        return "Drum Kit";
+    }
+    INSTRUMENTS[program as usize & 0x7F]
+}
+
+/// The instrument of every channel with channel events, by the last program
+/// change on the channel. `None` for channels without a program change, the
+/// drum channel is always a drum kit
+pub type ProgramMap = BTreeMap<u8, Option<&'static str>>;
+
+/// Build the [`ProgramMap`] of a list of events
+pub fn program_map(events: &[Event]) -> ProgramMap {
+    let mut programs = ProgramMap::new();
+    for event in events {
+        add_to_program_map(&mut programs, event);
+    }
+    programs
+}
+
+/// Account for a single event in a [`ProgramMap`], in playing order
+pub fn add_to_program_map(programs: &mut ProgramMap, event: &Event) {
+    let Event::Midi { data, .. } = event else {
+        return;
+    };
+
+    let chan = data.channel();
+    match *data {
+        MidiEvent::ProgramChange { program, .. } => {
+            programs.insert(chan, Some(instrument_name(chan, program)));
+        },
+        _ => {
+            programs
+                .entry(chan)
+                .or_insert((chan == DRUM_CHANNEL).then_some("Drum Kit"));
+        },
+    }
+}
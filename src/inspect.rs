@@ -0,0 +1,162 @@
+use anyhow::{bail, Context};
+use std::{
+    collections::BTreeMap,
+    io::{BufRead, Write},
+    ops::Range,
+};
+
+use crate::model::{Event, MetaEvent, MidiEvent, Track};
+
+const HELP: &str = "\
+commands (RANGE is FROM:TO in ticks, TO excluded, either side may be left out):
+  events [RANGE]        all events
+  notes [RANGE]         note ons and note offs
+  channel N [RANGE]     channel events on channel N
+  type NAME [RANGE]     events of a type, like note_on, controller, tempo or sysex
+  tempo                 tempo changes
+  channels              channels with their number of events
+  help                  this text
+  quit                  leave";
+
+/// Answer queries about the events of a track read from `input`, one command
+/// per line, until `quit` or the end of the input. Matching events are
+/// written to `output` as one line of json each.
+///
+/// The events need absolute ticks, a track played with delta times gives
+/// wrong results.
+pub fn run(track: &Track, mut input: impl BufRead, mut output: impl Write) -> anyhow::Result<()> {
+    writeln!(
+        output,
+        "{} events, {} ticks. type help for a list of commands",
+        track.events.len(),
+        track.info.duration.tick
+    )
+    .context("write failed")?;
+
+    loop {
+        write!(output, "> ").context("write failed")?;
+        output.flush().context("write failed")?;
+
+        let mut line = String::new();
+        if input
+            .read_line(&mut line)
+            .context("failed to read command")?
+            == 0
+        {
+            return Ok(());
+        }
+
+        let words = line.split_whitespace().collect::<Vec<_>>();
+        match words.as_slice() {
+            [] => {},
+            ["quit" | "exit" | "q"] => return Ok(()),
+            ["help"] => writeln!(output, "{}", HELP).context("write failed")?,
+            ["channels"] => channels(track, &mut output)?,
+            command => {
+                if let Err(e) = query(track, command, &mut output) {
+                    writeln!(output, "error: {:#}", e).context("write failed")?;
+                }
+            },
+        }
+    }
+}
+
+type Filter = Box<dyn Fn(&Event) -> bool>;
+
+fn query(track: &Track, command: &[&str], output: &mut impl Write) -> anyhow::Result<()> {
+    let (filter, range): (Filter, _) = match command {
+        ["events", rest @ ..] => (Box::new(|_| true), rest),
+        ["notes", rest @ ..] => (
+            Box::new(|event| {
+                matches!(event, Event::Midi {
+                    data: MidiEvent::NoteOn { .. } | MidiEvent::NoteOff { .. },
+                    ..
+                })
+            }),
+            rest,
+        ),
+        ["tempo"] => (
+            Box::new(|event| {
+                matches!(event, Event::Meta {
+                    data: MetaEvent::Tempo(_),
+                    ..
+                })
+            }),
+            &[][..],
+        ),
+        ["channel", channel, rest @ ..] => {
+            let channel = channel
+                .parse::<u8>()
+                .with_context(|| format!("invalid channel {:?}", channel))?;
+            (
+                Box::new(
+                    move |event| matches!(event, Event::Midi { data, .. } if data.channel() == channel),
+                ),
+                rest,
+            )
+        },
+        ["type", name, rest @ ..] => {
+            let name = name.to_string();
+            (Box::new(move |event| type_name(event) == name), rest)
+        },
+        [command, ..] => bail!("unknown command {:?}, try help", command),
+        [] => return Ok(()),
+    };
+
+    let range = match range {
+        [] => 0..u64::MAX,
+        [range] => parse_range(range)?,
+        _ => bail!("too many arguments"),
+    };
+
+    let mut matched = 0;
+    for event in track.events.iter() {
+        if range.contains(&event.time().tick) && filter(event) {
+            serde_json::to_writer(&mut *output, event).context("failed to serialize data")?;
+            writeln!(output).context("write failed")?;
+            matched += 1;
+        }
+    }
+    writeln!(output, "{} events", matched).context("write failed")
+}
+
+fn channels(track: &Track, output: &mut impl Write) -> anyhow::Result<()> {
+    let mut counts = BTreeMap::<u8, usize>::new();
+    for event in track.events.iter() {
+        if let Event::Midi { data, .. } = event {
+            *counts.entry(data.channel()).or_default() += 1;
+        }
+    }
+
+    for (channel, count) in counts {
+        writeln!(output, "channel {}: {} events", channel, count).context("write failed")?;
+    }
+    Ok(())
+}
+
+/// Parse `FROM:TO`, `FROM:` or `:TO`
+fn parse_range(range: &str) -> anyhow::Result<Range<u64>> {
+    let (from, to) = range
+        .split_once(':')
+        .with_context(|| format!("invalid range {:?}, expected FROM:TO", range))?;
+    let bound = |tick: &str, default: u64| -> anyhow::Result<u64> {
+        match tick {
+            "" => Ok(default),
+            tick => tick
+                .parse()
+                .with_context(|| format!("invalid tick {:?}", tick)),
+        }
+    };
+
+    Ok(bound(from, 0)?..bound(to, u64::MAX)?)
+}
+
+/// The `type` of the event data, or the kind of event for events without one
+fn type_name(event: &Event) -> String {
+    let value = serde_json::to_value(event).unwrap_or_default();
+    value["data"]["type"]
+        .as_str()
+        .or_else(|| value["event"].as_str())
+        .unwrap_or_default()
+        .to_string()
+}
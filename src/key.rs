@@ -0,0 +1,66 @@
+use crate::notes;
+
+/// Krumhansl-Kessler probe tone ratings, starting at the tonic
+const MAJOR_PROFILE: [f64; 12] = [
+    6.35, 2.23, 3.48, 2.33, 4.38, 4.09, 2.52, 5.19, 2.39, 3.66, 2.29, 2.88,
+];
+const MINOR_PROFILE: [f64; 12] = [
+    6.33, 2.68, 3.52, 5.38, 2.60, 3.53, 2.54, 4.75, 3.98, 2.69, 3.34, 3.17,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Mode {
+    Major,
+    Minor,
+}
+
+/// The key a piece is most likely in
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct DetectedKey {
+    pub tonic:      &'static str,
+    pub mode:       Mode,
+    /// Correlation of the pitch classes with the key profile, from -1 to 1
+    pub confidence: f64,
+}
+
+/// Find the key with the Krumhansl-Schmuckler algorithm: the pitch class
+/// weights (like the total duration of every pitch class) are correlated with
+/// the profile of every major and minor key, the best match wins.
+///
+/// This is a heuristic, it may well disagree with the key signature of a
+/// file. Returns `None` if all weights are equal, like when there are no
+/// notes at all.
+pub fn detect_key(weights: &[f64; 12]) -> Option<DetectedKey> {
+    (0..12)
+        .flat_map(|tonic| [(tonic, Mode::Major), (tonic, Mode::Minor)])
+        .filter_map(|(tonic, mode)| {
+            let profile = match mode {
+                Mode::Major => &MAJOR_PROFILE,
+                Mode::Minor => &MINOR_PROFILE,
+            };
+            let rotated: Vec<f64> = (0..12).map(|pc| profile[(pc + 12 - tonic) % 12]).collect();
+            correlation(weights, &rotated).map(|r| (tonic, mode, r))
+        })
+        .max_by(|(.., l), (.., r)| l.total_cmp(r))
+        .map(|(tonic, mode, confidence)| DetectedKey {
+            tonic: notes::pitch_class_name(tonic as u8, false),
+            mode,
+            confidence,
+        })
+}
+
+/// Pearson correlation, `None` if either side has no variance
+fn correlation(xs: &[f64], ys: &[f64]) -> Option<f64> {
+    let mean = |vs: &[f64]| vs.iter().sum::<f64>() / vs.len() as f64;
+    let (mx, my) = (mean(xs), mean(ys));
+
+    let (mut cov, mut vx, mut vy) = (0.0, 0.0, 0.0);
+    for (x, y) in xs.iter().zip(ys) {
+        cov += (x - mx) * (y - my);
+        vx += (x - mx).powi(2);
+        vy += (y - my).powi(2);
+    }
+
+    (vx > 0.0 && vy > 0.0).then(|| cov / (vx * vy).sqrt())
+}
@@ -0,0 +1,23 @@
+pub mod beats;
+pub mod check;
+pub mod click;
+pub mod convert;
+pub mod gm;
+pub mod inspect;
+pub mod key;
+#[cfg(feature = "midi-out")]
+pub mod midi_out;
+pub mod model;
+pub mod notes;
+pub mod pianoroll;
+pub mod player;
+pub mod snapshot;
+pub mod summary;
+pub mod sysex;
+pub mod tempo;
+pub mod thin;
+pub mod tonejs;
+pub mod trackmode;
+pub mod velocity;
+#[cfg(feature = "wasm")]
+pub mod wasm;
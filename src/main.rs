@@ -1,17 +1,38 @@
-mod model;
-mod player;
-mod trackmode;
-
 use anyhow::Context;
-use chrono::Local;
-use model::PlayerResult;
-use player::MidiPlayer;
+use chrono::{Local, TimeZone, Utc};
+use flate2::read::MultiGzDecoder;
+#[cfg(feature = "midi-out")]
+use json_midi::midi_out;
+use json_midi::{
+    check,
+    click::ClickOptions,
+    convert,
+    gm,
+    inspect,
+    model::{self, NoteDeltaScope, Pedal, PlayerResult, Rounding, SerializeOptions},
+    notes::{MiddleC, NoteMatch, NoteNaming},
+    pianoroll::{PianoRoll, PianoRollOptions},
+    player::{DeltaViolation, MidiPlayer, PlayerOptions},
+    summary::{Summary, SummaryBuilder, SummaryOptions},
+    sysex::SysexEncoding,
+    tempo::{self, TempoMapFile},
+    tonejs,
+    trackmode::{LoopRegion, MergeStrategy, TieBreak},
+    velocity::VelocityCurve,
+};
+use sha2::{Digest, Sha256};
 use std::{
+    cell::Cell,
     fs,
-    io::{self, Write},
-    path::PathBuf,
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
 };
 use structopt::StructOpt;
+use tracing_subscriber::{fmt::format::FmtSpan, EnvFilter};
 
 #[derive(Debug, StructOpt)]
 struct Args {
@@ -23,6 +44,27 @@ struct Args {
     #[structopt(short, long)]
     pretty: bool,
 
+    /// Sort object keys alphabetically instead of using the declaration order,
+    /// for stable diffs between versions
+    #[structopt(long)]
+    sort_keys: bool,
+
+    /// Leave out fields holding their default value: `chan` 0, `track` 0 and
+    /// the note off `velocity` 64
+    #[structopt(long)]
+    omit_default_fields: bool,
+
+    /// Write tempo events as `{"type": "tempo", "bpm": 120.0}` instead of
+    /// their microseconds per quarter note. The bpm is derived and not always
+    /// exact
+    #[structopt(long)]
+    tempo_bpm_only: bool,
+
+    /// Output format (json, ndjson or cbor). ndjson writes one event per line
+    /// and streams the file instead of parsing it into memory up front
+    #[structopt(short, long, default_value = "json")]
+    format: OutputFormat,
+
     /// Emit timing information as a delta instead of an absolute timestamp
     #[structopt(short, long)]
     delta: bool,
@@ -31,53 +73,1495 @@ struct Args {
     #[structopt(short, long, parse(from_os_str))]
     output: Option<PathBuf>,
 
-    /// The file to convert
-    #[structopt(name = "FILE", parse(from_os_str))]
-    midi_file: PathBuf,
+    /// Resolve a relative --output against the directory of the input file
+    /// instead of the current directory
+    #[structopt(long, requires = "output")]
+    output_relative_to_input: bool,
+
+    /// The file to convert, may be gzip compressed
+    #[structopt(name = "FILE", parse(from_os_str), required_unless = "list-ports")]
+    midi_file: Option<PathBuf>,
 
     /// Dump the parsed object instead of scanning events
     #[structopt(long)]
     dump: bool,
 
-    #[structopt(long, name = "DEBUGF")]
-    debug: Option<String>
+    /// Attach the source bytes of each event and whether it used running
+    /// status
+    #[structopt(long)]
+    raw_bytes: bool,
+
+    /// Attach the debug representation of the source event to each event
+    #[structopt(long)]
+    include_raw: bool,
+
+    /// Override the ticks per quarter note declared in the file header
+    #[structopt(long)]
+    ppqn: Option<u16>,
+
+    /// Drop aftertouch events closer than this many milliseconds to the
+    /// previous one on the same channel and note
+    #[structopt(long, name = "MS", parse(try_from_str = parse_interval))]
+    thin_aftertouch: Option<f64>,
+
+    /// Drop controller and pitch bend events closer than this many
+    /// milliseconds to the previous one on the same channel and controller,
+    /// keeping the last value of a run. This is lossy
+    #[structopt(long, name = "CC_MS", parse(try_from_str = parse_interval))]
+    thin_cc: Option<f64>,
+
+    /// Fail if a merged event is not played at its tick. Without it, events out
+    /// of order are played with a delta of 0 and a warning is printed
+    #[structopt(long)]
+    validate_deltas: bool,
+
+    /// Convert each track of a sequential (type 2) file into an independent
+    /// pattern with its own timeline
+    #[structopt(long)]
+    patterns: bool,
+
+    /// Only convert the track with this index (counting from 0). A track of a
+    /// parallel file without tempo events of its own is played with the tempo
+    /// of the first track
+    #[structopt(long, name = "INDEX", conflicts_with_all = &["patterns", "force-format"])]
+    track: Option<usize>,
+
+    /// Emit SysEx events
+    #[structopt(long)]
+    sysex: bool,
+
+    /// Encoding of SysEx payloads (hex or base64)
+    #[structopt(long, default_value = "hex")]
+    sysex_encoding: SysexEncoding,
+
+    /// Drop note off events, including note ons with a velocity of 0
+    #[structopt(long)]
+    drop_note_off: bool,
+
+    /// Round float fields like `seconds` to this many decimals
+    #[structopt(long, name = "N")]
+    float_precision: Option<u8>,
+
+    /// Only emit the conductor track: tempo, time signature, key signature
+    /// and SMPTE offset events. These are emitted even without --meta, all
+    /// other meta events are dropped even with it
+    #[structopt(long)]
+    conductor_only: bool,
+
+    /// Attach the index of each event among all processed events (matching
+    /// the order of --dump for single track files) and among the emitted
+    /// events
+    #[structopt(long)]
+    index: bool,
+
+    /// First tick of a region to play multiple times. Loops are not detected
+    /// automatically, the region has to be given explicitly
+    #[structopt(long, requires_all = &["loop-to-tick", "loop-count"])]
+    loop_from_tick: Option<u64>,
+
+    /// Tick the loop region ends at (exclusive)
+    #[structopt(long, requires_all = &["loop-from-tick", "loop-count"])]
+    loop_to_tick: Option<u64>,
+
+    /// How often the loop region is played in total
+    #[structopt(long, requires_all = &["loop-from-tick", "loop-to-tick"])]
+    loop_count: Option<u32>,
+
+    /// Only emit channel events on these channels (0-15, comma separated).
+    /// Meta and SysEx events are not affected
+    #[structopt(long, require_delimiter = true)]
+    channels: Option<Vec<u8>>,
+
+    /// Only emit note ons and note offs of these notes (numbers or names like
+    /// C1 or F#2, comma separated), on all channels. Other events are kept
+    #[structopt(long, require_delimiter = true)]
+    notes: Option<Vec<String>>,
+
+    /// Drop all channel events besides the notes of --notes, like controllers
+    /// and program changes. Meta and SysEx events are not affected
+    #[structopt(long, requires = "notes")]
+    notes_only: bool,
+
+    /// Add the time since the previous note on (`time_since_last_note`) to
+    /// note ons, measured on the same channel or across all channels (channel
+    /// or global). Controllers and other events in between do not count
+    #[structopt(long, name = "SCOPE")]
+    note_delta: Option<NoteDeltaScope>,
+
+    /// Emit realtime (clock, start, stop, ...) and system common messages
+    /// stored in escape events as `live` events
+    #[structopt(long)]
+    live_messages: bool,
+
+    /// Emit pedal controllers as `sustain`, `sostenuto` and `soft` events
+    /// with a `down` flag (values of 64 and above) instead of controller
+    /// events. See --pedals
+    #[structopt(long)]
+    decode_pedal: bool,
+
+    /// The pedals decoded by --decode-pedal (sustain, sostenuto or soft,
+    /// comma separated)
+    #[structopt(long, default_value = "sustain", require_delimiter = true)]
+    pedals: Vec<Pedal>,
+
+    /// End the file with its last channel event instead of the last end of
+    /// track marker, dropping the silence before it. This shortens the
+    /// duration and moves the final end of track events
+    #[structopt(long)]
+    trim_trailing_silence: bool,
+
+    /// Multiply all micros and seconds by this factor, 0.5 plays the file
+    /// twice as fast. Ticks and tempo events are left as they are
+    #[structopt(long, name = "FACTOR", conflicts_with = "LENGTH")]
+    retime: Option<f64>,
+
+    /// Retime the file so it is this long, in seconds ("120" or "120s"),
+    /// milliseconds ("1500ms") or minutes ("2m"). Plays the file twice
+    #[structopt(long, name = "LENGTH", parse(try_from_str = parse_seconds))]
+    target_duration: Option<f64>,
+
+    /// Add a metronome click on the drum channel (10) on every beat of the
+    /// time signature. Only for files with metrical timing
+    #[structopt(long)]
+    click: bool,
+
+    /// Drum note of the click, a side stick by default
+    #[structopt(long, name = "CLICK_NOTE", requires = "click")]
+    click_note: Option<u8>,
+
+    /// Velocity of the click
+    #[structopt(long, name = "CLICK_VELOCITY", requires = "click")]
+    click_velocity: Option<u8>,
+
+    /// Add the position of every event as bar.beat.tick, counted from the
+    /// time signatures. `null` for SMPTE timed files without --tempo-map
+    #[structopt(long)]
+    beat_position: bool,
+
+    /// Fail instead of writing anything if any event of the file would be
+    /// dropped, listing the dropped events. Needs --meta and, depending on the
+    /// file, --sysex and --escape to pass
+    #[structopt(long)]
+    strict: bool,
+
+    /// Add a `snapshot` of the state of playback at this tick to the header:
+    /// the notes held, program, controllers and pitch bend of every channel
+    /// and the tempo and time signature, for starting playback from there
+    #[structopt(long, name = "TICK")]
+    snapshot_tick: Option<u64>,
+
+    /// Read the tempo and time signatures of an SMPTE timed file from this
+    /// json file, for --beat-position and --quarters
+    #[structopt(long, parse(from_os_str), conflicts_with = "no-time")]
+    tempo_map: Option<PathBuf>,
+
+    /// Drop channel events whose absolute tick is not a multiple of this, to
+    /// thin out dense controller data. Notes are kept. This is lossy
+    #[structopt(long, name = "TICKS")]
+    tick_modulo: Option<u64>,
+
+    /// Drop notes off the --tick-modulo grid as well, which can leave notes
+    /// without their note off or the other way around
+    #[structopt(long, requires = "TICKS")]
+    tick_modulo_notes: bool,
+
+    /// Add `implied_note_off: true` to note ons with a velocity of 0, which
+    /// end a note. They keep their note_on type
+    #[structopt(long)]
+    annotate_note_off: bool,
+
+    /// Drop events with out of spec values, like a tempo of 0 or a key
+    /// signature with more than 7 sharps. Their number is written to the
+    /// header as `invalid_events`
+    #[structopt(long)]
+    sanitize: bool,
+
+    /// Emit meta events of an unknown type, even without --meta
+    #[structopt(long)]
+    emit_unknown: bool,
+
+    /// Add `abs_quarters` to the time of every event, the absolute time in
+    /// quarter notes (tick / ppqn). It does not depend on the tempo
+    #[structopt(long)]
+    quarters: bool,
+
+    /// Add `delta_beats` to the time of every event, the ticks since the
+    /// previous event in quarter notes (0.25 is a sixteenth). It does not
+    /// depend on the tempo
+    #[structopt(long)]
+    beat_delta: bool,
+
+    /// Add `note_on_index` to note offs, the emitted index of the note on
+    /// they end (null if none is playing)
+    #[structopt(long)]
+    link_note_pairs: bool,
+
+    /// Which note a note off ends when the same key was struck again before
+    /// it was released: fifo (the oldest) or lifo (the most recent). Used for
+    /// --link-note-pairs, --energy and the note durations of tonejs output
+    #[structopt(long, default_value = "fifo")]
+    note_match: NoteMatch,
+
+    /// Add the program last changed to on their channel (`active_program`)
+    /// and its General MIDI name (`active_instrument`) to note ons, null
+    /// before the first program change
+    #[structopt(long)]
+    note_program: bool,
+
+    /// Ticks per row of the piano roll. Defaults to a sixteenth note, or a
+    /// frame for SMPTE timed files
+    #[structopt(long, name = "STEP_TICKS")]
+    pianoroll_resolution: Option<u64>,
+
+    /// Lowest note of the piano roll, 0 by default
+    #[structopt(long, name = "LOW_NOTE")]
+    pianoroll_low: Option<u8>,
+
+    /// Highest note of the piano roll, 127 by default
+    #[structopt(long, name = "HIGH_NOTE")]
+    pianoroll_high: Option<u8>,
+
+    /// Write the piano roll as a single base64 string of one byte per cell,
+    /// row by row, instead of an array of rows
+    #[structopt(long)]
+    pianoroll_blob: bool,
+
+    /// Emit a `rest` with its `duration_micros` after a note on that ends a
+    /// gap of more than this many milliseconds without notes on its channel
+    #[structopt(long, name = "REST_MS", conflicts_with = "no-time")]
+    emit_rests: Option<u64>,
+
+    /// Number of events to reserve memory for up front. Defaults to the number
+    /// of events in the file
+    #[structopt(long, name = "EVENTS")]
+    capacity_hint: Option<usize>,
+
+    /// Drop tempo events that set the tempo already in effect. The first
+    /// tempo event is always kept, even if it sets the default of 120 bpm
+    #[structopt(long)]
+    dedupe_tempo: bool,
+
+    /// Write a velocity of 0 for all note offs, for consumers that ignore the
+    /// release velocity. Most files only use 64 or 0 as a placeholder anyway
+    #[structopt(long)]
+    collapse_note_off_velocity: bool,
+
+    /// How fractional microseconds are rounded (floor, round or ceil)
+    #[structopt(long, default_value = "floor")]
+    rounding: Rounding,
+
+    /// Annotate note ons with the interval in semitones to the previous note
+    /// on the same channel. Notes starting on the same tick are all compared
+    /// to the last note before that tick
+    #[structopt(long)]
+    intervals: bool,
+
+    /// Add the name of the note (like C4 or F#5) to note events
+    #[structopt(long)]
+    note_names: bool,
+
+    /// The octave note 60 is in for --note-names and the note names of --notes
+    /// (c3, c4 or c5, default c4)
+    #[structopt(long)]
+    middle_c: Option<MiddleC>,
+
+    /// Spell note names with flats instead of sharps
+    #[structopt(long, requires = "note-names")]
+    flats: bool,
+
+    /// Only use the tempo changes of this track (an index, or auto for the
+    /// track with the most tempo changes). Tempo events on other tracks are
+    /// still emitted but do not change the tempo
+    #[structopt(long, name = "TRACK")]
+    tempo_track: Option<TempoTrack>,
+
+    /// Play the file as this format (single, parallel or sequential) instead
+    /// of the one declared in its header, for files with a broken header
+    #[structopt(long, name = "SMF_FORMAT")]
+    force_format: Option<ForceFormat>,
+
+    /// Only emit ticks, without micros and seconds. Skips all tempo
+    /// calculations
+    #[structopt(long, conflicts_with_all = &["MS", "CC_MS", "realtime", "play"])]
+    no_time: bool,
+
+    /// Include escape (F7) events with their bytes as hex. What they mean
+    /// depends on the device they were meant for
+    #[structopt(long)]
+    escape: bool,
+
+    /// Drop controller events that repeat the previous value of the same
+    /// controller on the same channel
+    #[structopt(long)]
+    merge_adjacent_cc: bool,
+
+    /// How events of different tracks on the same tick are ordered in
+    /// parallel files: track-order, meta-first (tempo and controllers before
+    /// notes) or note-first
+    #[structopt(long, default_value = "meta-first")]
+    tie_break: TieBreak,
+
+    /// Drop the obsolete MidiChannel and MidiPort meta events and instead add
+    /// the channel and port they set to the following meta and SysEx events
+    /// of the same track (as `channel_prefix` and `port_prefix`)
+    #[structopt(long)]
+    strip_channel_prefix_meta: bool,
+
+    /// Drop note ons quieter than this, along with their note offs
+    #[structopt(long)]
+    min_velocity: Option<u8>,
+
+    /// Drop note ons louder than this, along with their note offs
+    #[structopt(long)]
+    max_velocity: Option<u8>,
+
+    /// Leave out the generated timestamp for reproducible output. Setting
+    /// SOURCE_DATE_EPOCH uses that time instead of the current one
+    #[structopt(long)]
+    no_timestamp: bool,
+
+    /// Write this as `source_file` instead of the path of the midi file, to
+    /// not leak the path. With --no-timestamp the output only depends on the
+    /// contents of the file
+    #[structopt(long, name = "LABEL")]
+    source_label: Option<String>,
+
+    /// Write the SHA-256 of the midi file as `source_sha256`, to tell which
+    /// version of a file an output was converted from
+    #[structopt(long)]
+    checksum: bool,
+
+    /// Play the file on a midi output port instead of converting it. Needs the
+    /// midi-out feature
+    #[structopt(long)]
+    play: bool,
+
+    /// Index of the midi output port for --play, see --list-ports
+    #[structopt(long, default_value = "0")]
+    port: usize,
+
+    /// List the available midi output ports. Needs the midi-out feature
+    #[structopt(long)]
+    list_ports: bool,
+
+    /// Write every event at the time it is played, as a sequencer would.
+    /// Requires ndjson output
+    #[structopt(long)]
+    realtime: bool,
+
+    /// Write a summary of the file instead of its events
+    #[structopt(long, conflicts_with_all = &["patterns", "group-by-channel", "events-only"])]
+    summary: bool,
+
+    /// Add the tick range and note count of every channel to the summary
+    #[structopt(long)]
+    channel_activity: bool,
+
+    /// Add the lowest and highest note, the most notes playing at once, the
+    /// number of notes and the average velocity of every channel to the
+    /// summary
+    #[structopt(long)]
+    channel_stats: bool,
+
+    /// Guess the key of the file from the notes and add it to the summary.
+    /// This is a heuristic and may disagree with the key signature
+    #[structopt(long)]
+    detect_key: bool,
+
+    /// Add the General MIDI instrument of every channel, by its last program
+    /// change, to the output. Channels without a program change are `null`
+    #[structopt(long)]
+    program_map: bool,
+
+    /// Add the total energy (velocity times length in seconds, summed over
+    /// all notes) and the energy of every channel to the summary
+    #[structopt(long)]
+    energy: bool,
+
+    /// Also write the events in this format (json, ndjson or cbor) or a
+    /// summary, from the same pass over the file. May be given multiple times,
+    /// every output goes next to --output with the extension of its format
+    /// (.summary.json for summaries)
+    #[structopt(
+        long,
+        number_of_values = 1,
+        requires = "output",
+        conflicts_with_all = &["patterns", "realtime", "dump", "play"]
+    )]
+    also: Vec<Also>,
+
+    /// Split the output into files of at most this many events. The chunks
+    /// are named after --output with their number before the extension
+    /// (out.0.json, out.1.json, ...), every chunk is a complete track
+    #[structopt(
+        long,
+        name = "CHUNK",
+        requires = "output",
+        conflicts_with_all = &[
+            "patterns", "summary", "also", "group-by-channel", "combine-channels-into-tracks",
+            "split-on-marker", "realtime", "dump", "play"
+        ]
+    )]
+    events_per_file: Option<usize>,
+
+    /// Memory budget in megabytes for the converted events. If the file would
+    /// need more, json output is streamed as ndjson instead and other outputs
+    /// fail before converting anything
+    #[structopt(long, name = "MB")]
+    max_memory: Option<u64>,
+
+    /// Write the events last to first. Absolute times are kept, with --delta
+    /// every delta is the time back to the event written before it
+    #[structopt(
+        long,
+        conflicts_with_all = &[
+            "patterns", "summary", "events-per-file", "inspect", "realtime", "dump", "play"
+        ]
+    )]
+    reverse: bool,
+
+    /// Open a prompt to query the events of the file by tick range, channel
+    /// or type instead of writing them. Meta events are always included
+    #[structopt(
+        long,
+        conflicts_with_all = &[
+            "delta", "output", "patterns", "summary", "also", "realtime", "play", "dump",
+            "group-by-channel", "events-only"
+        ]
+    )]
+    inspect: bool,
+
+    /// Write only the events as a top level array, without the generated
+    /// time, source file, counts and duration. ndjson output never has them
+    #[structopt(long, conflicts_with_all = &["patterns", "group-by-channel"])]
+    events_only: bool,
+
+    /// Add a `scope` field ("note" or "channel") to aftertouch events
+    #[structopt(long)]
+    aftertouch_verbose: bool,
+
+    /// Group events by channel instead of writing a flat list. Meta and SysEx
+    /// events go into a separate "meta" list
+    #[structopt(long, conflicts_with = "patterns")]
+    group_by_channel: bool,
+
+    /// Write a list of tracks, one for every channel with its events, label
+    /// and instrument, instead of a flat list. Meta and SysEx events go into
+    /// a separate "meta" list
+    #[structopt(
+        long,
+        conflicts_with_all = &["patterns", "group-by-channel", "events-only"]
+    )]
+    combine_channels_into_tracks: bool,
+
+    /// Write a list of segments instead of a flat list, a new one starting at
+    /// every marker with its text as the name. Implies --meta
+    #[structopt(
+        long,
+        conflicts_with_all = &[
+            "patterns", "group-by-channel", "combine-channels-into-tracks", "events-only",
+            "delta", "reverse"
+        ]
+    )]
+    split_on_marker: bool,
+
+    /// Write debug logs to this file. Without it, logs go to stderr as
+    /// selected by RUST_LOG (like RUST_LOG=debug)
+    #[structopt(long, name = "DEBUGF", parse(from_os_str))]
+    debug: Option<PathBuf>,
+
+    /// Print the time spent reading, parsing, converting and writing and the
+    /// number of events converted per second to stderr when done
+    #[structopt(long)]
+    profile: bool,
+
+    /// Remap note velocities (linear, compress, expand, gain=<f> or gamma=<f>)
+    #[structopt(long, name = "CURVE")]
+    velocity_curve: Option<VelocityCurve>,
+
+    /// Remap note velocities using a table of 128 values read from a file
+    #[structopt(long, parse(from_os_str), conflicts_with = "CURVE")]
+    velocity_table: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OutputFormat {
+    Json,
+    /// One event per line, written while the file is played
+    Ndjson,
+    Cbor,
+    /// The shape of @tonejs/midi, see [`tonejs::Midi`]
+    Tonejs,
+    /// A grid of note velocities, see [`PianoRoll`]
+    Pianoroll,
+}
+
+impl FromStr for OutputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(OutputFormat::Json),
+            "ndjson" => Ok(OutputFormat::Ndjson),
+            "cbor" => Ok(OutputFormat::Cbor),
+            "tonejs" => Ok(OutputFormat::Tonejs),
+            "pianoroll" => Ok(OutputFormat::Pianoroll),
+            _ => anyhow::bail!(
+                "unknown output format {:?} (expected json, ndjson, cbor, tonejs or pianoroll)",
+                s
+            ),
+        }
+    }
+}
+
+impl OutputFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::Json => "json",
+            OutputFormat::Ndjson => "ndjson",
+            OutputFormat::Cbor => "cbor",
+            OutputFormat::Tonejs => "json",
+            OutputFormat::Pianoroll => "json",
+        }
+    }
+}
+
+/// An additional output written from the same pass
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Also {
+    Format(OutputFormat),
+    Summary,
+}
+
+impl FromStr for Also {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "summary" => Ok(Also::Summary),
+            "tonejs" | "pianoroll" => anyhow::bail!("{} can only be the main output", s),
+            _ => s.parse().map(Also::Format).map_err(|_| {
+                anyhow::anyhow!(
+                    "unknown output {:?} (expected json, ndjson, cbor or summary)",
+                    s
+                )
+            }),
+        }
+    }
+}
+
+/// The track whose tempo changes are used
+#[derive(Debug, Clone, Copy)]
+enum TempoTrack {
+    Index(usize),
+    /// The track with the most tempo changes
+    Auto,
+}
+
+impl FromStr for TempoTrack {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(TempoTrack::Auto),
+            _ => s.parse().map(TempoTrack::Index).map_err(|_| {
+                anyhow::anyhow!(
+                    "invalid tempo track {:?} (expected a track index or auto)",
+                    s
+                )
+            }),
+        }
+    }
+}
+
+/// The format a file is played as with `--force-format`
+#[derive(Debug, Clone, Copy)]
+enum ForceFormat {
+    Single,
+    Parallel,
+    Sequential,
+}
+
+impl FromStr for ForceFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "single" => Ok(ForceFormat::Single),
+            "parallel" => Ok(ForceFormat::Parallel),
+            "sequential" => Ok(ForceFormat::Sequential),
+            _ => anyhow::bail!(
+                "unknown format {:?} (expected single, parallel or sequential)",
+                s
+            ),
+        }
+    }
+}
+
+impl ForceFormat {
+    /// The format to play a file with `num_tracks` tracks as
+    fn apply(self, num_tracks: usize) -> anyhow::Result<midly::Format> {
+        match self {
+            ForceFormat::Single => {
+                if num_tracks > 1 {
+                    eprintln!(
+                        "warning: playing the file as a single track file, only the first of its \
+                         {} tracks is played",
+                        num_tracks
+                    );
+                }
+                Ok(midly::Format::SingleTrack)
+            },
+            ForceFormat::Parallel => Ok(midly::Format::Parallel),
+            ForceFormat::Sequential => Ok(midly::Format::Sequential),
+        }
+    }
+}
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// What a run works out along the way, as opposed to the flags in [`Args`]
+#[derive(Debug, Default)]
+struct RunContext {
+    /// SHA-256 of the midi file, with `--checksum`
+    source_sha256: Option<String>,
+    timings:       Timings,
 }
 
-struct DbgWriter {
-    d: Option<std::fs::File>
+/// Time spent in the phases of a conversion for `--profile`. Merging the
+/// tracks happens while playing, it is part of converting
+#[derive(Debug, Default)]
+struct Timings {
+    start:  Option<Instant>,
+    read:   Cell<Duration>,
+    parse:  Cell<Duration>,
+    write:  Cell<Duration>,
+    events: Cell<usize>,
 }
 
-impl DbgWriter {
-    pub fn n(v: Option<String>) -> Self {
-        Self {
-            d: v.map(|x| std::fs::File::create(x).expect("fatal: failed to create debug file"))
+impl Timings {
+    /// Add the time since `since` to a phase, if profiling
+    fn add(&self, phase: &Cell<Duration>, since: Option<Instant>) {
+        if let Some(since) = since {
+            phase.set(phase.get() + since.elapsed());
         }
     }
 
-    pub fn w(&mut self, t: &'static str, s: String) {
-        if let Some(f) = self.d.as_mut() {
-            let _ = writeln!(f, "############### {}", t);
-            let _ = writeln!(f, "{}", s);
+    /// The time a phase started at, if profiling
+    fn now(&self) -> Option<Instant> { self.start.map(|_| Instant::now()) }
+
+    fn report(&self) {
+        let Some(start) = self.start else {
+            return;
+        };
+        let total = start.elapsed();
+        let convert = total.saturating_sub(self.read.get() + self.parse.get() + self.write.get());
+        let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+        eprintln!(
+            "profile: read {:.1}ms, parse {:.1}ms, convert {:.1}ms, write {:.1}ms, total {:.1}ms, \
+             {} events at {:.0} events/s",
+            ms(self.read.get()),
+            ms(self.parse.get()),
+            ms(convert),
+            ms(self.write.get()),
+            ms(total),
+            self.events.get(),
+            self.events.get() as f64 / total.as_secs_f64()
+        );
+    }
+}
+
+impl Args {
+    fn midi_file(&self) -> &Path {
+        // clap only allows leaving it out with --list-ports
+        self.midi_file.as_deref().expect("no midi file given")
+    }
+
+    fn serialize_options(&self) -> SerializeOptions {
+        SerializeOptions {
+            omit_default_fields: self.omit_default_fields,
+            tempo_bpm_only:      self.tempo_bpm_only,
+        }
+    }
+
+    /// What to write as `source_file`
+    fn source_label(&self) -> String {
+        match &self.source_label {
+            Some(label) => label.clone(),
+            None => format!("{}", self.midi_file().display()),
         }
     }
 }
 
 fn main() -> anyhow::Result<()> {
-    let args = Args::from_args();
+    let mut args = Args::from_args();
+    let mut run = RunContext::default();
+
+    if args.list_ports {
+        return list_ports();
+    }
+
+    if args.output_relative_to_input {
+        args.output = args
+            .output
+            .take()
+            .map(|output| relative_to_input(args.midi_file(), output));
+    }
+
+    if (args.channel_activity || args.channel_stats || args.detect_key || args.energy)
+        && !(args.summary || args.also.contains(&Also::Summary))
+    {
+        anyhow::bail!(
+            "--channel-activity, --channel-stats, --detect-key and --energy require --summary or \
+             --also summary"
+        );
+    }
+
+    if let Some(output) = args.output.as_deref() {
+        let mut paths = vec![output.to_path_buf()];
+        for &also in args.also.iter() {
+            let path = also_path(output, also);
+            if paths.contains(&path) {
+                anyhow::bail!("{} would be written more than once", path.display());
+            }
+            paths.push(path);
+        }
+    }
+
+    if args.pretty
+        && !matches!(
+            args.format,
+            OutputFormat::Json | OutputFormat::Tonejs | OutputFormat::Pianoroll
+        )
+    {
+        anyhow::bail!("--pretty can only be used with json output");
+    }
+
+    let generated = generated_timestamp(&args)?;
+
+    if args.realtime && args.format != OutputFormat::Ndjson {
+        anyhow::bail!("--realtime requires ndjson output");
+    }
+
+    if let (OutputFormat::Ndjson, Some(flag)) = (args.format, ndjson_conflict(&args)) {
+        anyhow::bail!("{} cannot be used with ndjson output", flag);
+    }
+
+    if args.format == OutputFormat::Tonejs
+        && (args.delta
+            || args.no_time
+            || args.summary
+            || args.patterns
+            || args.group_by_channel
+            || args.combine_channels_into_tracks
+            || args.split_on_marker
+            || args.events_only
+            || args.events_per_file.is_some()
+            || args.reverse)
+    {
+        anyhow::bail!(
+            "tonejs output is a whole file with absolute times, it cannot be used with --delta, \
+             --no-time, --summary, --patterns, --group-by-channel, \
+             --combine-channels-into-tracks, --split-on-marker, --events-only, --events-per-file \
+             or --reverse"
+        );
+    }
+
+    if args.format == OutputFormat::Pianoroll
+        && (args.delta
+            || args.summary
+            || args.patterns
+            || args.group_by_channel
+            || args.combine_channels_into_tracks
+            || args.split_on_marker
+            || args.events_only
+            || args.events_per_file.is_some()
+            || args.reverse)
+    {
+        anyhow::bail!(
+            "pianoroll output is a whole file with absolute ticks, it cannot be used with \
+             --delta, --summary, --patterns, --group-by-channel, --combine-channels-into-tracks, \
+             --split-on-marker, --events-only, --events-per-file or --reverse"
+        );
+    }
+
+    if args.format != OutputFormat::Pianoroll
+        && (args.pianoroll_resolution.is_some()
+            || args.pianoroll_low.is_some()
+            || args.pianoroll_high.is_some()
+            || args.pianoroll_blob)
+    {
+        anyhow::bail!("the --pianoroll options need --format pianoroll");
+    }
 
-    let mut dbg = DbgWriter::n(args.debug.clone());
-    dbg.w("args", format!("{:#?}", args));
+    if args.checksum
+        && (matches!(args.format, OutputFormat::Ndjson | OutputFormat::Tonejs) || args.events_only)
+    {
+        anyhow::bail!(
+            "--checksum is written into the header, it cannot be used with ndjson or tonejs \
+             output or --events-only"
+        );
+    }
+
+    // the markers are meta events
+    if args.split_on_marker {
+        args.meta = true;
+    }
+
+    if args
+        .retime
+        .is_some_and(|factor| !factor.is_finite() || factor <= 0.0)
+    {
+        anyhow::bail!("--retime must be a positive number");
+    }
+
+    if args.snapshot_tick.is_some()
+        && (matches!(
+            args.format,
+            OutputFormat::Ndjson | OutputFormat::Tonejs | OutputFormat::Pianoroll
+        ) || args.summary
+            || args.events_only
+            || args.events_per_file.is_some())
+    {
+        anyhow::bail!(
+            "--snapshot-tick is written into the header, it cannot be used with ndjson, tonejs or \
+             pianoroll output, --summary, --events-only or --events-per-file"
+        );
+    }
+
+    if args.tempo_map.is_some() && !(args.beat_position || args.quarters) {
+        anyhow::bail!("--tempo-map needs --beat-position or --quarters");
+    }
+
+    if args.middle_c.is_some() && !args.note_names && args.notes.is_none() {
+        anyhow::bail!("--middle-c needs --note-names or --notes");
+    }
 
-    let midi_file = fs::read(&args.midi_file).context("failed to read midi data into memory")?;
-    dbg.w("file", format!("read length {}", midi_file.len()));
+    if args.events_per_file == Some(0) {
+        anyhow::bail!("--events-per-file must be at least 1");
+    }
 
-    let smf = midly::Smf::parse(&midi_file).context("failed to parse midi file header")?;
+    init_logging(args.debug.as_deref())?;
+    tracing::debug!("args: {:#?}", args);
 
-    dbg.w("midi.header", format!("{:#?}", smf.header));
+    if args.profile {
+        run.timings.start = Some(Instant::now());
+    }
 
-    let stdout = io::stdout();
+    let read = tracing::debug_span!("read", file = %args.midi_file().display()).entered();
+    let midi_file = fs::read(args.midi_file()).context("failed to read midi data into memory")?;
+    if args.checksum {
+        run.source_sha256 = Some(format!("{:x}", Sha256::digest(&midi_file)));
+    }
+    let midi_file = decompress(midi_file)?;
 
-    let sd = match args.output {
+    let invalid_data = check::find_invalid_data_bytes(&midi_file);
+    for invalid in invalid_data.iter() {
+        eprintln!(
+            "{}: track {} event {}: data byte 0x{:02x} at offset 0x{:x} of a 0x{:02x} message is \
+             out of range, the rest of the track is dropped",
+            if args.strict { "error" } else { "warning" },
+            invalid.track,
+            invalid.event,
+            invalid.byte,
+            invalid.offset,
+            invalid.status
+        );
+    }
+    if args.strict && !invalid_data.is_empty() {
+        anyhow::bail!("{} tracks are cut short", invalid_data.len());
+    }
+    tracing::debug!(length = midi_file.len(), "read midi file");
+    drop(read);
+    run.timings.add(&run.timings.read, run.timings.start);
+
+    if let Some(budget) = args.max_memory {
+        check_memory(&mut args, &midi_file, budget)?;
+    }
+
+    let velocity_curve = match args.velocity_table.as_deref() {
+        Some(path) => Some(VelocityCurve::from_table_file(path)?),
+        None => args.velocity_curve.clone(),
+    };
+
+    let naming = NoteNaming {
+        middle_c: args.middle_c.unwrap_or_default(),
+        flats:    args.flats,
+    };
+    let notes = args
+        .notes
+        .as_ref()
+        .map(|notes| {
+            notes
+                .iter()
+                .map(|note| naming.parse(note))
+                .collect::<anyhow::Result<Vec<_>>>()
+        })
+        .transpose()
+        .context("invalid --notes")?;
+
+    let mut options = PlayerOptions {
+        emit_meta: args.meta || args.inspect || args.format == OutputFormat::Tonejs,
+        delta_times: args.delta,
+        velocity_curve,
+        include_raw: args.include_raw,
+        ppqn: args.ppqn,
+        thin_aftertouch: args.thin_aftertouch,
+        thin_cc: args.thin_cc,
+        // always checked, --validate-deltas only decides if it is an error
+        validate_deltas: true,
+        sysex: Some(args.sysex_encoding).filter(|_| args.sysex),
+        drop_note_off: args.drop_note_off,
+        float_precision: args.float_precision,
+        conductor_only: args.conductor_only,
+        index: args.index,
+        loop_region: match (args.loop_from_tick, args.loop_to_tick, args.loop_count) {
+            (Some(from), Some(to), Some(count)) => Some(LoopRegion { from, to, count }),
+            _ => None,
+        },
+        aftertouch_verbose: args.aftertouch_verbose,
+        channels: args.channels.clone(),
+        rounding: args.rounding,
+        intervals: args.intervals,
+        note_names: Some(naming).filter(|_| args.note_names),
+        velocity_range: match (args.min_velocity, args.max_velocity) {
+            (None, None) => None,
+            (min, max) => Some(min.unwrap_or(1)..=max.unwrap_or(127)),
+        },
+        // needs the parsed tracks for auto detection, see below
+        tempo_track: None,
+        no_time: args.no_time,
+        escape: args.escape,
+        merge_adjacent_cc: args.merge_adjacent_cc,
+        tie_break: args.tie_break,
+        channel_prefix: args.strip_channel_prefix_meta,
+        note_delta: args.note_delta,
+        collapse_note_off_velocity: args.collapse_note_off_velocity,
+        dedupe_tempo: args.dedupe_tempo,
+        live_messages: args.live_messages,
+        trim_trailing_silence: args.trim_trailing_silence,
+        pedals: if args.decode_pedal {
+            args.pedals.clone()
+        } else {
+            Vec::new()
+        },
+        // --target-duration needs the length of the file, see below
+        retime: args.retime,
+        click: args.click.then(|| {
+            let default = ClickOptions::default();
+            ClickOptions {
+                note:     args.click_note.unwrap_or(default.note),
+                velocity: args.click_velocity.unwrap_or(default.velocity),
+            }
+        }),
+        beat_position: args.beat_position,
+        tick_modulo: args.tick_modulo,
+        tick_modulo_notes: args.tick_modulo_notes,
+        annotate_note_off: args.annotate_note_off,
+        sanitize: args.sanitize,
+        strategy: None,
+        emit_unknown: args.emit_unknown,
+        quarters: args.quarters,
+        beat_delta: args.beat_delta,
+        link_note_pairs: args.link_note_pairs,
+        note_match: args.note_match,
+        emit_rests: args.emit_rests,
+        note_program: args.note_program,
+        notes,
+        notes_only: args.notes_only,
+        tempo_map: args
+            .tempo_map
+            .as_deref()
+            .map(TempoMapFile::from_file)
+            .transpose()?,
+        snapshot_tick: args.snapshot_tick,
+        record_ignored: args.strict,
+    };
+
+    let mut violations = Vec::new();
+    let sd = if args.format == OutputFormat::Ndjson
+        && !args.raw_bytes
+        && !args.dump
+        && !args.play
+        && args.also.is_empty()
+        && !args.inspect
+    {
+        // parse lazily and write every event as soon as it is played, so only
+        // the input file and a single event are held in memory
+        let parse = tracing::debug_span!("parse", lazy = true).entered();
+        let parse_start = run.timings.now();
+        let (mut header, tracks) =
+            midly::parse(&midi_file).context("failed to parse midi file header")?;
+        tracing::debug!("header: {:#?}", header);
+
+        let tracks = tracks
+            .collect::<Result<Vec<_>, _>>()
+            .context("failed to parse midi tracks")?;
+        if let Some(format) = args.force_format {
+            header.format = format.apply(tracks.len())?;
+        }
+        options.strategy = args.track.map(|track| single_track(header.format, track));
+        if cfg!(feature = "strict-parsing") {
+            // the lazy parser only reports errors once it reaches them, check
+            // the whole file before emitting anything
+            for track in tracks.iter() {
+                for event in track.clone() {
+                    event.context("failed to parse midi track")?;
+                }
+            }
+        }
+
+        if !args.live_messages {
+            warn_live_messages(check::count_live_messages(
+                tracks
+                    .iter()
+                    .map(|track| track.clone().map_while(Result::ok)),
+            ));
+        }
+
+        options.tempo_track = match args.tempo_track {
+            Some(TempoTrack::Index(idx)) => Some(idx),
+            Some(TempoTrack::Auto) => tempo::detect_tempo_track(
+                tracks
+                    .iter()
+                    .map(|track| track.clone().map_while(Result::ok)),
+            ),
+            None => None,
+        };
+
+        let tracks = tracks
+            .into_iter()
+            .map(|track| track.map_while(Result::ok))
+            .collect::<Vec<_>>();
+        drop(parse);
+        run.timings.add(&run.timings.parse, parse_start);
+        if args.profile {
+            // counted after timing the parse, the lazy parser has to go
+            // through the tracks once more for it
+            let events = tracks.iter().map(|track| track.clone().count()).sum();
+            run.timings.events.set(events);
+        }
+
+        let _convert = tracing::debug_span!("convert").entered();
+
+        if let Some(target) = args.target_duration {
+            let player = MidiPlayer::from_tracks(header, tracks.clone(), options.clone())?;
+            options.retime = Some(retime_factor(player, target)?);
+        }
+
+        if args.strict {
+            check_strict(MidiPlayer::from_tracks(
+                header,
+                tracks.clone(),
+                options.clone(),
+            )?)?;
+        }
+
+        let player = MidiPlayer::from_tracks(header, tracks, options)?;
+
+        if let Some(size) = args.events_per_file {
+            violations.extend(write_chunks(player, &args, &run, &generated, size)?);
+            run.timings.report();
+            return report_violations(&violations, &args);
+        }
+
+        let (outfile, sd) = open_output(args.output.clone())?;
+        violations.extend(stream_events(player, outfile, &args, &run)?);
+        sd
+    } else {
+        let parse = tracing::debug_span!("parse", lazy = false).entered();
+        let parse_start = run.timings.now();
+        // midly always expands running status, the bytemap is the only way to
+        // get at the source bytes of an event
+        let (mut smf, raw_bytes) = if args.raw_bytes {
+            let bytemap =
+                midly::SmfBytemap::parse(&midi_file).context("failed to parse midi file header")?;
+            let smf = midly::Smf {
+                header: bytemap.header,
+                tracks: bytemap
+                    .tracks
+                    .iter()
+                    .map(|track| track.iter().map(|(_, event)| *event).collect())
+                    .collect(),
+            };
+            let raw = bytemap
+                .tracks
+                .iter()
+                .map(|track| track.iter().map(|(bytes, _)| *bytes).collect::<Vec<_>>())
+                .collect::<Vec<_>>();
+            (smf, Some(raw))
+        } else {
+            let smf = midly::Smf::parse(&midi_file).context("failed to parse midi file header")?;
+            (smf, None)
+        };
+
+        tracing::debug!(tracks = smf.tracks.len(), "header: {:#?}", smf.header);
+
+        if let Some(format) = args.force_format {
+            smf.header.format = format.apply(smf.tracks.len())?;
+        }
+        options.strategy = args
+            .track
+            .map(|track| single_track(smf.header.format, track));
+
+        if !args.live_messages {
+            warn_live_messages(check::count_live_messages(
+                smf.tracks.iter().map(|track| track.iter().copied()),
+            ));
+        }
+
+        options.tempo_track = match args.tempo_track {
+            Some(TempoTrack::Index(idx)) => Some(idx),
+            Some(TempoTrack::Auto) => {
+                tempo::detect_tempo_track(smf.tracks.iter().map(|track| track.iter().copied()))
+            },
+            None => None,
+        };
+        drop(parse);
+        run.timings.add(&run.timings.parse, parse_start);
+        run.timings
+            .events
+            .set(smf.tracks.iter().map(|track| track.len()).sum());
+
+        let _convert = tracing::debug_span!("convert").entered();
+
+        if let Some(target) = args.target_duration {
+            options.retime = Some(retime_factor(
+                MidiPlayer::new(&smf, options.clone())?,
+                target,
+            )?);
+        }
+
+        if args.strict {
+            check_strict(MidiPlayer::new(&smf, options.clone())?)?;
+        }
+
+        if args.play {
+            return play_midi(MidiPlayer::new(&smf, options)?, args.port);
+        }
+
+        if args.inspect {
+            let player = MidiPlayer::new(&smf, options)?;
+            let player = match raw_bytes.as_deref() {
+                Some(raw) => player.with_raw_bytes(raw),
+                None => player,
+            };
+            let (track, _) = play(player, &args, &run, &generated);
+            return inspect::run(&track, io::stdin().lock(), io::stdout());
+        }
+
+        let make_player = |pattern: Option<usize>| -> anyhow::Result<MidiPlayer> {
+            let player = match pattern {
+                Some(idx) => MidiPlayer::for_pattern(&smf, idx, options.clone())?,
+                None => MidiPlayer::new(&smf, options.clone())?,
+            };
+            Ok(match raw_bytes.as_deref() {
+                Some(raw) => player.with_raw_bytes(raw),
+                None => player,
+            })
+        };
+
+        if let Some(size) = args.events_per_file {
+            violations.extend(write_chunks(
+                make_player(None)?,
+                &args,
+                &run,
+                &generated,
+                size,
+            )?);
+            run.timings.report();
+            return report_violations(&violations, &args);
+        }
+
+        let (mut outfile, sd) = open_output(args.output.clone())?;
+        let serialize = args.serialize_options();
+
+        if args.dump {
+            write!(outfile, "{:#?}", smf).context("write failed")?;
+            return Ok(());
+        }
+
+        if args.patterns {
+            if smf.header.format != midly::Format::Sequential {
+                anyhow::bail!("--patterns requires a sequential (type 2) midi file");
+            }
+
+            let mut patterns = Vec::with_capacity(smf.tracks.len());
+            for idx in 0..smf.tracks.len() {
+                let (track, v) = play(make_player(Some(idx))?, &args, &run, &generated);
+                violations.extend(v);
+                patterns.push(track);
+            }
+
+            write_output(
+                outfile,
+                &args,
+                &run,
+                &serialize.wrap(&model::Patterns { patterns }),
+            )?;
+        } else if !args.also.is_empty() {
+            let (mut track, summary, v) =
+                play_and_summarize(make_player(None)?, &args, &run, &generated);
+            violations.extend(v);
+            if args.reverse {
+                reverse_events(&mut track.events, args.delta);
+            }
+
+            // --output is required with --also
+            let output = args.output.as_deref().expect("no output file given");
+            for &also in args.also.iter() {
+                let (outfile, sd) = open_output(Some(also_path(output, also)))?;
+                match also {
+                    Also::Format(OutputFormat::Ndjson) => {
+                        write_ndjson(outfile, &args, &run, &track.events)?
+                    },
+                    Also::Format(format) => {
+                        write_output_as(outfile, &args, &run, format, &serialize.wrap(&track))?
+                    },
+                    Also::Summary => {
+                        write_output_as(outfile, &args, &run, OutputFormat::Json, &summary)?
+                    },
+                }
+                if let Some((s, d)) = sd {
+                    fs::rename(s, d).context("failed to move tmp file over target")?;
+                }
+            }
+            write_track(outfile, &args, &run, track, &summary)?;
+        } else if args.summary {
+            let (summary, v) = summarize(make_player(None)?, &args, &run, &generated);
+            violations.extend(v);
+            write_output(outfile, &args, &run, &summary)?;
+        } else if args.format == OutputFormat::Ndjson {
+            violations.extend(stream_events(make_player(None)?, outfile, &args, &run)?);
+        } else {
+            let (mut track, v) = play(make_player(None)?, &args, &run, &generated);
+            violations.extend(v);
+            if args.reverse {
+                reverse_events(&mut track.events, args.delta);
+            }
+            if args.format == OutputFormat::Tonejs {
+                write_tonejs(outfile, &args, &run, &track)?;
+            } else if args.format == OutputFormat::Pianoroll {
+                write_pianoroll(outfile, &args, &run, &track)?;
+            } else if args.group_by_channel {
+                write_output(
+                    outfile,
+                    &args,
+                    &run,
+                    &serialize.wrap(&track.group_by_channel()),
+                )?;
+            } else if args.combine_channels_into_tracks {
+                write_output(
+                    outfile,
+                    &args,
+                    &run,
+                    &serialize.wrap(&track.combine_channels_into_tracks()),
+                )?;
+            } else if args.split_on_marker {
+                write_output(
+                    outfile,
+                    &args,
+                    &run,
+                    &serialize.wrap(&track.split_on_marker()),
+                )?;
+            } else if args.events_only {
+                write_output(outfile, &args, &run, &serialize.wrap(&track.events))?;
+            } else {
+                write_output(outfile, &args, &run, &serialize.wrap(&track))?;
+            }
+        }
+
+        sd
+    };
+
+    if let Some((s, d)) = sd {
+        fs::rename(s, d).context("failed to move tmp file over target")?;
+    }
+
+    run.timings.report();
+    report_violations(&violations, &args)
+}
+
+/// How to play a single track of a file of the given format with `--track`
+fn single_track(format: midly::Format, track: usize) -> MergeStrategy {
+    match format {
+        midly::Format::Parallel => MergeStrategy::TrackWithTempo(track),
+        _ => MergeStrategy::PerTrack(track),
+    }
+}
+
+/// Reverse the order of played events. With deltas, every event takes over
+/// the delta of the event after it, so its delta is the time back to the
+/// event before it in the reversed list. The first one gets a delta of 0.
+/// `delta_beats` is always a delta and moves the same way
+fn reverse_events(events: &mut [model::Event], delta: bool) {
+    for idx in 1..events.len() {
+        let next = events[idx].time().clone();
+        let time = events[idx - 1].time_mut();
+        if delta {
+            time.tick = next.tick;
+            time.micros = next.micros;
+            time.seconds = next.seconds;
+        }
+        time.delta_beats = next.delta_beats;
+    }
+    if let Some(last) = events.last_mut() {
+        let time = last.time_mut();
+        if delta {
+            time.tick = 0;
+            time.micros = time.micros.map(|_| 0);
+            time.seconds = time.seconds.map(|_| 0.0);
+        }
+        time.delta_beats = time.delta_beats.map(|beats| beats.map(|_| 0.0));
+    }
+    events.reverse();
+}
+
+/// Print the events that were not played at their tick, failing with
+/// `--validate-deltas`
+fn report_violations(violations: &[DeltaViolation], args: &Args) -> anyhow::Result<()> {
+    if !violations.is_empty() {
+        let level = if args.validate_deltas {
+            "error"
+        } else {
+            "warning"
+        };
+        for v in violations.iter() {
+            eprintln!(
+                "{}: event {} at tick {} is played at tick {}",
+                level, v.index, v.tick, v.played_tick
+            );
+        }
+
+        if args.validate_deltas {
+            anyhow::bail!("{} events are not played at their tick", violations.len());
+        }
+        eprintln!(
+            "warning: {} events are not played at their tick",
+            violations.len()
+        );
+    }
+
+    Ok(())
+}
+
+/// Log to the `debug` file, everything at debug level and above unless
+/// RUST_LOG says otherwise, or to stderr as selected by RUST_LOG. The read,
+/// parse, convert and write phases are logged with their duration when they
+/// end
+fn init_logging(debug: Option<&Path>) -> anyhow::Result<()> {
+    match debug {
+        Some(path) => {
+            let file = fs::File::create(path).context("failed to create debug file")?;
+            tracing_subscriber::fmt()
+                .with_env_filter(
+                    EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("debug")),
+                )
+                .with_span_events(FmtSpan::CLOSE)
+                .with_writer(Mutex::new(file))
+                .with_ansi(false)
+                .init();
+        },
+        None => tracing_subscriber::fmt()
+            .with_env_filter(EnvFilter::from_default_env())
+            .with_span_events(FmtSpan::CLOSE)
+            .with_writer(io::stderr)
+            .init(),
+    }
+    Ok(())
+}
+
+/// Memory taken up by a collected event together with its serialized form,
+/// on the high side of the usual events so estimates err on the safe side
+const EVENT_MEMORY: u64 = 256;
+
+/// Make sure the events of the file fit into `budget` megabytes if they are
+/// collected before writing. Json output is switched to ndjson, which is
+/// written as it is played, anything else fails
+fn check_memory(args: &mut Args, midi_file: &[u8], budget: u64) -> anyhow::Result<()> {
+    let collects = !args.also.is_empty()
+        || args.inspect
+        || (args.format != OutputFormat::Ndjson
+            && !args.summary
+            && !args.dump
+            && !args.play
+            && args.events_per_file.is_none());
+    if !collects {
+        return Ok(());
+    }
+
+    // counted without keeping the events, as the lazy parser reads them
+    let (_, tracks) = midly::parse(midi_file).context("failed to parse midi file header")?;
+    let mut events = 0u64;
+    for track in tracks {
+        events += track.map_or(0, |track| track.take_while(Result::is_ok).count() as u64);
+    }
+    let needed = events * EVENT_MEMORY / (1024 * 1024);
+    tracing::debug!(events, needed, budget, "estimated memory");
+    if needed <= budget {
+        return Ok(());
+    }
+
+    let streamable = args.format == OutputFormat::Json
+        && !args.pretty
+        && !args.checksum
+        && ndjson_conflict(args).is_none()
+        && args.also.is_empty()
+        && !args.inspect;
+    if !streamable {
+        anyhow::bail!(
+            "the {} events of the file would need about {}MB, more than the {}MB of --max-memory. \
+             Stream them with --format ndjson or split them up with --events-per-file",
+            events,
+            needed,
+            budget
+        );
+    }
+
+    eprintln!(
+        "warning: the {} events of the file would need about {}MB, more than the {}MB of \
+         --max-memory, writing ndjson instead",
+        events, needed, budget
+    );
+    args.format = OutputFormat::Ndjson;
+    Ok(())
+}
+
+/// Resolve a relative output path against the directory of the input file.
+/// Absolute paths are kept as they are
+/// The first flag that shapes the output as a whole, which ndjson cannot do
+/// as it writes one event at a time
+fn ndjson_conflict(args: &Args) -> Option<&'static str> {
+    [
+        (args.summary, "--summary"),
+        (args.patterns, "--patterns"),
+        (args.group_by_channel, "--group-by-channel"),
+        (
+            args.combine_channels_into_tracks,
+            "--combine-channels-into-tracks",
+        ),
+        (args.reverse, "--reverse"),
+        (args.split_on_marker, "--split-on-marker"),
+    ]
+    .into_iter()
+    .find_map(|(set, flag)| set.then_some(flag))
+}
+
+fn relative_to_input(input: &Path, output: PathBuf) -> PathBuf {
+    match input.parent() {
+        Some(dir) if output.is_relative() => dir.join(output),
+        _ => output,
+    }
+}
+
+/// Temporary output file and the path it is moved to when done
+type TmpFile = (PathBuf, PathBuf);
+
+/// Open the output file, or stdout if there is none. Files are written to a
+/// temporary file first, the returned paths have to be renamed once the output
+/// is complete.
+fn open_output(output: Option<PathBuf>) -> anyhow::Result<(Box<dyn Write>, Option<TmpFile>)> {
+    let sd = match output {
         Some(mut f) => {
             let f1 = f.clone();
 
@@ -96,52 +1580,798 @@ fn main() -> anyhow::Result<()> {
 
     let outfile: Box<dyn Write> = match sd.as_ref() {
         Some((f, _)) => Box::new(fs::File::create(f).context("could not create output file")?),
-        None => Box::new(stdout.lock()),
+        None => Box::new(io::stdout()),
     };
 
-    if args.dump {
-        // rebind outfile as mutable
-        let mut outfile = outfile;
-        write!(outfile, "{:#?}", smf).context("write failed")?;
+    Ok((outfile, sd))
+}
+
+/// Write every emitted event as a single line of json while playing
+fn stream_events(
+    player: MidiPlayer,
+    outfile: Box<dyn Write>,
+    args: &Args,
+    run: &RunContext,
+) -> anyhow::Result<Vec<DeltaViolation>> {
+    let mut outfile = io::BufWriter::new(outfile);
+    let mut player = player.into_iter();
+    let start = Instant::now();
+
+    while let Some(event) = player.next() {
+        if let PlayerResult::Event(event) = event {
+            if args.realtime {
+                // sleep until the event is due relative to the start instead of
+                // by its delta, so time spent writing does not add up
+                let due = Duration::from_micros(player.player().elapsed_micros() as u64);
+                if let Some(wait) = due.checked_sub(start.elapsed()) {
+                    thread::sleep(wait);
+                }
+            }
+
+            write_line(&mut outfile, args, run, &event)?;
+            if args.realtime {
+                outfile.flush().context("write failed")?;
+            }
+        }
+    }
+
+    outfile.flush().context("write failed")?;
+    warn_if_inexact(player.player().elapsed_micros());
+    warn_unknown_events(player.player().unknown_events());
+    Ok(player.player().violations().to_vec())
+}
+
+#[cfg(feature = "midi-out")]
+fn list_ports() -> anyhow::Result<()> {
+    for (idx, name) in midi_out::list_ports()?.iter().enumerate() {
+        println!("{}: {}", idx, name);
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "midi-out"))]
+fn list_ports() -> anyhow::Result<()> {
+    anyhow::bail!("--list-ports needs json_midi to be built with the midi-out feature")
+}
+
+#[cfg(feature = "midi-out")]
+fn play_midi(player: MidiPlayer, port: usize) -> anyhow::Result<()> {
+    let mut conn = midi_out::connect(port)?;
+    midi_out::play(player, &mut conn)
+}
+
+#[cfg(not(feature = "midi-out"))]
+fn play_midi(_player: MidiPlayer, _port: usize) -> anyhow::Result<()> {
+    anyhow::bail!("--play needs json_midi to be built with the midi-out feature")
+}
+
+/// The time the output was generated at, `SOURCE_DATE_EPOCH` takes precedence
+/// over the current time
+fn generated_timestamp(args: &Args) -> anyhow::Result<Option<String>> {
+    if args.no_timestamp {
+        return Ok(None);
+    }
+
+    match std::env::var("SOURCE_DATE_EPOCH") {
+        Ok(epoch) => {
+            let time = epoch
+                .trim()
+                .parse::<i64>()
+                .ok()
+                .and_then(|secs| Utc.timestamp_opt(secs, 0).single())
+                .with_context(|| format!("invalid SOURCE_DATE_EPOCH {:?}", epoch))?;
+            Ok(Some(time.to_rfc3339()))
+        },
+        Err(_) => Ok(Some(Local::now().to_rfc3339())),
+    }
+}
+
+fn summary_builder(args: &Args) -> SummaryBuilder {
+    SummaryBuilder::new(SummaryOptions {
+        channel_activity: args.channel_activity,
+        channel_stats:    args.channel_stats,
+        detect_key:       args.detect_key,
+        energy:           args.energy,
+        program_map:      args.program_map,
+        note_match:       args.note_match,
+    })
+}
+
+/// Run the player to completion and aggregate its events
+fn summarize(
+    player: MidiPlayer,
+    args: &Args,
+    run: &RunContext,
+    generated: &Option<String>,
+) -> (Summary, Vec<DeltaViolation>) {
+    let mut player = player.into_iter();
+    let mut builder = summary_builder(args);
+    let (mut p, mut e) = (0, 0);
+
+    while let Some(event) = player.next() {
+        p += 1;
+        if let PlayerResult::Event(event) = event {
+            e += 1;
+            builder.add(
+                &event,
+                player.player().elapsed().tick,
+                player.player().elapsed_micros(),
+            );
+        }
+    }
+
+    let mut summary = builder.build(
+        generated.clone(),
+        args.source_label(),
+        p,
+        e,
+        player.player().elapsed(),
+        player.player().elapsed_micros(),
+    );
+    summary.source_sha256 = run.source_sha256.clone();
+
+    warn_if_inexact(player.player().elapsed_micros());
+    warn_unknown_events(player.player().unknown_events());
+    (summary, player.player().violations().to_vec())
+}
+
+/// Run the player to completion and collect its events
+fn play(
+    player: MidiPlayer,
+    args: &Args,
+    run: &RunContext,
+    generated: &Option<String>,
+) -> (model::Track, Vec<DeltaViolation>) {
+    let (mut track, violations) = convert::collect_track(
+        player,
+        args.source_label(),
+        generated.clone(),
+        args.meta || args.conductor_only,
+        args.capacity_hint,
+    );
+
+    track.info.source_sha256 = run.source_sha256.clone();
+    if args.program_map {
+        track.info.program_map = Some(gm::program_map(&track.events));
+    }
+    tracing::debug!(
+        processed = track.info.events_processed,
+        emitted = track.info.events_emitted,
+        "played file"
+    );
+    warn_if_inexact(track.info.duration.micros.unwrap_or(0) as f64);
+    warn_unknown_events(track.info.unknown_events);
+    (track, violations)
+}
+
+/// Run the player to completion, collecting its events and aggregating them
+/// into a summary at the same time
+fn play_and_summarize(
+    player: MidiPlayer,
+    args: &Args,
+    run: &RunContext,
+    generated: &Option<String>,
+) -> (model::Track, Summary, Vec<DeltaViolation>) {
+    let mut builder = summary_builder(args);
+    let mut end_micros = 0.0;
+    let (mut track, violations) = convert::collect_track_with(
+        player,
+        args.source_label(),
+        generated.clone(),
+        args.meta || args.conductor_only,
+        args.capacity_hint,
+        |event, player| {
+            end_micros = player.elapsed_micros();
+            builder.add(event, player.elapsed().tick, end_micros);
+        },
+    );
+
+    let mut summary = builder.build(
+        track.info.generated.clone(),
+        track.info.source_file.clone(),
+        track.info.events_processed,
+        track.info.events_emitted,
+        track.info.duration.clone(),
+        end_micros,
+    );
+
+    track.info.source_sha256 = run.source_sha256.clone();
+    summary.source_sha256 = track.info.source_sha256.clone();
+    track.info.program_map = summary.program_map.clone();
+    warn_if_inexact(end_micros);
+    warn_unknown_events(track.info.unknown_events);
+    (track, summary, violations)
+}
+
+fn warn_live_messages(count: usize) {
+    if count > 0 {
+        eprintln!(
+            "note: {} realtime or system common messages are stored in escape events, pass \
+             --live-messages to emit them",
+            count
+        );
+    }
+}
+
+/// Play the file without output to find the factor that makes it `target`
+/// seconds long
+fn retime_factor(player: MidiPlayer, target: f64) -> anyhow::Result<f64> {
+    let mut player = player.into_iter();
+    for _ in player.by_ref() {}
+
+    let length = player.player().elapsed_micros();
+    if length <= 0.0 {
+        anyhow::bail!("--target-duration cannot be used with a file without length");
+    }
+    Ok(target * model::MICROS_PER_SECOND as f64 / length)
+}
+
+/// Play the file once and fail if any of its events is not emitted, listing
+/// them, for --strict
+fn check_strict(player: MidiPlayer) -> anyhow::Result<()> {
+    let mut player = player.into_iter();
+    for _ in player.by_ref() {}
+
+    let ignored = player.player().ignored_events();
+    if ignored.is_empty() {
         return Ok(());
     }
+    for event in ignored.iter() {
+        eprintln!(
+            "error: event {} of track {} at tick {} would be dropped: {}",
+            event.index, event.track, event.tick, event.kind
+        );
+    }
+    anyhow::bail!(
+        "{} events would be dropped, see the list above",
+        ignored.len()
+    )
+}
 
-    let player = MidiPlayer::new(&smf, args.meta, args.delta);
+/// Parse a length in seconds with an optional unit: `120`, `120s`, `1500ms`
+/// or `2m`
+fn parse_seconds(s: &str) -> anyhow::Result<f64> {
+    let (value, scale) = if let Some(ms) = s.strip_suffix("ms") {
+        (ms, 0.001)
+    } else if let Some(secs) = s.strip_suffix('s') {
+        (secs, 1.0)
+    } else if let Some(mins) = s.strip_suffix('m') {
+        (mins, 60.0)
+    } else {
+        (s, 1.0)
+    };
 
-    let (p, e, ev) = player
-        .into_iter()
-        .fold((0, 0, Vec::new()), |(mut p, mut e, mut ev), ne| {
-            match ne {
-                PlayerResult::Event(v) => {
-                    p += 1;
-                    e += 1;
-                    ev.push(v);
-                },
-                PlayerResult::Ignored => {
-                    p += 1;
+    let seconds = value
+        .trim()
+        .parse::<f64>()
+        .with_context(|| format!("invalid length {:?}", s))?
+        * scale;
+    if !seconds.is_finite() || seconds <= 0.0 {
+        anyhow::bail!("length {:?} must be positive", s);
+    }
+    Ok(seconds)
+}
+
+/// Decompress a gzip compressed midi file, other files are returned as they
+/// are
+fn decompress(midi_file: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+    if !midi_file.starts_with(&GZIP_MAGIC) {
+        return Ok(midi_file);
+    }
+
+    let mut data = Vec::new();
+    MultiGzDecoder::new(midi_file.as_slice())
+        .read_to_end(&mut data)
+        .context("failed to decompress gzipped midi file")?;
+    Ok(data)
+}
+
+/// Parse a thinning interval in milliseconds
+fn parse_interval(s: &str) -> anyhow::Result<f64> {
+    let ms = s
+        .trim()
+        .parse::<f64>()
+        .with_context(|| format!("invalid interval {:?}", s))?;
+    if !ms.is_finite() || ms <= 0.0 {
+        anyhow::bail!("interval {:?} must be a positive number of milliseconds", s);
+    }
+    Ok(ms)
+}
+
+fn warn_unknown_events(count: usize) {
+    if count > 0 {
+        eprintln!(
+            "warning: {} meta events of an unknown type were found, they are only emitted with \
+             --meta or --emit-unknown",
+            count
+        );
+    }
+}
+
+/// Times past 2^53 micros can not be represented exactly
+fn warn_if_inexact(micros: f64) {
+    if model::is_inexact(micros) {
+        eprintln!(
+            "warning: the file is longer than 2^53 microseconds (about 285 years), micros past \
+             that point are not exact"
+        );
+    }
+}
+
+/// Write the primary output of a fully collected track, as selected by the
+/// output flags
+fn write_track(
+    outfile: Box<dyn Write>,
+    args: &Args,
+    run: &RunContext,
+    track: model::Track,
+    summary: &Summary,
+) -> anyhow::Result<()> {
+    let serialize = args.serialize_options();
+    if args.summary {
+        write_output(outfile, args, run, summary)
+    } else if args.format == OutputFormat::Ndjson {
+        write_ndjson(outfile, args, run, &track.events)
+    } else if args.format == OutputFormat::Tonejs {
+        write_tonejs(outfile, args, run, &track)
+    } else if args.format == OutputFormat::Pianoroll {
+        write_pianoroll(outfile, args, run, &track)
+    } else if args.group_by_channel {
+        write_output(
+            outfile,
+            args,
+            run,
+            &serialize.wrap(&track.group_by_channel()),
+        )
+    } else if args.combine_channels_into_tracks {
+        write_output(
+            outfile,
+            args,
+            run,
+            &serialize.wrap(&track.combine_channels_into_tracks()),
+        )
+    } else if args.split_on_marker {
+        write_output(
+            outfile,
+            args,
+            run,
+            &serialize.wrap(&track.split_on_marker()),
+        )
+    } else if args.events_only {
+        write_output(outfile, args, run, &serialize.wrap(&track.events))
+    } else {
+        write_output(outfile, args, run, &serialize.wrap(&track))
+    }
+}
+
+/// Write a track played with absolute times and meta events in the shape of
+/// @tonejs/midi
+fn write_tonejs(
+    outfile: Box<dyn Write>,
+    args: &Args,
+    run: &RunContext,
+    track: &model::Track,
+) -> anyhow::Result<()> {
+    let ppq = match track.info.header.division {
+        model::Division::Metrical { ppqn } => args.ppqn.unwrap_or(ppqn),
+        model::Division::Timecode { .. } => {
+            anyhow::bail!("tonejs output needs a file with metrical timing")
+        },
+    };
+    write_output_as(
+        outfile,
+        args,
+        run,
+        OutputFormat::Json,
+        &tonejs::Midi::from_track(track, ppq, args.note_match),
+    )
+}
+
+/// Write the piano roll of a track played with absolute ticks
+fn write_pianoroll(
+    outfile: Box<dyn Write>,
+    args: &Args,
+    run: &RunContext,
+    track: &model::Track,
+) -> anyhow::Result<()> {
+    let low_note = args.pianoroll_low.unwrap_or(0);
+    let high_note = args.pianoroll_high.unwrap_or(127);
+    if low_note > high_note || high_note > 127 {
+        anyhow::bail!("the piano roll notes must not be empty and end at 127 at most");
+    }
+
+    // a sixteenth note, or a frame for SMPTE timed files
+    let resolution = args
+        .pianoroll_resolution
+        .unwrap_or(match track.info.header.division {
+            model::Division::Metrical { ppqn } => (args.ppqn.unwrap_or(ppqn) / 4).max(1) as u64,
+            model::Division::Timecode { tpf, .. } => tpf.max(1) as u64,
+        });
+    if resolution == 0 {
+        anyhow::bail!("--pianoroll-resolution must be at least 1");
+    }
+
+    write_output_as(
+        outfile,
+        args,
+        run,
+        OutputFormat::Json,
+        &PianoRoll::from_track(track, &PianoRollOptions {
+            resolution,
+            low_note,
+            high_note,
+            note_match: args.note_match,
+            blob: args.pianoroll_blob,
+        }),
+    )
+}
+
+/// The file an additional output is written to: the output file with the
+/// extension of the format, `.summary.json` for summaries
+fn also_path(output: &Path, also: Also) -> PathBuf {
+    match also {
+        Also::Format(format) => output.with_extension(format.extension()),
+        Also::Summary => output.with_extension("summary.json"),
+    }
+}
+
+/// The file chunk `idx` of `--events-per-file` is written to: the output file
+/// with the chunk number before its extension
+fn chunk_path(output: &Path, idx: usize) -> PathBuf {
+    let stem = output
+        .file_stem()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_string();
+    let name = match output.extension() {
+        Some(ext) => format!("{}.{}.{}", stem, idx, ext.to_string_lossy()),
+        None => format!("{}.{}", stem, idx),
+    };
+    output.with_file_name(name)
+}
+
+/// Play the file and write its events in chunks of `size` events, every chunk
+/// to its own file as a complete track. A chunk is written as soon as it is
+/// full, so only one chunk is kept in memory. Events ignored after the last
+/// emitted one count towards the last chunk.
+fn write_chunks(
+    player: MidiPlayer,
+    args: &Args,
+    run: &RunContext,
+    generated: &Option<String>,
+    size: usize,
+) -> anyhow::Result<Vec<DeltaViolation>> {
+    // --output is required with --events-per-file
+    let output = args.output.as_deref().expect("no output file given");
+    let source_file = args.source_label();
+    let header = player.header().clone();
+    let serialize = args.serialize_options();
+    let write_chunk = |idx: usize,
+                       events: Vec<model::Event>,
+                       processed: usize,
+                       (unknown, invalid): (usize, usize),
+                       duration: model::TimeInfo|
+     -> anyhow::Result<()> {
+        let (outfile, sd) = open_output(Some(chunk_path(output, idx)))?;
+        if args.format == OutputFormat::Ndjson {
+            write_ndjson(outfile, args, run, &events)?;
+        } else if args.events_only {
+            write_output(outfile, args, run, &serialize.wrap(&events))?;
+        } else {
+            let track = model::Track {
+                info: model::TrackInfo {
+                    generated: generated.clone(),
+                    source_file: source_file.clone(),
+                    source_sha256: run.source_sha256.clone(),
+                    header: header.clone(),
+                    events_processed: processed,
+                    events_emitted: events.len(),
+                    unknown_events: unknown,
+                    invalid_events: args.sanitize.then_some(invalid),
+                    emitted_meta: args.meta || args.conductor_only,
+                    duration,
+                    program_map: args.program_map.then(|| gm::program_map(&events)),
+                    snapshot: None,
                 },
+                events,
             };
-            (p, e, ev)
-        });
+            write_output(outfile, args, run, &serialize.wrap(&track))?;
+        }
+        if let Some((s, d)) = sd {
+            fs::rename(s, d).context("failed to move tmp file over target")?;
+        }
+        Ok(())
+    };
 
-    let track = model::Track {
-        generated:        Local::now().to_rfc3339(),
-        source_file:      format!("{}", args.midi_file.display()),
-        events_processed: p,
-        events_emitted:   e,
-        emitted_meta:     args.meta,
-        events:           ev,
+    let mut player = player.into_iter();
+    let (mut idx, mut processed, mut events) = (0, 0, Vec::with_capacity(size));
+    let mut duration = player.player().elapsed();
+    // unknown and invalid events played before the current event and before
+    // the chunk
+    let dropped = |player: &MidiPlayer| {
+        (
+            player.unknown_events(),
+            player.invalid_events().unwrap_or(0),
+        )
     };
+    let (mut before, mut chunk_before) = ((0, 0), (0, 0));
 
-    if args.pretty {
-        serde_json::to_writer_pretty(outfile, &track).context("failed to serialize data")?;
-    } else {
-        serde_json::to_writer(outfile, &track).context("failed to serialize data")?;
+    while let Some(result) = player.next() {
+        processed += 1;
+        if let PlayerResult::Event(event) = result {
+            if events.len() == size {
+                // the event that starts the next chunk was already counted
+                let full = std::mem::replace(&mut events, Vec::with_capacity(size));
+                let counts = (before.0 - chunk_before.0, before.1 - chunk_before.1);
+                write_chunk(idx, full, processed - 1, counts, duration)?;
+                idx += 1;
+                processed = 1;
+                chunk_before = before;
+            }
+            events.push(event);
+            duration = player.player().elapsed();
+        }
+        before = dropped(player.player());
     }
 
-    if let Some((s, d)) = sd {
-        fs::rename(s, d).context("failed to move tmp file over target")?;
+    // the last chunk is always written, an empty file still gives one chunk
+    let total = dropped(player.player());
+    let counts = (total.0 - chunk_before.0, total.1 - chunk_before.1);
+    write_chunk(idx, events, processed, counts, player.player().elapsed())?;
+    warn_if_inexact(player.player().elapsed_micros());
+    warn_unknown_events(player.player().unknown_events());
+    Ok(player.player().violations().to_vec())
+}
+
+/// Write collected events as ndjson, one event per line
+fn write_ndjson(
+    outfile: Box<dyn Write>,
+    args: &Args,
+    run: &RunContext,
+    events: &[model::Event],
+) -> anyhow::Result<()> {
+    let _span = tracing::debug_span!("write", format = "ndjson", events = events.len()).entered();
+    let mut outfile = io::BufWriter::new(outfile);
+    for event in events {
+        write_line(&mut outfile, args, run, event)?;
     }
+    outfile.flush().context("write failed")
+}
 
+fn write_line(
+    outfile: &mut impl Write,
+    args: &Args,
+    run: &RunContext,
+    event: &model::Event,
+) -> anyhow::Result<()> {
+    let start = run.timings.now();
+    let event = args.serialize_options().wrap(event);
+    if args.sort_keys {
+        let event = serde_json::to_value(event).context("failed to serialize data")?;
+        serde_json::to_writer(&mut *outfile, &event).context("failed to serialize data")?;
+    } else {
+        serde_json::to_writer(&mut *outfile, &event).context("failed to serialize data")?;
+    }
+    outfile.write_all(b"\n").context("write failed")?;
+    run.timings.add(&run.timings.write, start);
     Ok(())
 }
+
+fn write_output<T: serde::Serialize>(
+    outfile: Box<dyn Write>,
+    args: &Args,
+    run: &RunContext,
+    value: &T,
+) -> anyhow::Result<()> {
+    write_output_as(outfile, args, run, args.format, value)
+}
+
+fn write_output_as<T: serde::Serialize>(
+    outfile: Box<dyn Write>,
+    args: &Args,
+    run: &RunContext,
+    format: OutputFormat,
+    value: &T,
+) -> anyhow::Result<()> {
+    let start = run.timings.now();
+    if args.sort_keys {
+        // serde_json's map is ordered by key
+        let value = serde_json::to_value(value).context("failed to serialize data")?;
+        write_formatted(outfile, args, format, &value)?;
+    } else {
+        write_formatted(outfile, args, format, value)?;
+    }
+    run.timings.add(&run.timings.write, start);
+    Ok(())
+}
+
+fn write_formatted<T: serde::Serialize>(
+    outfile: Box<dyn Write>,
+    args: &Args,
+    format: OutputFormat,
+    value: &T,
+) -> anyhow::Result<()> {
+    let _span = tracing::debug_span!("write", ?format).entered();
+    let mut outfile = io::BufWriter::new(outfile);
+    match format {
+        OutputFormat::Json if args.pretty => {
+            serde_json::to_writer_pretty(&mut outfile, value).context("failed to serialize data")?
+        },
+        OutputFormat::Json => {
+            serde_json::to_writer(&mut outfile, value).context("failed to serialize data")?
+        },
+        OutputFormat::Cbor => {
+            ciborium::ser::into_writer(value, &mut outfile).context("failed to serialize data")?
+        },
+        OutputFormat::Ndjson => anyhow::bail!("ndjson output can only be used for event streams"),
+        OutputFormat::Tonejs => anyhow::bail!("tonejs output can only be written for a whole file"),
+        OutputFormat::Pianoroll => {
+            anyhow::bail!("pianoroll output can only be written for a whole file")
+        },
+    }
+    outfile.flush().context("write failed")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A single track file with one note
+    fn midi_file() -> Vec<u8> {
+        #[rustfmt::skip]
+        let track: &[u8] = &[
+            0x00, 0x90, 60, 100,
+            0x83, 0x60, 0x80, 60, 64,
+            0x00, 0xFF, 0x2F, 0x00,
+        ];
+        let mut bytes = b"MThd\0\0\0\x06\0\0\0\x01\x01\xE0MTrk".to_vec();
+        bytes.extend((track.len() as u32).to_be_bytes());
+        bytes.extend(track);
+        bytes
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn gzipped_input() {
+        let plain = midi_file();
+        assert_eq!(decompress(plain.clone()).unwrap(), plain);
+
+        let data = decompress(gzip(&plain)).unwrap();
+        assert_eq!(data, plain);
+        let smf = midly::Smf::parse(&data).unwrap();
+        assert_eq!(smf.tracks[0].len(), 3);
+
+        // concatenated gzip members, as written by appending to a .gz file
+        let (first, second) = plain.split_at(10);
+        let mut members = gzip(first);
+        members.extend(gzip(second));
+        assert_eq!(decompress(members).unwrap(), plain);
+
+        let mut corrupt = gzip(&plain);
+        corrupt.truncate(corrupt.len() / 2);
+        assert!(decompress(corrupt).is_err());
+    }
+
+    #[test]
+    fn retime_to_target_duration() {
+        let data = midi_file();
+        let smf = midly::Smf::parse(&data).unwrap();
+        let player = || MidiPlayer::new(&smf, PlayerOptions::default()).unwrap();
+
+        // the note off is at half a second
+        assert_eq!(retime_factor(player(), 2.0).unwrap(), 4.0);
+        assert_eq!(retime_factor(player(), 0.25).unwrap(), 0.5);
+
+        let retimed = MidiPlayer::new(&smf, PlayerOptions {
+            retime: Some(4.0),
+            ..Default::default()
+        })
+        .unwrap();
+        let times = retimed
+            .into_iter()
+            .filter_map(|result| match result {
+                PlayerResult::Event(event) => Some(event.time().clone()),
+                PlayerResult::Ignored => None,
+            })
+            .map(|time| (time.tick, time.micros, time.seconds))
+            .collect::<Vec<_>>();
+        // ticks stay the same
+        assert_eq!(times, vec![
+            (0, Some(0), Some(0.0)),
+            (480, Some(2_000_000), Some(2.0))
+        ]);
+    }
+
+    #[test]
+    fn retime_without_length() {
+        let smf = midly::Smf {
+            header: midly::Header::new(
+                midly::Format::SingleTrack,
+                midly::Timing::Metrical(480.into()),
+            ),
+            tracks: vec![vec![midly::TrackEvent {
+                delta: 0.into(),
+                kind:  midly::TrackEventKind::Meta(midly::MetaMessage::EndOfTrack),
+            }]],
+        };
+        let player = MidiPlayer::new(&smf, PlayerOptions::default()).unwrap();
+        assert!(retime_factor(player, 2.0).is_err());
+    }
+
+    #[test]
+    fn retime_conflicts_with_target_duration() {
+        let args =
+            |extra: &[&str]| Args::from_iter_safe(["json_midi", "song.mid"].iter().chain(extra));
+
+        assert_eq!(args(&["--retime", "0.5"]).unwrap().retime, Some(0.5));
+        assert_eq!(
+            args(&["--target-duration", "2m"]).unwrap().target_duration,
+            Some(120.0)
+        );
+        assert!(args(&["--retime", "0.5", "--target-duration", "10"]).is_err());
+    }
+
+    #[test]
+    fn lengths() {
+        assert_eq!(parse_seconds("120").unwrap(), 120.0);
+        assert_eq!(parse_seconds("120s").unwrap(), 120.0);
+        assert_eq!(parse_seconds("1500ms").unwrap(), 1.5);
+        assert_eq!(parse_seconds("2m").unwrap(), 120.0);
+        for invalid in ["0", "-1s", "NaN", "infm", "2h", ""] {
+            assert!(
+                parse_seconds(invalid).is_err(),
+                "{:?} was accepted",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn intervals() {
+        assert_eq!(parse_interval("10").unwrap(), 10.0);
+        assert_eq!(parse_interval("2.5").unwrap(), 2.5);
+        for invalid in ["NaN", "inf", "-inf", "-5", "0", "", "10ms"] {
+            assert!(
+                parse_interval(invalid).is_err(),
+                "{:?} was accepted",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn patterns_are_not_streamed() {
+        let args =
+            |extra: &[&str]| Args::from_iter_safe(["json_midi", "song.mid"].iter().chain(extra));
+
+        let ndjson = args(&["--patterns", "--format", "ndjson"]).unwrap();
+        assert_eq!(ndjson_conflict(&ndjson), Some("--patterns"));
+        assert_eq!(
+            ndjson_conflict(&args(&["--format", "ndjson"]).unwrap()),
+            None
+        );
+
+        // enough notes for a few MB, so any budget of 0MB is exceeded
+        let mut track = Vec::new();
+        for _ in 0..10_000 {
+            track.extend([0x00, 0x90, 60, 100]);
+        }
+        track.extend([0x00, 0xFF, 0x2F, 0x00]);
+        let mut midi_file = b"MThd\0\0\0\x06\0\0\0\x01\x01\xE0MTrk".to_vec();
+        midi_file.extend((track.len() as u32).to_be_bytes());
+        midi_file.extend(track);
+
+        let mut json = args(&[]).unwrap();
+        check_memory(&mut json, &midi_file, 0).unwrap();
+        assert!(json.format == OutputFormat::Ndjson);
+
+        let mut patterns = args(&["--patterns"]).unwrap();
+        assert!(check_memory(&mut patterns, &midi_file, 0).is_err());
+        assert!(patterns.format == OutputFormat::Json);
+    }
+}
@@ -0,0 +1,106 @@
+use anyhow::anyhow;
+use midir::{MidiOutput, MidiOutputConnection};
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    model::{Event, MidiEvent, PlayerResult},
+    player::MidiPlayer,
+};
+
+const CLIENT_NAME: &str = "json_midi";
+
+/// Names of the available output ports, in the order used for port indices
+pub fn list_ports() -> anyhow::Result<Vec<String>> {
+    let output = MidiOutput::new(CLIENT_NAME).map_err(|e| anyhow!("failed to open midi: {}", e))?;
+
+    output
+        .ports()
+        .iter()
+        .map(|port| {
+            output
+                .port_name(port)
+                .map_err(|e| anyhow!("failed to get port name: {}", e))
+        })
+        .collect()
+}
+
+/// Connect to the output port with the given index
+pub fn connect(port: usize) -> anyhow::Result<MidiOutputConnection> {
+    let output = MidiOutput::new(CLIENT_NAME).map_err(|e| anyhow!("failed to open midi: {}", e))?;
+    let ports = output.ports();
+    let port = ports.get(port).ok_or_else(|| {
+        anyhow!(
+            "midi output port {} does not exist, there are {} ports",
+            port,
+            ports.len()
+        )
+    })?;
+
+    output
+        .connect(port, CLIENT_NAME)
+        .map_err(|e| anyhow!("failed to connect to midi output port: {}", e))
+}
+
+/// Send the channel events of a player to a port, each at the time it is due.
+/// Meta and SysEx events are skipped.
+pub fn play(player: MidiPlayer, conn: &mut MidiOutputConnection) -> anyhow::Result<()> {
+    let mut player = player.into_iter();
+    let start = Instant::now();
+
+    while let Some(event) = player.next() {
+        let PlayerResult::Event(Event::Midi { data, .. }) = event else {
+            continue;
+        };
+        if let MidiEvent::Rest { .. } = data {
+            continue;
+        }
+
+        let due = Duration::from_micros(player.player().elapsed_micros() as u64);
+        if let Some(wait) = due.checked_sub(start.elapsed()) {
+            thread::sleep(wait);
+        }
+
+        conn.send(&encode(&data))
+            .map_err(|e| anyhow!("failed to send midi message: {}", e))?;
+    }
+
+    Ok(())
+}
+
+/// Turn an event back into the bytes of a midi message
+fn encode(event: &MidiEvent) -> Vec<u8> {
+    match *event {
+        MidiEvent::NoteOff {
+            chan,
+            note,
+            velocity,
+            ..
+        } => vec![0x80 | chan, note, velocity],
+        MidiEvent::NoteOn {
+            chan,
+            note,
+            velocity,
+            ..
+        } => vec![0x90 | chan, note, velocity],
+        MidiEvent::Aftertouch {
+            chan,
+            note,
+            velocity,
+            ..
+        } => vec![0xA0 | chan, note, velocity],
+        MidiEvent::Controller { chan, ctrl, value } => vec![0xB0 | chan, ctrl, value],
+        MidiEvent::ProgramChange { chan, program } => vec![0xC0 | chan, program],
+        MidiEvent::ChannelAftertouch { chan, velocity, .. } => vec![0xD0 | chan, velocity],
+        MidiEvent::PitchBend { chan, bend_by } => {
+            vec![0xE0 | chan, (bend_by & 0x7F) as u8, (bend_by >> 7) as u8]
+        },
+        // the exact value is lost when decoding, send fully pressed or released
+        MidiEvent::Sustain { chan, down } => vec![0xB0 | chan, 64, if down { 127 } else { 0 }],
+        MidiEvent::Sostenuto { chan, down } => vec![0xB0 | chan, 66, if down { 127 } else { 0 }],
+        MidiEvent::Soft { chan, down } => vec![0xB0 | chan, 67, if down { 127 } else { 0 }],
+        MidiEvent::Rest { .. } => unreachable!("rests are not midi messages"),
+    }
+}
@@ -1,21 +1,257 @@
+use itertools::Itertools;
 use midly::TrackEvent;
+use serde::{ser::Impossible, Serialize};
+use std::collections::BTreeMap;
 
-#[derive(Debug, serde::Serialize)]
+use crate::{
+    gm::{self, ProgramMap, DRUM_CHANNEL},
+    snapshot::Snapshot,
+    tempo,
+};
+
+#[derive(Debug)]
 pub struct Track {
-    pub generated:        String,
+    pub info:   TrackInfo,
+    pub events: Vec<Event>,
+}
+
+/// Everything about a conversion but its events, shared by [`Track`] and the
+/// ways of splitting it up
+#[derive(Debug, serde::Serialize)]
+pub struct TrackInfo {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub generated:        Option<String>,
     pub source_file:      String,
+    /// SHA-256 of the midi file as read, compressed or not, with `--checksum`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source_sha256:    Option<String>,
+    pub header:           HeaderInfo,
     pub events_processed: usize,
     pub events_emitted:   usize,
+    /// Meta events of an unknown type, see [`MetaEvent::Unknown`]. They are
+    /// counted even when meta events are not emitted
+    pub unknown_events:   usize,
+    /// Events dropped as out of spec, only with `--sanitize`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub invalid_events:   Option<usize>,
     pub emitted_meta:     bool,
-    pub events:           Vec<Event>,
+    /// Absolute time of the last emitted event
+    pub duration:         TimeInfo,
+    /// The instrument of every channel, see [`ProgramMap`]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub program_map:      Option<ProgramMap>,
+    /// The state of playback at `--snapshot-tick`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub snapshot:         Option<Snapshot>,
+}
+
+/// The header of a midi file
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct HeaderInfo {
+    pub format:     Format,
+    pub num_tracks: usize,
+    pub division:   Division,
+}
+
+impl HeaderInfo {
+    pub fn new(header: midly::Header, num_tracks: usize) -> Self {
+        Self {
+            format: match header.format {
+                midly::Format::SingleTrack => Format::SingleTrack,
+                midly::Format::Parallel => Format::Parallel,
+                midly::Format::Sequential => Format::Sequential,
+            },
+            num_tracks,
+            division: match header.timing {
+                midly::Timing::Metrical(ppqn) => Division::Metrical {
+                    ppqn: ppqn.as_int(),
+                },
+                midly::Timing::Timecode(fps, tpf) => Division::Timecode {
+                    fps: fps.as_f32(),
+                    tpf,
+                },
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Format {
+    SingleTrack,
+    Parallel,
+    Sequential,
+}
+
+/// How ticks are defined
+#[derive(Debug, Clone, Copy, serde::Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Division {
+    /// `ppqn` ticks per quarter note
+    Metrical { ppqn: u16 },
+    /// `tpf` ticks per SMPTE frame at `fps` frames per second
+    Timecode { fps: f32, tpf: u8 },
+}
+
+/// The independent patterns of a sequential file
+#[derive(Debug)]
+pub struct Patterns {
+    pub patterns: Vec<Track>,
+}
+
+/// A [`Track`] with its events split up by channel. Timestamps stay absolute,
+/// so the streams can be merged again by sorting on the tick.
+#[derive(Debug)]
+pub struct ChannelTrack {
+    pub info:     TrackInfo,
+    pub channels: BTreeMap<u8, Vec<Event>>,
+    /// Events without a channel: meta and SysEx events
+    pub meta:     Vec<Event>,
+}
+
+/// A [`Track`] with its events split into one track per channel, the way a
+/// DAW shows them. Events keep the source track they came from in `track`
+#[derive(Debug)]
+pub struct ChannelTracks {
+    pub info:   TrackInfo,
+    /// Ordered by channel, only channels with events
+    pub tracks: Vec<ChannelTrackEntry>,
+    /// Events without a channel: meta and SysEx events
+    pub meta:   Vec<Event>,
+}
+
+/// The events of a single channel
+#[derive(Debug)]
+pub struct ChannelTrackEntry {
+    pub channel:    u8,
+    /// `Channel 1` to `Channel 16`, counted from 1 like DAWs do
+    pub label:      String,
+    /// The first program change on the channel, `null` if there is none
+    pub program:    Option<u8>,
+    /// General MIDI name of `program`, the drum channel is always a drum kit
+    pub instrument: Option<&'static str>,
+    pub events:     Vec<Event>,
+}
+
+/// A [`Track`] with its events split into sections at its markers
+#[derive(Debug)]
+pub struct Segments {
+    pub info:     TrackInfo,
+    pub segments: Vec<Segment>,
+}
+
+/// The events from a marker up to the next one
+#[derive(Debug)]
+pub struct Segment {
+    /// The text of the marker, `null` for the events before the first marker
+    pub name:       Option<String>,
+    pub start_tick: u64,
+    /// Starting with the marker itself
+    pub events:     Vec<Event>,
+}
+
+impl Track {
+    /// Split the events into a segment at every marker, see [`Segments`].
+    /// Events before the first marker go into an unnamed segment, which is
+    /// left out if there are none. The ticks have to be absolute
+    pub fn split_on_marker(self) -> Segments {
+        let mut segments = vec![Segment {
+            name:       None,
+            start_tick: 0,
+            events:     Vec::new(),
+        }];
+
+        for event in self.events {
+            if let Event::Meta {
+                data: MetaEvent::Marker(name),
+                time,
+                ..
+            } = &event
+            {
+                segments.push(Segment {
+                    name:       Some(String::from_utf8_lossy(name).into_owned()),
+                    start_tick: time.tick,
+                    events:     Vec::new(),
+                });
+            }
+            // the list always has the unnamed segment
+            segments.last_mut().unwrap().events.push(event);
+        }
+
+        if segments.len() > 1 && segments[0].events.is_empty() {
+            segments.remove(0);
+        }
+
+        Segments {
+            info: self.info,
+            segments,
+        }
+    }
+
+    /// Split the events into one track per channel, see [`ChannelTracks`]
+    pub fn combine_channels_into_tracks(self) -> ChannelTracks {
+        let mut tracks = BTreeMap::<u8, ChannelTrackEntry>::new();
+        let mut meta = Vec::new();
+
+        for event in self.events {
+            let Event::Midi { data, .. } = &event else {
+                meta.push(event);
+                continue;
+            };
+
+            let chan = data.channel();
+            let entry = tracks.entry(chan).or_insert_with(|| ChannelTrackEntry {
+                channel:    chan,
+                label:      format!("Channel {}", chan + 1),
+                program:    None,
+                instrument: (chan == DRUM_CHANNEL).then_some("Drum Kit"),
+                events:     Vec::new(),
+            });
+            if let (None, MidiEvent::ProgramChange { program, .. }) = (entry.program, data) {
+                entry.program = Some(*program);
+                entry.instrument = Some(gm::instrument_name(chan, *program));
+            }
+            entry.events.push(event);
+        }
+
+        ChannelTracks {
+            info: self.info,
+            tracks: tracks.into_values().collect(),
+            meta,
+        }
+    }
+
+    pub fn group_by_channel(self) -> ChannelTrack {
+        let mut channels = BTreeMap::<u8, Vec<Event>>::new();
+        let mut meta = Vec::new();
+
+        for event in self.events {
+            match &event {
+                Event::Midi { data, .. } => channels.entry(data.channel()).or_default().push(event),
+                _ => meta.push(event),
+            }
+        }
+
+        ChannelTrack {
+            info: self.info,
+            channels,
+            meta,
+        }
+    }
 }
 
 /// Event proxy containing an extra delta field that contains the correct delta
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub struct CDTrackEvent<'smf> {
-    pub real_delta:   usize,
-    pub source_track: u32,
-    pub event:        TrackEvent<'smf>,
+    pub real_delta:    usize,
+    /// Tick of the event in the merged stream
+    pub absolute_tick: u64,
+    pub source_track:  u32,
+    /// Index of the event inside its source track
+    pub source_index:  usize,
+    /// Position of the event in the merged stream
+    pub index:         usize,
+    pub event:         TrackEvent<'smf>,
 }
 
 #[derive(Debug)]
@@ -46,32 +282,601 @@ impl<T> From<Option<T>> for PlayerResult<T> {
 }
 
 pub const MICROS_PER_SECOND: u64 = 1_000_000;
+
+/// How the events of a conversion are serialized, see
+/// [`SerializeOptions::wrap`]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SerializeOptions {
+    /// Leave `chan` out of channel events when it is 0, `track` out of events
+    /// when it is 0 and `velocity` out of note offs when it is 64 (the release
+    /// velocity of devices without one)
+    pub omit_default_fields: bool,
+    /// Write tempo events as `{"type": "tempo", "bpm": 120.0}` instead of
+    /// their microseconds per quarter note
+    pub tempo_bpm_only:      bool,
+}
+
+impl SerializeOptions {
+    /// Serialize `value` with these options. Serializing the value itself
+    /// uses the default options
+    pub fn wrap<T: ?Sized>(self, value: &T) -> WithOptions<'_, T> {
+        WithOptions {
+            value,
+            options: self,
+        }
+    }
+}
+
+/// A value serialized with [`SerializeOptions`], see [`SerializeOptions::wrap`]
+pub struct WithOptions<'a, T: ?Sized> {
+    value:   &'a T,
+    options: SerializeOptions,
+}
+
+/// Implement `Serialize` for values that hold events as their serialization
+/// with the default options
+macro_rules! serialize_with_defaults {
+    ($($ty:ty),*) => {$(
+        impl Serialize for $ty {
+            fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                SerializeOptions::default().wrap(self).serialize(serializer)
+            }
+        }
+    )*};
+}
+
+serialize_with_defaults!(
+    Track,
+    Patterns,
+    ChannelTrack,
+    ChannelTracks,
+    ChannelTrackEntry,
+    Segments,
+    Segment,
+    Event
+);
+
+impl<T> Serialize for WithOptions<'_, [T]>
+where
+    for<'a> WithOptions<'a, T>: Serialize,
+{
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.value.iter().map(|value| self.options.wrap(value)))
+    }
+}
+
+impl<T> Serialize for WithOptions<'_, Vec<T>>
+where
+    for<'a> WithOptions<'a, T>: Serialize,
+{
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.options
+            .wrap(self.value.as_slice())
+            .serialize(serializer)
+    }
+}
+
+impl Serialize for WithOptions<'_, BTreeMap<u8, Vec<Event>>> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(
+            self.value
+                .iter()
+                .map(|(channel, events)| (channel, self.options.wrap(events))),
+        )
+    }
+}
+
+#[derive(Serialize)]
+struct TrackRef<'a> {
+    #[serde(flatten)]
+    info:   &'a TrackInfo,
+    events: WithOptions<'a, Vec<Event>>,
+}
+
+impl Serialize for WithOptions<'_, Track> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let Track { info, events } = self.value;
+        TrackRef {
+            info,
+            events: self.options.wrap(events),
+        }
+        .serialize(serializer)
+    }
+}
+
+#[derive(Serialize)]
+struct PatternsRef<'a> {
+    patterns: WithOptions<'a, Vec<Track>>,
+}
+
+impl Serialize for WithOptions<'_, Patterns> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let Patterns { patterns } = self.value;
+        PatternsRef {
+            patterns: self.options.wrap(patterns),
+        }
+        .serialize(serializer)
+    }
+}
+
+#[derive(Serialize)]
+struct ChannelTrackRef<'a> {
+    #[serde(flatten)]
+    info:     &'a TrackInfo,
+    channels: WithOptions<'a, BTreeMap<u8, Vec<Event>>>,
+    meta:     WithOptions<'a, Vec<Event>>,
+}
+
+impl Serialize for WithOptions<'_, ChannelTrack> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let ChannelTrack {
+            info,
+            channels,
+            meta,
+        } = self.value;
+        ChannelTrackRef {
+            info,
+            channels: self.options.wrap(channels),
+            meta: self.options.wrap(meta),
+        }
+        .serialize(serializer)
+    }
+}
+
+#[derive(Serialize)]
+struct ChannelTracksRef<'a> {
+    #[serde(flatten)]
+    info:   &'a TrackInfo,
+    tracks: WithOptions<'a, Vec<ChannelTrackEntry>>,
+    meta:   WithOptions<'a, Vec<Event>>,
+}
+
+impl Serialize for WithOptions<'_, ChannelTracks> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let ChannelTracks { info, tracks, meta } = self.value;
+        ChannelTracksRef {
+            info,
+            tracks: self.options.wrap(tracks),
+            meta: self.options.wrap(meta),
+        }
+        .serialize(serializer)
+    }
+}
+
+#[derive(Serialize)]
+struct ChannelTrackEntryRef<'a> {
+    channel:    u8,
+    label:      &'a str,
+    program:    Option<u8>,
+    instrument: Option<&'static str>,
+    events:     WithOptions<'a, Vec<Event>>,
+}
+
+impl Serialize for WithOptions<'_, ChannelTrackEntry> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let ChannelTrackEntry {
+            channel,
+            label,
+            program,
+            instrument,
+            events,
+        } = self.value;
+        ChannelTrackEntryRef {
+            channel: *channel,
+            label,
+            program: *program,
+            instrument: *instrument,
+            events: self.options.wrap(events),
+        }
+        .serialize(serializer)
+    }
+}
+
+#[derive(Serialize)]
+struct SegmentsRef<'a> {
+    #[serde(flatten)]
+    info:     &'a TrackInfo,
+    segments: WithOptions<'a, Vec<Segment>>,
+}
+
+impl Serialize for WithOptions<'_, Segments> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let Segments { info, segments } = self.value;
+        SegmentsRef {
+            info,
+            segments: self.options.wrap(segments),
+        }
+        .serialize(serializer)
+    }
+}
+
+#[derive(Serialize)]
+struct SegmentRef<'a> {
+    name:       Option<&'a str>,
+    start_tick: u64,
+    events:     WithOptions<'a, Vec<Event>>,
+}
+
+impl Serialize for WithOptions<'_, Segment> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let Segment {
+            name,
+            start_tick,
+            events,
+        } = self.value;
+        SegmentRef {
+            name:       name.as_deref(),
+            start_tick: *start_tick,
+            events:     self.options.wrap(events),
+        }
+        .serialize(serializer)
+    }
+}
+
+/// An [`Event`] as it is written, `D` is its data
+#[derive(Serialize)]
+struct EventRef<'a, D> {
+    event: &'static str,
+    time:  &'a TimeInfo,
+    data:  D,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    track: Option<u32>,
+    #[serde(flatten)]
+    info:  &'a EventInfo,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename = "tempo")]
+struct TempoBpm {
+    bpm: f64,
+}
+
+impl Serialize for WithOptions<'_, Event> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let options = self.options;
+        let track = |track: &u32| (*track != 0 || !options.omit_default_fields).then_some(*track);
+        match self.value {
+            Event::Midi {
+                time,
+                data,
+                track: t,
+                info,
+            } => EventRef {
+                event: "midi",
+                time,
+                data: options.wrap(data),
+                track: track(t),
+                info,
+            }
+            .serialize(serializer),
+            Event::Meta {
+                time,
+                data: MetaEvent::Tempo(mpqn),
+                track: t,
+                info,
+            } if options.tempo_bpm_only => EventRef {
+                event: "meta",
+                time,
+                data: TempoBpm {
+                    bpm: tempo::bpm(*mpqn),
+                },
+                track: track(t),
+                info,
+            }
+            .serialize(serializer),
+            Event::Meta {
+                time,
+                data,
+                track: t,
+                info,
+            } => EventRef {
+                event: "meta",
+                time,
+                data,
+                track: track(t),
+                info,
+            }
+            .serialize(serializer),
+            Event::Sysex {
+                time,
+                data,
+                track: t,
+                info,
+            } => EventRef {
+                event: "sysex",
+                time,
+                data,
+                track: track(t),
+                info,
+            }
+            .serialize(serializer),
+            Event::Escape {
+                time,
+                data,
+                track: t,
+                info,
+            } => EventRef {
+                event: "escape",
+                time,
+                data,
+                track: track(t),
+                info,
+            }
+            .serialize(serializer),
+            Event::Live {
+                time,
+                data,
+                track: t,
+                info,
+            } => EventRef {
+                event: "live",
+                time,
+                data,
+                track: track(t),
+                info,
+            }
+            .serialize(serializer),
+        }
+    }
+}
+
+impl Serialize for WithOptions<'_, MidiEvent> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let omit = self.options.omit_default_fields;
+        self.value.serialize(SkipDefaults {
+            inner:    serializer,
+            chan:     omit && self.value.channel() == 0,
+            velocity: omit && matches!(self.value, MidiEvent::NoteOff { velocity: 64, .. }),
+        })
+    }
+}
+
+/// Serializes a [`MidiEvent`] with `S`, leaving out `chan` and `velocity` if
+/// asked to. serde can only skip a field by its own value, so this sits
+/// between the derived implementation and the actual serializer. Every
+/// [`MidiEvent`] is a struct, anything else is an error
+struct SkipDefaults<S> {
+    inner:    S,
+    chan:     bool,
+    velocity: bool,
+}
+
+impl<S> SkipDefaults<S> {
+    fn skips(&self, key: &str) -> bool {
+        (self.chan && key == "chan") || (self.velocity && key == "velocity")
+    }
+}
+
+macro_rules! only_structs {
+    ($($method:ident$(<$T:ident>)?($($arg:ty),*) -> $ret:ty;)*) => {$(
+        fn $method$(<$T: ?Sized + Serialize>)?(self, $(_: $arg),*) -> Result<$ret, Self::Error> {
+            Err(serde::ser::Error::custom("expected a struct"))
+        }
+    )*};
+}
+
+impl<S: serde::Serializer> serde::Serializer for SkipDefaults<S> {
+    type Error = S::Error;
+    type Ok = S::Ok;
+    type SerializeMap = Impossible<S::Ok, S::Error>;
+    type SerializeSeq = Impossible<S::Ok, S::Error>;
+    type SerializeStruct = SkipDefaults<S::SerializeStruct>;
+    type SerializeStructVariant = Impossible<S::Ok, S::Error>;
+    type SerializeTuple = Impossible<S::Ok, S::Error>;
+    type SerializeTupleStruct = Impossible<S::Ok, S::Error>;
+    type SerializeTupleVariant = Impossible<S::Ok, S::Error>;
+
+    only_structs! {
+        serialize_bool(bool) -> S::Ok;
+        serialize_i8(i8) -> S::Ok;
+        serialize_i16(i16) -> S::Ok;
+        serialize_i32(i32) -> S::Ok;
+        serialize_i64(i64) -> S::Ok;
+        serialize_u8(u8) -> S::Ok;
+        serialize_u16(u16) -> S::Ok;
+        serialize_u32(u32) -> S::Ok;
+        serialize_u64(u64) -> S::Ok;
+        serialize_f32(f32) -> S::Ok;
+        serialize_f64(f64) -> S::Ok;
+        serialize_char(char) -> S::Ok;
+        serialize_str(&str) -> S::Ok;
+        serialize_bytes(&[u8]) -> S::Ok;
+        serialize_none() -> S::Ok;
+        serialize_some<T>(&T) -> S::Ok;
+        serialize_unit() -> S::Ok;
+        serialize_unit_struct(&'static str) -> S::Ok;
+        serialize_unit_variant(&'static str, u32, &'static str) -> S::Ok;
+        serialize_newtype_struct<T>(&'static str, &T) -> S::Ok;
+        serialize_newtype_variant<T>(&'static str, u32, &'static str, &T) -> S::Ok;
+        serialize_seq(Option<usize>) -> Self::SerializeSeq;
+        serialize_tuple(usize) -> Self::SerializeTuple;
+        serialize_tuple_struct(&'static str, usize) -> Self::SerializeTupleStruct;
+        serialize_tuple_variant(&'static str, u32, &'static str, usize) -> Self::SerializeTupleVariant;
+        serialize_map(Option<usize>) -> Self::SerializeMap;
+        serialize_struct_variant(&'static str, u32, &'static str, usize) -> Self::SerializeStructVariant;
+    }
+
+    fn serialize_struct(
+        self,
+        name: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeStruct, Self::Error> {
+        // every variant has a channel, only note offs have a release velocity
+        let skipped = self.chan as usize + self.velocity as usize;
+        Ok(SkipDefaults {
+            inner:    self.inner.serialize_struct(name, len - skipped)?,
+            chan:     self.chan,
+            velocity: self.velocity,
+        })
+    }
+}
+
+impl<S: serde::ser::SerializeStruct> serde::ser::SerializeStruct for SkipDefaults<S> {
+    type Error = S::Error;
+    type Ok = S::Ok;
+
+    fn serialize_field<T: ?Sized + Serialize>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Self::Error> {
+        match self.skips(key) {
+            true => self.inner.skip_field(key),
+            false => self.inner.serialize_field(key, value),
+        }
+    }
+
+    fn skip_field(&mut self, key: &'static str) -> Result<(), Self::Error> {
+        self.inner.skip_field(key)
+    }
+
+    fn end(self) -> Result<Self::Ok, Self::Error> { self.inner.end() }
+}
+
+/// Largest number of micros a `f64` holds without losing whole micros, 2^53
+const MAX_EXACT_MICROS: f64 = 9_007_199_254_740_992.0;
+
+/// Whether a time is past the micros a `f64` can represent exactly, about 285
+/// years. Times after that are off by a few micros
+pub fn is_inexact(micros: f64) -> bool { micros > MAX_EXACT_MICROS }
+
+/// How fractional microseconds are turned into the integer `micros` field.
+///
+/// Time is tracked as a float internally, the rounding only applies to the
+/// emitted value and does not accumulate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Rounding {
+    #[default]
+    Floor,
+    Round,
+    Ceil,
+}
+
+impl Rounding {
+    pub fn apply(self, micros: f64) -> u64 {
+        match self {
+            Rounding::Floor => micros.floor() as u64,
+            Rounding::Round => micros.round() as u64,
+            Rounding::Ceil => micros.ceil() as u64,
+        }
+    }
+}
+
+impl std::str::FromStr for Rounding {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "floor" => Ok(Rounding::Floor),
+            "round" => Ok(Rounding::Round),
+            "ceil" => Ok(Rounding::Ceil),
+            _ => anyhow::bail!(
+                "unknown rounding mode {:?} (expected floor, round or ceil)",
+                s
+            ),
+        }
+    }
+}
+
+/// Forward only clock converting tick deltas into absolute and relative time.
+///
+/// For converting arbitrary ticks see [`TempoMap`](crate::tempo::TempoMap).
 pub struct PlayerTimingInfo {
     // state
-    current_tick: u64,
-    current_ms:   f64,
+    current_tick:   u64,
+    current_micros: f64,
+    /// Tick and micros of the last tempo change. Time is computed from there
+    /// instead of summing up deltas, so rounding errors do not accumulate
+    segment_tick:   u64,
+    segment_micros: f64,
 
     // timing data
     timing_data: TimingData,
+    /// Factor every length in micros is multiplied with
+    scale:       f64,
 }
 
 impl PlayerTimingInfo {
+    /// Advance the clock by `delta` ticks using the current tempo
     pub fn next_tick(&mut self, delta: u64) -> NextTickInfo {
-        let delta_len = self.timing_data.get_len(delta);
+        let previous = self.current_micros;
 
         self.current_tick += delta;
-        self.current_ms += delta_len;
+        self.current_micros = self.micros_at(self.current_tick);
+
+        let delta_micros = self.current_micros - previous;
+        debug_assert!(
+            delta_micros.is_finite() && delta_micros >= 0.0,
+            "a delta of {} ticks is {} micros long",
+            delta,
+            delta_micros
+        );
 
         NextTickInfo {
-            delta_tick:   delta,
-            delta_micros: delta_len,
-            abs_tick:     self.current_tick,
-            abs_micros:   self.current_ms,
+            delta_tick: delta,
+            delta_micros,
+            abs_tick: self.current_tick,
+            abs_micros: self.current_micros,
         }
     }
 
+    /// Absolute micros of a tick at or after the last tempo change
+    fn micros_at(&self, tick: u64) -> f64 {
+        self.segment_micros + self.timing_data.get_len(tick - self.segment_tick) * self.scale
+    }
+
+    /// Fail if ticks have no finite, non-negative length in time, which
+    /// [`next_tick`] relies on. Happens with a division of 0 ticks per
+    /// quarter note or per frame, tempo changes can not cause it
+    ///
+    /// [`next_tick`]: PlayerTimingInfo::next_tick
+    pub fn check(&self) -> anyhow::Result<()> {
+        let len = self.timing_data.get_len(1) * self.scale;
+        if !len.is_finite() || len < 0.0 {
+            anyhow::bail!(
+                "a tick of this file is {} micros long, the division in its header is probably 0. \
+                 Convert it with --no-time, or with --ppqn if it has metrical timing",
+                len
+            );
+        }
+        Ok(())
+    }
+
+    /// Stretch (above 1) or compress (below 1) the clock by `scale`, without
+    /// changing the ticks. Only valid before the clock was advanced
+    pub fn with_scale(mut self, scale: f64) -> Self {
+        self.scale = scale;
+        self
+    }
+
+    /// Advance the clock by `delta` ticks without keeping track of time,
+    /// returning the absolute tick
+    pub fn advance_ticks(&mut self, delta: u64) -> u64 {
+        self.current_tick += delta;
+        self.current_tick
+    }
+
+    /// Absolute micros of an event `delta` ticks from now, without advancing
+    /// the clock
+    pub fn peek_micros(&self, delta: u64) -> f64 { self.micros_at(self.current_tick + delta) }
+
+    /// Ticks per quarter note, `None` for SMPTE timed files
+    pub fn ppqn(&self) -> Option<f64> {
+        match self.timing_data {
+            TimingData::Metric { ppqn, .. } => Some(ppqn),
+            TimingData::Fps { .. } => None,
+        }
+    }
+
+    /// Absolute tick and micros reached so far
+    pub fn elapsed(&self) -> (u64, f64) { (self.current_tick, self.current_micros) }
+
+    /// Change the tempo to `npt` microseconds per quarter note. Has no effect
+    /// on SMPTE timed files.
     pub fn update_mpt(&mut self, npt: u32) {
         if let TimingData::Metric { ppqn, .. } = self.timing_data {
+            self.segment_micros = self.micros_at(self.current_tick);
+            self.segment_tick = self.current_tick;
             self.timing_data = TimingData::Metric {
                 ppqn,
                 npt: npt as f64,
@@ -82,7 +887,30 @@ impl PlayerTimingInfo {
 
 impl From<midly::Timing> for PlayerTimingInfo {
     fn from(t: midly::Timing) -> Self {
-        let td = match t {
+        PlayerTimingInfo {
+            current_tick:   0,
+            current_micros: 0.0,
+            segment_tick:   0,
+            segment_micros: 0.0,
+            timing_data:    TimingData::from(t),
+            scale:          1.0,
+        }
+    }
+}
+
+/// Length of a tick, either from the SMPTE frame rate or from the ticks per
+/// quarter note and the current tempo
+#[derive(Debug, Clone, Copy)]
+pub enum TimingData {
+    /// `fps` frames per second with `tpf` ticks per frame
+    Fps { fps: f32, tpf: u8 },
+    /// `ppqn` ticks per quarter note at `npt` microseconds per quarter note
+    Metric { ppqn: f64, npt: f64 },
+}
+
+impl From<midly::Timing> for TimingData {
+    fn from(t: midly::Timing) -> Self {
+        match t {
             midly::Timing::Metrical(ppqn) => TimingData::Metric {
                 ppqn: ppqn.as_int() as f64,
                 npt:  500_000f64,
@@ -91,29 +919,19 @@ impl From<midly::Timing> for PlayerTimingInfo {
                 fps: fps.as_f32(),
                 tpf: npt,
             },
-        };
-
-        PlayerTimingInfo {
-            current_tick: 0,
-            current_ms:   0.0,
-            timing_data:  td,
         }
     }
 }
 
-#[derive(Debug)]
-pub enum TimingData {
-    Fps { fps: f32, tpf: u8 },
-    Metric { ppqn: f64, npt: f64 },
-}
-
 impl TimingData {
+    /// Length of `ticks` ticks in microseconds
     pub fn get_len(&self, ticks: u64) -> f64 {
         match self {
             TimingData::Fps { fps, tpf } => {
                 (MICROS_PER_SECOND as f64 / *fps as f64 / *tpf as f64) * ticks as f64
             },
-            TimingData::Metric { ppqn, npt } => (npt / ppqn) * ticks as f64,
+            // multiplying first keeps the result exact for integer results
+            TimingData::Metric { ppqn, npt } => npt * ticks as f64 / ppqn,
         }
     }
 }
@@ -126,45 +944,172 @@ pub struct NextTickInfo {
     pub abs_micros:   f64,
 }
 
-#[derive(Debug, serde::Serialize)]
-#[serde(tag = "event", rename_all = "snake_case")]
+#[derive(Debug)]
 pub enum Event {
     Midi {
         time:  TimeInfo,
         data:  MidiEvent,
         track: u32,
+        info:  EventInfo,
     },
     Meta {
         time:  TimeInfo,
         data:  MetaEvent,
         track: u32,
+        info:  EventInfo,
+    },
+    Sysex {
+        time:  TimeInfo,
+        data:  SysexEvent,
+        track: u32,
+        info:  EventInfo,
+    },
+    Escape {
+        time:  TimeInfo,
+        data:  EscapeEvent,
+        track: u32,
+        info:  EventInfo,
+    },
+    Live {
+        time:  TimeInfo,
+        data:  LiveMessage,
+        track: u32,
+        info:  EventInfo,
     },
 }
 
-#[derive(Debug, serde::Serialize)]
+impl Event {
+    pub fn time(&self) -> &TimeInfo {
+        match self {
+            Event::Midi { time, .. }
+            | Event::Meta { time, .. }
+            | Event::Sysex { time, .. }
+            | Event::Escape { time, .. }
+            | Event::Live { time, .. } => time,
+        }
+    }
+
+    pub fn time_mut(&mut self) -> &mut TimeInfo {
+        match self {
+            Event::Midi { time, .. }
+            | Event::Meta { time, .. }
+            | Event::Sysex { time, .. }
+            | Event::Escape { time, .. }
+            | Event::Live { time, .. } => time,
+        }
+    }
+
+    pub fn info_mut(&mut self) -> &mut EventInfo {
+        match self {
+            Event::Midi { info, .. }
+            | Event::Meta { info, .. }
+            | Event::Sysex { info, .. }
+            | Event::Escape { info, .. }
+            | Event::Live { info, .. } => info,
+        }
+    }
+}
+
+/// Optional diagnostic information attached to an event
+#[derive(Debug, Default, serde::Serialize)]
+pub struct EventInfo {
+    /// The bytes of the event as they appear in the source file, excluding
+    /// the delta time
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bytes:          Option<String>,
+    /// Whether the status byte was omitted in the source file
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub running_status: Option<bool>,
+    /// Debug representation of the source event as parsed by midly
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub raw:            Option<String>,
+    /// Position of the event among all processed events
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub index:          Option<usize>,
+    /// Position of the event among the emitted events
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub emitted_index:  Option<usize>,
+    /// Channel set by the last `MidiChannel` meta event of the track
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub channel_prefix: Option<u8>,
+    /// Port set by the last `MidiPort` meta event of the track
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub port_prefix:    Option<u8>,
+    /// `bar.beat.tick` of the event, `null` for SMPTE timed files
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub position:       Option<Option<String>>,
+}
+
+/// Format bytes as space separated lowercase hex
+pub fn to_hex(data: &[u8]) -> String { data.iter().map(|b| format!("{:02x}", b)).join(" ") }
+
+#[derive(Debug, Clone, serde::Serialize)]
 pub struct TimeInfo {
-    pub tick:    u64,
-    pub micros:  u64,
-    pub seconds: f32,
+    pub tick:         u64,
+    /// Left out when only ticks are tracked
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub micros:       Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seconds:      Option<f32>,
+    /// Absolute time in quarter notes, also with deltas. `null` for SMPTE
+    /// timed files
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub abs_quarters: Option<Option<f64>>,
+    /// Ticks since the previous event in quarter notes, also with absolute
+    /// times. `null` for SMPTE timed files
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub delta_beats:  Option<Option<f64>>,
 }
 
 #[derive(Debug, serde::Serialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum MidiEvent {
     NoteOff {
-        chan:     u8,
-        note:     u8,
-        velocity: u8,
+        chan:          u8,
+        note:          u8,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        note_name:     Option<String>,
+        velocity:      u8,
+        /// Emitted index of the note on this note off ends, `null` if no note
+        /// is playing
+        #[serde(skip_serializing_if = "Option::is_none")]
+        note_on_index: Option<Option<usize>>,
     },
     NoteOn {
-        chan:     u8,
-        note:     u8,
-        velocity: u8,
+        chan:                 u8,
+        note:                 u8,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        note_name:            Option<String>,
+        velocity:             u8,
+        /// Semitones from the previous note on the channel, `null` for the
+        /// first one
+        #[serde(skip_serializing_if = "Option::is_none")]
+        interval:             Option<Option<i8>>,
+        /// Time since the previous note on, `null` for the first one
+        #[serde(skip_serializing_if = "Option::is_none")]
+        time_since_last_note: Option<Option<TimeInfo>>,
+        /// `true` for note ons with a velocity of 0, which end a note like a
+        /// note off
+        #[serde(skip_serializing_if = "Option::is_none")]
+        implied_note_off:     Option<bool>,
+        /// For note ons with a velocity of 0, see `NoteOff`
+        #[serde(skip_serializing_if = "Option::is_none")]
+        note_on_index:        Option<Option<usize>>,
+        /// The last program changed to on the channel, `null` before the first
+        /// program change
+        #[serde(skip_serializing_if = "Option::is_none")]
+        active_program:       Option<Option<u8>>,
+        /// The General MIDI name of `active_program`, always a drum kit on the
+        /// drum channel
+        #[serde(skip_serializing_if = "Option::is_none")]
+        active_instrument:    Option<Option<&'static str>>,
     },
     Aftertouch {
         chan:     u8,
         note:     u8,
         velocity: u8,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        scope:    Option<AftertouchScope>,
     },
     Controller {
         chan:  u8,
@@ -178,11 +1123,225 @@ pub enum MidiEvent {
     ChannelAftertouch {
         chan:     u8,
         velocity: u8,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        scope:    Option<AftertouchScope>,
     },
     PitchBend {
         chan:    u8,
         bend_by: u16,
     },
+    /// Controller 64, decoded with `--decode-pedal`
+    Sustain {
+        chan: u8,
+        /// Values of 64 and above press the pedal
+        down: bool,
+    },
+    /// Controller 66
+    Sostenuto {
+        chan: u8,
+        down: bool,
+    },
+    /// Controller 67
+    Soft {
+        chan: u8,
+        down: bool,
+    },
+    /// A gap without notes playing on a channel, emitted with `--emit-rests`
+    /// right after the note on that ends it
+    Rest {
+        chan:            u8,
+        duration_micros: u64,
+    },
+}
+
+impl MidiEvent {
+    pub fn channel(&self) -> u8 {
+        match *self {
+            MidiEvent::NoteOff { chan, .. }
+            | MidiEvent::NoteOn { chan, .. }
+            | MidiEvent::Aftertouch { chan, .. }
+            | MidiEvent::Controller { chan, .. }
+            | MidiEvent::ProgramChange { chan, .. }
+            | MidiEvent::ChannelAftertouch { chan, .. }
+            | MidiEvent::PitchBend { chan, .. }
+            | MidiEvent::Sustain { chan, .. }
+            | MidiEvent::Sostenuto { chan, .. }
+            | MidiEvent::Soft { chan, .. }
+            | MidiEvent::Rest { chan, .. } => chan,
+        }
+    }
+}
+
+/// A pedal controller that can be decoded into pressed and released
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pedal {
+    Sustain,
+    Sostenuto,
+    Soft,
+}
+
+impl Pedal {
+    pub fn controller(self) -> u8 {
+        match self {
+            Pedal::Sustain => 64,
+            Pedal::Sostenuto => 66,
+            Pedal::Soft => 67,
+        }
+    }
+
+    /// The event for a value of the pedal controller on a channel
+    pub fn event(self, chan: u8, value: u8) -> MidiEvent {
+        let down = value >= 64;
+        match self {
+            Pedal::Sustain => MidiEvent::Sustain { chan, down },
+            Pedal::Sostenuto => MidiEvent::Sostenuto { chan, down },
+            Pedal::Soft => MidiEvent::Soft { chan, down },
+        }
+    }
+}
+
+impl std::str::FromStr for Pedal {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sustain" => Ok(Pedal::Sustain),
+            "sostenuto" => Ok(Pedal::Sostenuto),
+            "soft" => Ok(Pedal::Soft),
+            _ => anyhow::bail!(
+                "unknown pedal {:?} (expected sustain, sostenuto or soft)",
+                s
+            ),
+        }
+    }
+}
+
+/// Whether an aftertouch event applies to a single note or the whole channel
+#[derive(Debug, Clone, Copy, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AftertouchScope {
+    Note,
+    Channel,
+}
+
+/// Which note on `time_since_last_note` is measured from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NoteDeltaScope {
+    /// The previous note on the same channel
+    Channel,
+    /// The previous note on any channel
+    Global,
+}
+
+impl std::str::FromStr for NoteDeltaScope {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "channel" => Ok(NoteDeltaScope::Channel),
+            "global" => Ok(NoteDeltaScope::Global),
+            _ => anyhow::bail!(
+                "unknown note delta scope {:?} (expected channel or global)",
+                s
+            ),
+        }
+    }
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct SysexEvent {
+    pub manufacturer: String,
+    /// The message without the leading `F0`, encoded as requested
+    pub payload:      String,
+}
+
+/// Arbitrary bytes stored behind an `F7` escape, like realtime messages or
+/// the continuation of a split SysEx message
+#[derive(Debug, serde::Serialize)]
+pub struct EscapeEvent {
+    /// The bytes without the leading `F7`, as hex
+    pub payload: String,
+}
+
+/// A System Common or System Realtime message, which can only be stored in a
+/// file behind an escape
+#[derive(Debug, serde::Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum LiveMessage {
+    TimingClock,
+    Start,
+    Continue,
+    Stop,
+    ActiveSensing,
+    Reset,
+    SongPosition {
+        /// In sixteenth notes from the start of the song
+        position: u16,
+    },
+    SongSelect {
+        song: u8,
+    },
+    TuneRequest,
+    /// A piece of a MIDI time code
+    MtcQuarterFrame {
+        /// Which part of the time code `value` is, like `frames_low`
+        piece: &'static str,
+        value: u8,
+    },
+    Undefined {
+        status: u8,
+    },
+}
+
+impl LiveMessage {
+    /// Parse the payload of an escape event, if it holds a realtime or
+    /// system common message (other than SysEx)
+    pub fn from_escape(data: &[u8]) -> Option<Self> {
+        use midly::live::{LiveEvent, MtcQuarterFrameMessage as Mtc, SystemCommon, SystemRealtime};
+
+        if !matches!(data.first(), Some(0xF1..=0xF6 | 0xF8..=0xFE)) {
+            return None;
+        }
+
+        Some(match LiveEvent::parse(data).ok()? {
+            LiveEvent::Realtime(realtime) => match realtime {
+                SystemRealtime::TimingClock => LiveMessage::TimingClock,
+                SystemRealtime::Start => LiveMessage::Start,
+                SystemRealtime::Continue => LiveMessage::Continue,
+                SystemRealtime::Stop => LiveMessage::Stop,
+                SystemRealtime::ActiveSensing => LiveMessage::ActiveSensing,
+                SystemRealtime::Reset => LiveMessage::Reset,
+                SystemRealtime::Undefined(status) => LiveMessage::Undefined { status },
+            },
+            LiveEvent::Common(common) => match common {
+                SystemCommon::SongPosition(position) => LiveMessage::SongPosition {
+                    position: position.as_int(),
+                },
+                SystemCommon::SongSelect(song) => LiveMessage::SongSelect {
+                    song: song.as_int(),
+                },
+                SystemCommon::TuneRequest => LiveMessage::TuneRequest,
+                SystemCommon::MidiTimeCodeQuarterFrame(piece, value) => {
+                    LiveMessage::MtcQuarterFrame {
+                        piece: match piece {
+                            Mtc::FramesLow => "frames_low",
+                            Mtc::FramesHigh => "frames_high",
+                            Mtc::SecondsLow => "seconds_low",
+                            Mtc::SecondsHigh => "seconds_high",
+                            Mtc::MinutesLow => "minutes_low",
+                            Mtc::MinutesHigh => "minutes_high",
+                            Mtc::HoursLow => "hours_low",
+                            Mtc::HoursHigh => "hours_high",
+                        },
+                        value: value.as_int(),
+                    }
+                },
+                SystemCommon::Undefined(status, _) => LiveMessage::Undefined { status },
+                SystemCommon::SysEx(_) => return None,
+            },
+            LiveEvent::Midi { .. } => return None,
+        })
+    }
 }
 
 #[derive(Debug, serde::Serialize)]
@@ -204,61 +1363,252 @@ pub enum MetaEvent {
     Tempo(u32),
     TimeSignature(u8, u8, u8, u8),
     KeySignature(i8, bool),
-    Unknown(u8, Vec<u8>),
+    SmpteOffset {
+        fps:      f32,
+        hour:     u8,
+        minute:   u8,
+        second:   u8,
+        frame:    u8,
+        subframe: u8,
+    },
+    /// A meta event of a type midly does not know, with its payload as hex
+    Unknown {
+        meta_type: u8,
+        payload:   String,
+    },
 }
 
-/// Repeat the first element N times. For use with tools like
-/// `itertools::Iterator`
-pub struct RepeatFirstN<I>
-where
-    I: Iterator,
-    <I as Iterator>::Item: Clone,
-{
-    it: I,
-    e:  Option<<I as Iterator>::Item>,
-    c:  usize,
-    n:  usize,
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-impl<I> Iterator for RepeatFirstN<I>
-where
-    I: Iterator,
-    I::Item: Clone,
-{
-    type Item = <I as Iterator>::Item;
+    fn note_off(chan: u8, track: u32) -> Event {
+        Event::Midi {
+            time: TimeInfo {
+                tick:         0,
+                micros:       Some(0),
+                seconds:      Some(0.0),
+                abs_quarters: None,
+                delta_beats:  None,
+            },
+            data: MidiEvent::NoteOff {
+                chan,
+                note: 60,
+                note_name: None,
+                velocity: 64,
+                note_on_index: None,
+            },
+            track,
+            info: EventInfo::default(),
+        }
+    }
 
-    fn size_hint(&self) -> (usize, Option<usize>) { self.it.size_hint() }
+    fn fields(options: SerializeOptions, event: &Event) -> Vec<String> {
+        let value = serde_json::to_value(options.wrap(event)).unwrap();
+        let mut fields = value["data"]
+            .as_object()
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect::<Vec<_>>();
+        if value.get("track").is_some() {
+            fields.push("track".to_owned());
+        }
+        fields
+    }
 
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.c <= self.n {
-            self.c += 1;
-            return self.e.clone();
+    fn tempo(mpqn: u32) -> Event {
+        Event::Meta {
+            time:  TimeInfo {
+                tick:         0,
+                micros:       None,
+                seconds:      None,
+                abs_quarters: None,
+                delta_beats:  None,
+            },
+            data:  MetaEvent::Tempo(mpqn),
+            track: 0,
+            info:  EventInfo::default(),
         }
+    }
+
+    #[test]
+    fn tempo_in_bpm() {
+        let bpm_only = SerializeOptions {
+            tempo_bpm_only: true,
+            ..Default::default()
+        };
 
-        self.it.next()
+        assert_eq!(
+            serde_json::to_value(tempo(500_000)).unwrap()["data"],
+            serde_json::json!({"type": "tempo", "data": 500_000})
+        );
+        assert_eq!(
+            serde_json::to_value(bpm_only.wrap(&tempo(400_000))).unwrap()["data"],
+            serde_json::json!({"type": "tempo", "bpm": 150.0})
+        );
+        assert_eq!(
+            serde_json::to_value(tempo(400_000)).unwrap()["data"]["data"],
+            400_000
+        );
     }
-}
 
-pub trait RepeatFirst
-where
-    Self: Sized + Iterator,
-    <Self as Iterator>::Item: Clone,
-{
-    fn repeat_first_n(self, n: usize) -> RepeatFirstN<Self>;
-}
+    #[test]
+    fn default_fields_are_kept() {
+        assert_eq!(fields(Default::default(), &note_off(0, 0)), vec![
+            "chan", "note", "type", "velocity", "track"
+        ]);
+    }
 
-impl<T, I> RepeatFirst for T
-where
-    T: Iterator<Item = I>,
-    I: Clone,
-{
-    fn repeat_first_n(mut self, n: usize) -> RepeatFirstN<Self> {
-        let el = self.next();
-        RepeatFirstN {
-            it: self,
-            e: el,
-            c: 0,
-            n,
+    #[test]
+    fn default_fields_are_omitted() {
+        let omit = SerializeOptions {
+            omit_default_fields: true,
+            ..Default::default()
+        };
+
+        assert_eq!(fields(omit, &note_off(0, 0)), vec!["note", "type"]);
+        assert_eq!(fields(omit, &note_off(1, 2)), vec![
+            "chan", "note", "type", "track"
+        ]);
+        // only for the wrapped event
+        assert_eq!(
+            serde_json::to_value(note_off(0, 0)).unwrap()["data"]["chan"],
+            0
+        );
+    }
+
+    #[test]
+    fn cbor_without_default_fields() {
+        let omit = SerializeOptions {
+            omit_default_fields: true,
+            ..Default::default()
+        };
+        let track = Track {
+            info:   TrackInfo {
+                generated:        None,
+                source_file:      "song.mid".to_owned(),
+                source_sha256:    None,
+                header:           HeaderInfo::new(
+                    midly::Header::new(
+                        midly::Format::SingleTrack,
+                        midly::Timing::Metrical(480.into()),
+                    ),
+                    1,
+                ),
+                events_processed: 2,
+                events_emitted:   2,
+                unknown_events:   0,
+                invalid_events:   None,
+                emitted_meta:     false,
+                duration:         note_off(0, 0).time().clone(),
+                program_map:      None,
+                snapshot:         None,
+            },
+            events: vec![note_off(0, 0), tempo(500_000)],
+        };
+
+        // the struct lengths written up front have to match the fields
+        let mut bytes = Vec::new();
+        ciborium::ser::into_writer(&omit.wrap(&track), &mut bytes).unwrap();
+        let value: ciborium::value::Value = ciborium::de::from_reader(bytes.as_slice()).unwrap();
+        let json = serde_json::to_value(omit.wrap(&track)).unwrap();
+        assert_eq!(serde_json::to_value(value).unwrap(), json);
+        assert_eq!(
+            json["events"][0]["data"],
+            serde_json::json!({"type": "note_off", "note": 60})
+        );
+    }
+
+    #[test]
+    fn micros_across_tempo_changes() {
+        let mut timing = PlayerTimingInfo::from(midly::Timing::Metrical(480.into()));
+        // the micros as they used to be computed, summing up the deltas
+        let mut accumulated = 0.0;
+        let mut exact = 0.0;
+
+        for tempo in [500_000, 400_000, 333_333, 600_001] {
+            timing.update_mpt(tempo);
+            let tick_len = tempo as f64 / 480.0;
+            let start = timing.elapsed().1;
+            for step in 1..=10_000u64 {
+                let info = timing.next_tick(1);
+                accumulated += info.delta_micros;
+                // micros are computed from the last tempo change, not summed up
+                assert_eq!(info.abs_micros, start + tempo as f64 * step as f64 / 480.0);
+                assert!((info.delta_micros - tick_len).abs() < 1e-6);
+            }
+            exact += tempo as f64 * 10_000.0 / 480.0;
+
+            let micros = timing.elapsed().1;
+            assert!((micros - exact).abs() < 1e-6, "{} != {}", micros, exact);
+            assert!((micros - accumulated).abs() < 1.0);
+        }
+        assert_eq!(timing.elapsed().0, 40_000);
+    }
+
+    #[test]
+    fn micros_of_long_files() {
+        let mut timing = PlayerTimingInfo::from(midly::Timing::Metrical(1.into()));
+        // half a second per tick, 2^53 micros are about 18 billion ticks
+        timing.next_tick(18_000_000_000);
+        assert!(!is_inexact(timing.elapsed().1));
+        assert_eq!(timing.elapsed().1, 9e15);
+
+        let info = timing.next_tick(100_000_000);
+        assert!(is_inexact(timing.elapsed().1));
+        assert_eq!(info.delta_micros, 5e13);
+        assert_eq!(info.abs_tick, 18_100_000_000);
+    }
+
+    #[test]
+    fn split_tracks_keep_the_track_info() {
+        let track = || {
+            let events = vec![note_off(0, 0), note_off(1, 0)];
+            Track {
+                info: TrackInfo {
+                    generated:        None,
+                    source_file:      "song.mid".to_owned(),
+                    source_sha256:    Some("00".to_owned()),
+                    header:           HeaderInfo::new(
+                        midly::Header::new(
+                            midly::Format::SingleTrack,
+                            midly::Timing::Metrical(480.into()),
+                        ),
+                        1,
+                    ),
+                    events_processed: 2,
+                    events_emitted:   2,
+                    unknown_events:   0,
+                    invalid_events:   None,
+                    emitted_meta:     false,
+                    duration:         events[1].time().clone(),
+                    program_map:      Some(gm::program_map(&events)),
+                    snapshot:         None,
+                },
+                events,
+            }
+        };
+        let info = |value: serde_json::Value, key: &str| {
+            let mut object = value.as_object().unwrap().clone();
+            assert!(object.remove(key).is_some());
+            object
+        };
+
+        let expected = info(serde_json::to_value(track()).unwrap(), "events");
+        assert!(expected.contains_key("program_map"));
+        assert!(expected.contains_key("source_sha256"));
+        for (value, key) in [
+            (serde_json::to_value(track().split_on_marker()), "segments"),
+            (serde_json::to_value(track().group_by_channel()), "channels"),
+            (
+                serde_json::to_value(track().combine_channels_into_tracks()),
+                "tracks",
+            ),
+        ] {
+            let mut object = info(value.unwrap(), key);
+            object.remove("meta");
+            assert_eq!(object, expected, "{key}");
         }
     }
 }
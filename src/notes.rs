@@ -0,0 +1,157 @@
+use std::{collections::VecDeque, str::FromStr};
+
+const SHARPS: [&str; 12] = [
+    "C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B",
+];
+const FLATS: [&str; 12] = [
+    "C", "Db", "D", "Eb", "E", "F", "Gb", "G", "Ab", "A", "Bb", "B",
+];
+
+/// The octave note 60 is in. Vendors disagree on it, C4 is the scientific
+/// pitch notation
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MiddleC {
+    C3,
+    #[default]
+    C4,
+    C5,
+}
+
+impl FromStr for MiddleC {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "c3" => Ok(MiddleC::C3),
+            "c4" => Ok(MiddleC::C4),
+            "c5" => Ok(MiddleC::C5),
+            _ => anyhow::bail!("unknown middle c {:?} (expected c3, c4 or c5)", s),
+        }
+    }
+}
+
+/// Name of a pitch class, 0 being C
+pub fn pitch_class_name(pitch_class: u8, flats: bool) -> &'static str {
+    let names = if flats { &FLATS } else { &SHARPS };
+    names[(pitch_class % 12) as usize]
+}
+
+/// Names notes like `C4` or `F#5`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoteNaming {
+    pub middle_c: MiddleC,
+    /// Spell black keys with flats (`Db`) instead of sharps (`C#`)
+    pub flats:    bool,
+}
+
+impl NoteNaming {
+    pub fn name(self, note: u8) -> String {
+        format!(
+            "{}{}",
+            pitch_class_name(note, self.flats),
+            (note / 12) as i32 + self.octave_offset()
+        )
+    }
+
+    /// The note of a name like `C4`, `F#5` or `Bb-1`, or of a note number.
+    /// Sharps and flats are both understood, whatever `flats` is
+    pub fn parse(self, s: &str) -> anyhow::Result<u8> {
+        if let Ok(note) = s.parse::<u8>() {
+            anyhow::ensure!(
+                note <= 127,
+                "note {} does not exist, notes are 0 to 127",
+                note
+            );
+            return Ok(note);
+        }
+
+        let mut chars = s.chars();
+        let pitch_class = match chars.next().map(|c| c.to_ascii_uppercase()) {
+            Some(letter @ 'A'..='G') => SHARPS.iter().position(|n| n.starts_with(letter)).unwrap(),
+            _ => anyhow::bail!("invalid note {:?} (expected a number or a name like C4)", s),
+        } as i32;
+        let rest = chars.as_str();
+        let (pitch_class, octave) = match rest.strip_prefix('#') {
+            Some(octave) => (pitch_class + 1, octave),
+            None => match rest.strip_prefix('b') {
+                Some(octave) => (pitch_class - 1, octave),
+                None => (pitch_class, rest),
+            },
+        };
+        let octave: i32 = octave
+            .parse()
+            .map_err(|_| anyhow::anyhow!("invalid octave in note {:?}", s))?;
+
+        let note = (octave - self.octave_offset()) * 12 + pitch_class;
+        u8::try_from(note)
+            .ok()
+            .filter(|&note| note <= 127)
+            .ok_or_else(|| anyhow::anyhow!("note {:?} is outside of 0 to 127", s))
+    }
+
+    /// Octave of the notes 0 to 11
+    fn octave_offset(self) -> i32 {
+        match self.middle_c {
+            MiddleC::C3 => -2,
+            MiddleC::C4 => -1,
+            MiddleC::C5 => 0,
+        }
+    }
+}
+
+/// Which note a note off ends when its key was struck again before it was
+/// released, so several notes are playing on the same channel and key
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NoteMatch {
+    /// The oldest note, the first one struck is the first one released
+    #[default]
+    Fifo,
+    /// The most recent note, so the earlier note sounds on through repeated
+    /// strikes, as with a held sustain pedal
+    Lifo,
+}
+
+impl NoteMatch {
+    /// Take the note a note off ends from the notes playing on its key,
+    /// oldest first
+    pub fn take<T>(self, playing: &mut VecDeque<T>) -> Option<T> {
+        match self {
+            NoteMatch::Fifo => playing.pop_front(),
+            NoteMatch::Lifo => playing.pop_back(),
+        }
+    }
+}
+
+impl FromStr for NoteMatch {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "fifo" => Ok(NoteMatch::Fifo),
+            "lifo" => Ok(NoteMatch::Lifo),
+            _ => anyhow::bail!("unknown note matching {:?} (expected fifo or lifo)", s),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn note_match() {
+        let playing = || VecDeque::from([1, 2, 3]);
+
+        let mut fifo = playing();
+        assert_eq!(NoteMatch::Fifo.take(&mut fifo), Some(1));
+        assert_eq!(NoteMatch::Fifo.take(&mut fifo), Some(2));
+
+        let mut lifo = playing();
+        assert_eq!(NoteMatch::Lifo.take(&mut lifo), Some(3));
+        assert_eq!(NoteMatch::Lifo.take(&mut lifo), Some(2));
+
+        assert_eq!(NoteMatch::Fifo.take(&mut VecDeque::<u8>::new()), None);
+        assert_eq!("LIFO".parse::<NoteMatch>().unwrap(), NoteMatch::Lifo);
+        assert!("newest".parse::<NoteMatch>().is_err());
+    }
+}
@@ -0,0 +1,130 @@
+use base64::Engine;
+use std::collections::{HashMap, VecDeque};
+
+use crate::{
+    model::{Event, HeaderInfo, MidiEvent, Track},
+    notes::NoteMatch,
+};
+
+/// How a [`PianoRoll`] is built
+#[derive(Debug, Clone)]
+pub struct PianoRollOptions {
+    /// Ticks per step, at least 1
+    pub resolution: u64,
+    /// Lowest and highest note of the grid, inclusive
+    pub low_note:   u8,
+    pub high_note:  u8,
+    /// Which note a note off ends when several are playing on its key
+    pub note_match: NoteMatch,
+    /// Write the grid as a single base64 string instead of nested arrays
+    pub blob:       bool,
+}
+
+/// A dense grid of the note velocities at every step of a file, one row per
+/// step and one column per note from `low_note` to `high_note`. A cell holds
+/// the velocity of the loudest note playing on that key during the step, 0
+/// if none is
+#[derive(Debug, serde::Serialize)]
+pub struct PianoRoll {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub generated:     Option<String>,
+    pub source_file:   String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source_sha256: Option<String>,
+    pub header:        HeaderInfo,
+    /// Ticks per step
+    pub resolution:    u64,
+    pub low_note:      u8,
+    pub high_note:     u8,
+    /// Number of rows
+    pub steps:         usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub grid:          Option<Vec<Vec<u8>>>,
+    /// The rows one after another, one byte per cell, as base64
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub blob:          Option<String>,
+}
+
+impl PianoRoll {
+    /// Build the piano roll of a track played with absolute ticks. Notes are
+    /// merged across channels, notes that never end last until the end of
+    /// the file
+    pub fn from_track(track: &Track, options: &PianoRollOptions) -> Self {
+        let width = (options.high_note - options.low_note) as usize + 1;
+        let end = track.info.duration.tick;
+        let steps = end.div_ceil(options.resolution).max(1) as usize;
+        let mut cells = vec![0u8; steps * width];
+
+        let mut fill = |note: u8, velocity: u8, start: u64, stop: u64| {
+            if !(options.low_note..=options.high_note).contains(&note) {
+                return;
+            }
+            let column = (note - options.low_note) as usize;
+            // every step the note sounds in, at least the one it starts in
+            let first = start / options.resolution;
+            let last = (stop.div_ceil(options.resolution)).max(first + 1);
+            for step in first..last.min(steps as u64) {
+                let cell = &mut cells[step as usize * width + column];
+                *cell = (*cell).max(velocity);
+            }
+        };
+
+        // velocity and start tick of the notes playing, by channel and key
+        let mut playing = HashMap::<(u8, u8), VecDeque<(u8, u64)>>::new();
+        for event in track.events.iter() {
+            let Event::Midi { data, time, .. } = event else {
+                continue;
+            };
+            match *data {
+                MidiEvent::NoteOn {
+                    chan,
+                    note,
+                    velocity: velocity @ 1..,
+                    ..
+                } => playing
+                    .entry((chan, note))
+                    .or_default()
+                    .push_back((velocity, time.tick)),
+                MidiEvent::NoteOn { chan, note, .. } | MidiEvent::NoteOff { chan, note, .. } => {
+                    if let Some((velocity, start)) = playing
+                        .get_mut(&(chan, note))
+                        .and_then(|notes| options.note_match.take(notes))
+                    {
+                        fill(note, velocity, start, time.tick);
+                    }
+                },
+                _ => {},
+            }
+        }
+        for ((_, note), notes) in playing {
+            for (velocity, start) in notes {
+                fill(note, velocity, start, end);
+            }
+        }
+
+        let (grid, blob) = if options.blob {
+            (
+                None,
+                Some(base64::engine::general_purpose::STANDARD.encode(&cells)),
+            )
+        } else {
+            (
+                Some(cells.chunks(width).map(<[u8]>::to_vec).collect()),
+                None,
+            )
+        };
+
+        PianoRoll {
+            generated: track.info.generated.clone(),
+            source_file: track.info.source_file.clone(),
+            source_sha256: track.info.source_sha256.clone(),
+            header: track.info.header.clone(),
+            resolution: options.resolution,
+            low_note: options.low_note,
+            high_note: options.high_note,
+            steps,
+            grid,
+            blob,
+        }
+    }
+}
@@ -1,24 +1,351 @@
+use anyhow::bail;
+use itertools::{Itertools, MultiPeek};
 use midly::Smf;
+use std::{
+    collections::{HashMap, VecDeque},
+    ops::RangeInclusive,
+};
 
 use crate::{
-    model::{self, CDTrackEvent, MetaEvent, MidiEvent, PlayerResult, PlayerTimingInfo, TimeInfo},
-    trackmode::TrackMode,
+    beats::BeatGrid,
+    check,
+    click::{ClickOptions, Metronome},
+    gm::{self, DRUM_CHANNEL},
+    model::{
+        self,
+        AftertouchScope,
+        CDTrackEvent,
+        EscapeEvent,
+        EventInfo,
+        HeaderInfo,
+        LiveMessage,
+        MetaEvent,
+        MidiEvent,
+        NoteDeltaScope,
+        Pedal,
+        PlayerResult,
+        PlayerTimingInfo,
+        Rounding,
+        SysexEvent,
+        TimeInfo,
+    },
+    notes::{NoteMatch, NoteNaming},
+    snapshot::{Snapshot, SnapshotRecorder},
+    sysex::{self, SysexEncoding},
+    tempo::{SmpteBeats, TempoMapFile},
+    thin::Thinner,
+    trackmode::{LoopRegion, MergeStrategy, TieBreak, TrackMode},
+    velocity::VelocityCurve,
 };
 
+macro_rules! micros_to_secs {
+    ($e:expr) => {{
+        let __value = ($e as f64 / crate::model::MICROS_PER_SECOND as f64);
+        (if (__value.fract() >= 0.5) {
+            __value.ceil()
+        } else {
+            __value.floor()
+        }) as f32
+    }};
+}
+
+/// Conversion settings for the [`MidiPlayer`]
+#[derive(Debug, Default, Clone)]
+pub struct PlayerOptions {
+    /// Include meta events
+    pub emit_meta:                  bool,
+    /// Emit timing information as deltas instead of absolute timestamps
+    pub delta_times:                bool,
+    /// Curve applied to all note on velocities
+    pub velocity_curve:             Option<VelocityCurve>,
+    /// Attach the debug representation of the source event
+    pub include_raw:                bool,
+    /// Ticks per quarter note to use instead of the one in the header
+    pub ppqn:                       Option<u16>,
+    /// Minimum distance in milliseconds between two aftertouch events on the
+    /// same channel and note
+    pub thin_aftertouch:            Option<f64>,
+    /// Minimum distance in milliseconds between two controller or pitch bend
+    /// events on the same channel and controller, the last event of a run is
+    /// always kept
+    pub thin_cc:                    Option<f64>,
+    /// Record events that are not played at their absolute tick
+    pub validate_deltas:            bool,
+    /// Emit SysEx events with their payload in the given encoding
+    pub sysex:                      Option<SysexEncoding>,
+    /// Drop note off events, including note ons with a velocity of 0
+    pub drop_note_off:              bool,
+    /// Round float fields to this many decimals. By default seconds are
+    /// rounded to whole seconds
+    pub float_precision:            Option<u8>,
+    /// Only emit tempo, time signature, key signature and SMPTE offset
+    /// events. Implies `emit_meta` for those events
+    pub conductor_only:             bool,
+    /// Attach the processed and emitted index to each event
+    pub index:                      bool,
+    /// Play a tick range multiple times
+    pub loop_region:                Option<LoopRegion>,
+    /// Add a `scope` field to aftertouch events, telling polyphonic (`note`)
+    /// and channel aftertouch (`channel`) apart
+    pub aftertouch_verbose:         bool,
+    /// Only emit channel events on these channels (0-15)
+    pub channels:                   Option<Vec<u8>>,
+    /// How the `micros` field is rounded
+    pub rounding:                   Rounding,
+    /// Annotate note ons with the interval to the previous note on the same
+    /// channel
+    pub intervals:                  bool,
+    /// Add note names to note on and note off events
+    pub note_names:                 Option<NoteNaming>,
+    /// Drop note ons with a source velocity outside this range, together with
+    /// their note offs
+    pub velocity_range:             Option<RangeInclusive<u8>>,
+    /// Only let tempo changes of this track change the tempo. Tempo events of
+    /// other tracks are still emitted
+    pub tempo_track:                Option<usize>,
+    /// Only track ticks, leaving out micros and seconds
+    pub no_time:                    bool,
+    /// Emit escape (`F7`) events
+    pub escape:                     bool,
+    /// Drop controller events repeating the previous value of the controller
+    pub merge_adjacent_cc:          bool,
+    /// Order of events of different tracks on the same tick in `Parallel`
+    /// files
+    pub tie_break:                  TieBreak,
+    /// How the tracks are combined, instead of by the format in the header
+    pub strategy:                   Option<MergeStrategy>,
+    /// Consume `MidiChannel` and `MidiPort` meta events and attach the
+    /// channel and port they set to the following meta and SysEx events of
+    /// their track
+    pub channel_prefix:             bool,
+    /// Add the time since the previous note on to note ons
+    pub note_delta:                 Option<NoteDeltaScope>,
+    /// Write a velocity of 0 for all note offs
+    pub collapse_note_off_velocity: bool,
+    /// Drop tempo events setting the tempo that is already in effect
+    pub dedupe_tempo:               bool,
+    /// Emit realtime and system common messages stored in escape events as
+    /// `live` events, instead of treating them as escapes
+    pub live_messages:              bool,
+    /// Move end of track events after the last channel event back to the
+    /// previous event, so the file ends with its last note
+    pub trim_trailing_silence:      bool,
+    /// Emit the controllers of these pedals as pressed or released
+    pub pedals:                     Vec<Pedal>,
+    /// Multiply all micros and seconds by this factor, leaving ticks and the
+    /// values of tempo events alone
+    pub retime:                     Option<f64>,
+    /// Add a metronome click on the drum channel on every beat
+    pub click:                      Option<ClickOptions>,
+    /// Attach the bar, beat and tick within the beat to every event
+    pub beat_position:              bool,
+    /// Drop channel events whose absolute tick is not a multiple of this
+    pub tick_modulo:                Option<u64>,
+    /// Drop note ons and note offs off the `tick_modulo` grid as well,
+    /// instead of always keeping them
+    pub tick_modulo_notes:          bool,
+    /// Mark note ons with a velocity of 0 with `implied_note_off`
+    pub annotate_note_off:          bool,
+    /// Drop events with out of spec values, see [`check::is_out_of_spec`]
+    pub sanitize:                   bool,
+    /// Emit meta events of an unknown type, even without `emit_meta`
+    pub emit_unknown:               bool,
+    /// Add the absolute time in quarter notes to every event
+    pub quarters:                   bool,
+    /// Add the time since the previous event in quarter notes to every event
+    pub beat_delta:                 bool,
+    /// Link note offs to the emitted index of the note on they end
+    pub link_note_pairs:            bool,
+    /// Which note on a note off ends when several are playing on its channel
+    /// and key
+    pub note_match:                 NoteMatch,
+    /// Add the program and instrument playing on their channel to note ons
+    pub note_program:               bool,
+    /// Emit a rest after a note on that ends a gap of more than this many
+    /// milliseconds without notes on its channel
+    pub emit_rests:                 Option<u64>,
+    /// Only emit note ons and note offs of these notes (0-127)
+    pub notes:                      Option<Vec<u8>>,
+    /// Drop all other channel events as well with `notes`
+    pub notes_only:                 bool,
+    /// Tempo and time signatures of an SMPTE timed file, for `beat_position`
+    /// and `quarters`
+    pub tempo_map:                  Option<TempoMapFile>,
+    /// Record the state of playback at this tick, see [`Snapshot`]
+    pub snapshot_tick:              Option<u64>,
+    /// Keep a list of the events of the file that are not emitted, see
+    /// [`MidiPlayer::ignored_events`]
+    pub record_ignored:             bool,
+}
+
+/// The `MidiChannel` and `MidiPort` prefixes in effect on a track
+#[derive(Debug, Default, Clone, Copy)]
+struct Prefix {
+    /// Set by `MidiChannel`, until the next channel event of the track
+    channel: Option<u8>,
+    /// Set by `MidiPort`, until the end of the track
+    port:    Option<u8>,
+}
+
+/// The notes played last on a channel, for `--intervals`
+#[derive(Debug, Default)]
+struct NoteHistory {
+    tick:   u64,
+    last:   Option<u8>,
+    /// The last note before `tick`, notes of a chord are all compared to it
+    before: Option<u8>,
+}
+
+impl NoteHistory {
+    fn interval(&mut self, tick: u64, note: u8) -> Option<i8> {
+        if tick != self.tick || self.last.is_none() {
+            self.tick = tick;
+            self.before = self.last;
+        }
+        self.last = Some(note);
+
+        self.before
+            .map(|before| (note as i16 - before as i16) as i8)
+    }
+}
+
+/// An event whose delta does not take the clock to its absolute tick, as
+/// when it was merged out of tick order
+#[derive(Debug, Clone)]
+pub struct DeltaViolation {
+    /// Position of the event in the merged stream
+    pub index:       usize,
+    /// Absolute tick in the file
+    pub tick:        u64,
+    /// The tick the deltas of all events up to this one add up to, where the
+    /// event is played
+    pub played_tick: u64,
+}
+
+/// An event of the file that was played but not emitted
+#[derive(Debug, Clone)]
+pub struct IgnoredEvent {
+    /// Position of the event in the merged stream
+    pub index: usize,
+    pub track: u32,
+    /// Absolute tick in the file
+    pub tick:  u64,
+    /// See [`check::describe`]
+    pub kind:  String,
+}
+
 pub struct MidiPlayerIter<'data, 'smf>(MidiPlayer<'data, 'smf>);
 
+impl<'data, 'smf> MidiPlayerIter<'data, 'smf> {
+    pub fn player(&self) -> &MidiPlayer<'data, 'smf> { &self.0 }
+}
+
 impl<'data, 'smf> Iterator for MidiPlayerIter<'data, 'smf> {
     type Item = PlayerResult<model::Event>;
 
+    // every source event is either emitted or ignored, the clicks of a
+    // metronome and rests come on top
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let pending = self.0.pending_rest.is_some() as usize;
+        let (lo, hi) = self.0.events.size_hint();
+        let lo = lo.saturating_add(pending);
+        match (&self.0.metronome, self.0.emit_rests) {
+            (None, None) => (lo, hi.and_then(|hi| hi.checked_add(pending))),
+            _ => (lo, None),
+        }
+    }
+
     fn next(&mut self) -> Option<Self::Item> { self.0.next_event() }
 }
 
 pub struct MidiPlayer<'data, 'smf> {
-    emit_delta_times: bool,
-    emit_meta:        bool,
-    extra_delta:      u64,
-    timing:           PlayerTimingInfo,
-    events:           TrackMode<'data, 'smf>,
+    header:                     HeaderInfo,
+    emit_delta_times:           bool,
+    emit_meta:                  bool,
+    emit_unknown:               bool,
+    quarters:                   bool,
+    beat_delta:                 bool,
+    /// Tick of the last event `delta_beats` was given for
+    last_emitted_tick:          u64,
+    /// Emitted indices of the notes playing by channel and key, oldest first,
+    /// when linking note pairs
+    note_pairs:                 Option<HashMap<(u8, u8), VecDeque<usize>>>,
+    note_match:                 NoteMatch,
+    /// The last program changed to on every channel, when annotating note ons
+    /// with it
+    programs:                   Option<[Option<u8>; 16]>,
+    emit_rests:                 Option<u64>,
+    /// Number of notes playing and the micros the last one ended at, by
+    /// channel
+    channel_activity:           HashMap<u8, (usize, Option<f64>)>,
+    /// A rest to emit before the next event
+    pending_rest:               Option<model::Event>,
+    velocity_curve:             Option<VelocityCurve>,
+    include_raw:                bool,
+    raw_bytes:                  Option<&'data [Vec<&'smf [u8]>]>,
+    aftertouch_thinning:        Option<Thinner<(u8, Option<u8>)>>,
+    cc_thinning:                Option<Thinner<(u8, Option<u8>)>>,
+    validate_deltas:            bool,
+    sysex_encoding:             Option<SysexEncoding>,
+    drop_note_off:              bool,
+    float_precision:            Option<u8>,
+    conductor_only:             bool,
+    aftertouch_verbose:         bool,
+    channels:                   Option<Vec<u8>>,
+    rounding:                   Rounding,
+    note_history:               Option<HashMap<u8, NoteHistory>>,
+    note_names:                 Option<NoteNaming>,
+    velocity_range:             Option<RangeInclusive<u8>>,
+    notes:                      Option<Vec<u8>>,
+    notes_only:                 bool,
+    /// Note ons dropped by the velocity range, waiting for their note off
+    dropped_notes:              HashMap<(u8, u8), u32>,
+    tempo_track:                Option<usize>,
+    no_time:                    bool,
+    emit_escape:                bool,
+    /// Last value of every controller, for merging repeated values
+    cc_values:                  Option<HashMap<(u8, u8), u8>>,
+    /// Prefixes in effect by track, when applying prefixes
+    prefixes:                   Option<HashMap<u32, Prefix>>,
+    note_delta:                 Option<NoteDeltaScope>,
+    collapse_note_off_velocity: bool,
+    dedupe_tempo:               bool,
+    emit_live:                  bool,
+    trim_trailing_silence:      bool,
+    pedals:                     Vec<Pedal>,
+    /// The tempo set by the last applied tempo event
+    last_tempo:                 Option<u32>,
+    /// Tick and micros of the last note on, by channel or `None` for all
+    /// channels
+    last_note_times:            HashMap<Option<u8>, (u64, f64)>,
+    violations:                 Vec<DeltaViolation>,
+    /// Number of meta events of a type midly does not know, emitted or not
+    unknown_events:             usize,
+    sanitize:                   bool,
+    /// Number of events dropped as out of spec
+    invalid_events:             usize,
+    emitted:                    usize,
+    emit_index:                 bool,
+    /// The tick the deltas of the events so far add up to, when validating
+    /// deltas
+    delta_clock:                u64,
+    extra_delta:                u64,
+    metronome:                  Option<Metronome>,
+    beat_position:              bool,
+    /// Bars and beats for `beat_position`, `None` for SMPTE timed files
+    beat_grid:                  Option<BeatGrid>,
+    /// Bars, beats and quarters of an SMPTE timed file from a tempo map
+    smpte_beats:                Option<SmpteBeats>,
+    snapshot:                   Option<SnapshotRecorder>,
+    ignored:                    Option<Vec<IgnoredEvent>>,
+    tick_modulo:                Option<u64>,
+    tick_modulo_notes:          bool,
+    annotate_note_off:          bool,
+    /// Ticks the clicks played since the last event of the file advanced the
+    /// clock by, taken off the delta of the next one
+    click_ahead:                u64,
+    timing:                     PlayerTimingInfo,
+    events:                     MultiPeek<TrackMode<'data, 'smf>>,
 }
 
 impl<'data, 'smf> IntoIterator for MidiPlayer<'data, 'smf> {
@@ -30,23 +357,364 @@ impl<'data, 'smf> IntoIterator for MidiPlayer<'data, 'smf> {
 }
 
 impl<'data, 'smf> MidiPlayer<'data, 'smf> {
-    pub fn new(smf: &'data Smf<'smf>, emit_meta: bool, delta_times: bool) -> Self {
-        let timing = smf.header.timing.clone();
-        Self {
-            emit_meta,
-            emit_delta_times: delta_times,
-            extra_delta: 0,
-            events: TrackMode::from_smf(smf),
-            timing: PlayerTimingInfo::from(timing),
+    pub fn new(smf: &'data Smf<'smf>, options: PlayerOptions) -> anyhow::Result<Self> {
+        let header = HeaderInfo::new(smf.header, smf.tracks.len());
+        Self::with_events(
+            smf.header,
+            header,
+            TrackMode::with_strategy(
+                smf,
+                options.strategy.unwrap_or(smf.header.format.into()),
+                options.tie_break,
+            )?,
+            options,
+        )
+    }
+
+    /// Play lazily parsed tracks, without the file being fully parsed into
+    /// memory
+    pub fn from_tracks<I>(
+        header: midly::Header,
+        tracks: Vec<I>,
+        options: PlayerOptions,
+    ) -> anyhow::Result<Self>
+    where
+        'smf: 'data,
+        I: Iterator<Item = midly::TrackEvent<'smf>> + 'data,
+    {
+        let info = HeaderInfo::new(header, tracks.len());
+        Self::with_events(
+            header,
+            info,
+            TrackMode::from_tracks(
+                options.strategy.unwrap_or(header.format.into()),
+                tracks,
+                options.tie_break,
+            )?,
+            options,
+        )
+    }
+
+    /// Play a single track on its own timeline, as the patterns of a
+    /// sequential file are meant to be
+    pub fn for_pattern(
+        smf: &'data Smf<'smf>,
+        track: usize,
+        options: PlayerOptions,
+    ) -> anyhow::Result<Self> {
+        Self::new(smf, PlayerOptions {
+            strategy: Some(MergeStrategy::PerTrack(track)),
+            ..options
+        })
+    }
+
+    fn with_events(
+        header: midly::Header,
+        header_info: HeaderInfo,
+        events: TrackMode<'data, 'smf>,
+        options: PlayerOptions,
+    ) -> anyhow::Result<Self>
+    where
+        'smf: 'data,
+    {
+        let timing = match (header.timing, options.ppqn) {
+            (midly::Timing::Metrical(_), Some(ppqn)) => {
+                if ppqn == 0 || ppqn > 0x7FFF {
+                    bail!("ppqn must be between 1 and 32767");
+                }
+                midly::Timing::Metrical(ppqn.into())
+            },
+            (midly::Timing::Timecode(..), Some(_)) => {
+                bail!("cannot override the ppqn of a SMPTE timed file")
+            },
+            (timing, None) => timing,
+        };
+
+        let timing_info = PlayerTimingInfo::from(timing).with_scale(options.retime.unwrap_or(1.0));
+        if !options.no_time {
+            timing_info.check()?;
+        }
+
+        if let Some(channel) = options.channels.iter().flatten().find(|&&c| c > 15) {
+            bail!("channel {} does not exist, channels are 0 to 15", channel);
         }
+
+        if let Some(note) = options.notes.iter().flatten().find(|&&n| n > 127) {
+            bail!("note {} does not exist, notes are 0 to 127", note);
+        }
+
+        if let Some(range) = options.velocity_range.as_ref() {
+            if range.is_empty() || *range.end() > 127 {
+                bail!("the velocity range must not be empty and end at 127 at most");
+            }
+        }
+
+        if options.tempo_map.is_some() {
+            if !matches!(timing, midly::Timing::Timecode(..)) {
+                bail!("a tempo map can only be used with SMPTE timed files");
+            }
+            if options.no_time {
+                bail!("a tempo map needs the time of the events");
+            }
+        }
+
+        if options.tick_modulo == Some(0) {
+            bail!("the tick modulo must be at least 1");
+        }
+
+        let metronome = match (options.click, timing) {
+            (Some(click), midly::Timing::Metrical(ppqn)) => {
+                if click.note > 127 || click.velocity > 127 {
+                    bail!("the click note and velocity must be between 0 and 127");
+                }
+                Some(Metronome::new(click, ppqn.as_int()))
+            },
+            (Some(_), midly::Timing::Timecode(..)) => {
+                bail!("a click can only be added to files with metrical timing")
+            },
+            (None, _) => None,
+        };
+
+        let events = match options.loop_region {
+            Some(region) => {
+                if region.from >= region.to || region.count == 0 {
+                    bail!("the loop region must not be empty and be played at least once");
+                }
+                events.expand_loop(region)
+            },
+            None => events,
+        };
+
+        Ok(Self {
+            header: header_info,
+            emit_meta: options.emit_meta || options.conductor_only,
+            emit_unknown: options.emit_unknown,
+            quarters: options.quarters,
+            beat_delta: options.beat_delta,
+            last_emitted_tick: 0,
+            note_pairs: options.link_note_pairs.then(HashMap::new),
+            note_match: options.note_match,
+            programs: options.note_program.then_some([None; 16]),
+            emit_rests: options.emit_rests,
+            channel_activity: HashMap::new(),
+            pending_rest: None,
+            emit_delta_times: options.delta_times,
+            velocity_curve: options.velocity_curve,
+            include_raw: options.include_raw,
+            raw_bytes: None,
+            aftertouch_thinning: options.thin_aftertouch.map(Thinner::new),
+            cc_thinning: options.thin_cc.map(Thinner::new),
+            validate_deltas: options.validate_deltas,
+            sysex_encoding: options.sysex,
+            drop_note_off: options.drop_note_off,
+            float_precision: options.float_precision,
+            conductor_only: options.conductor_only,
+            aftertouch_verbose: options.aftertouch_verbose,
+            channels: options.channels,
+            rounding: options.rounding,
+            note_history: options.intervals.then(HashMap::new),
+            note_names: options.note_names,
+            velocity_range: options.velocity_range,
+            notes: options.notes,
+            notes_only: options.notes_only,
+            dropped_notes: HashMap::new(),
+            tempo_track: options.tempo_track,
+            no_time: options.no_time,
+            emit_escape: options.escape,
+            cc_values: options.merge_adjacent_cc.then(HashMap::new),
+            prefixes: options.channel_prefix.then(HashMap::new),
+            note_delta: options.note_delta,
+            collapse_note_off_velocity: options.collapse_note_off_velocity,
+            dedupe_tempo: options.dedupe_tempo,
+            emit_live: options.live_messages,
+            trim_trailing_silence: options.trim_trailing_silence,
+            pedals: options.pedals,
+            last_tempo: None,
+            last_note_times: HashMap::new(),
+            violations: Vec::new(),
+            unknown_events: 0,
+            sanitize: options.sanitize,
+            invalid_events: 0,
+            emitted: 0,
+            emit_index: options.index,
+            delta_clock: 0,
+            extra_delta: 0,
+            metronome,
+            beat_position: options.beat_position,
+            beat_grid: match timing {
+                midly::Timing::Metrical(ppqn) if options.beat_position => {
+                    Some(BeatGrid::new(ppqn.as_int()))
+                },
+                _ => None,
+            },
+            smpte_beats: options.tempo_map.as_ref().map(SmpteBeats::new),
+            snapshot: options
+                .snapshot_tick
+                .map(|tick| SnapshotRecorder::new(tick, options.note_match)),
+            ignored: options.record_ignored.then(Vec::new),
+            tick_modulo: options.tick_modulo,
+            tick_modulo_notes: options.tick_modulo_notes,
+            annotate_note_off: options.annotate_note_off,
+            click_ahead: 0,
+            events: events.multipeek(),
+            timing: timing_info,
+        })
+    }
+
+    /// Attach the source bytes of every event, indexed by track and event
+    /// index, as obtained from [`midly::SmfBytemap`]
+    pub fn with_raw_bytes(mut self, raw_bytes: &'data [Vec<&'smf [u8]>]) -> Self {
+        self.raw_bytes = Some(raw_bytes);
+        self
     }
 
     pub fn next_event(&mut self) -> Option<PlayerResult<model::Event>> {
-        self.events.next().map(|event| self._next_event(event))
+        if let Some(rest) = self.pending_rest.take() {
+            self.emitted += 1;
+            return Some(self.add_position(PlayerResult::Event(rest)));
+        }
+
+        if let Some(click) = self.next_click() {
+            return Some(self.add_position(click));
+        }
+
+        let mut event = self.events.next()?;
+        if self.validate_deltas {
+            self.delta_clock += event.real_delta as u64;
+            if event.absolute_tick != self.delta_clock {
+                self.violations.push(DeltaViolation {
+                    index:       event.index,
+                    tick:        event.absolute_tick,
+                    played_tick: self.delta_clock,
+                });
+            }
+        }
+        event.real_delta -= std::mem::take(&mut self.click_ahead) as usize;
+
+        let result = self._next_event(event);
+        if let (Some(ignored), PlayerResult::Ignored) = (self.ignored.as_mut(), &result) {
+            ignored.push(IgnoredEvent {
+                index: event.index,
+                track: event.source_track,
+                tick:  event.absolute_tick,
+                kind:  check::describe(&event.event.kind),
+            });
+        }
+        Some(self.add_position(result))
+    }
+
+    /// Attach the beat position of the current tick to an emitted event
+    fn add_position(&mut self, result: PlayerResult<model::Event>) -> PlayerResult<model::Event> {
+        if !self.beat_position {
+            return result;
+        }
+
+        result.map(|mut ev| {
+            let (tick, micros) = self.timing.elapsed();
+            let position = match (self.beat_grid.as_ref(), self.smpte_beats.as_mut()) {
+                (Some(grid), _) => Some(grid.position(tick)),
+                (None, Some(beats)) => Some(beats.position(micros)),
+                (None, None) => None,
+            };
+            ev.info_mut().position =
+                Some(position.map(|(bar, beat, tick)| format!("{}.{}.{}", bar, beat, tick)));
+            ev
+        })
     }
 
+    /// Play the next click of the metronome if it is due before the next
+    /// event of the file
+    fn next_click(&mut self) -> Option<PlayerResult<model::Event>> {
+        let metronome = self.metronome.as_mut()?;
+        let now = self.timing.elapsed().0 + self.extra_delta;
+
+        self.events.reset_peek();
+        let until = self
+            .events
+            .peek()
+            .map(|event| now + event.real_delta as u64 - self.click_ahead);
+        self.events.reset_peek();
+
+        let (tick, on) = metronome.next(until)?;
+        let click = metronome.options();
+        let delta = tick - now;
+        self.click_ahead += delta;
+
+        let (key, vel) = (click.note.into(), click.velocity.into());
+        let message = if on {
+            midly::MidiMessage::NoteOn { key, vel }
+        } else {
+            midly::MidiMessage::NoteOff {
+                key,
+                vel: 64.into(),
+            }
+        };
+        let result = self.handle_midi(self.header.num_tracks as u32, DRUM_CHANNEL, message, delta);
+        if let PlayerResult::Event(_) = result {
+            self.emitted += 1;
+        }
+        Some(result)
+    }
+
+    /// The header of the played file
+    pub fn header(&self) -> &HeaderInfo { &self.header }
+
+    /// Number of meta events of an unknown type played so far. They are only
+    /// emitted with `emit_meta` or `emit_unknown`, but counted either way so a
+    /// caller can tell that a conversion lost data
+    pub fn unknown_events(&self) -> usize { self.unknown_events }
+
+    /// Number of out of spec events dropped so far, `None` unless sanitizing
+    pub fn invalid_events(&self) -> Option<usize> { self.sanitize.then_some(self.invalid_events) }
+
+    /// Events not played at their absolute tick, only recorded when
+    /// validating deltas
+    pub fn violations(&self) -> &[DeltaViolation] { &self.violations }
+
+    /// Events played but not emitted so far, only recorded with
+    /// `record_ignored`
+    pub fn ignored_events(&self) -> &[IgnoredEvent] { self.ignored.as_deref().unwrap_or_default() }
+
     fn _next_event(&mut self, event: CDTrackEvent) -> PlayerResult<model::Event> {
-        match event.event.kind {
+        let raw = self.raw_bytes.and_then(|raw| {
+            raw.get(event.source_track as usize)
+                .and_then(|track| track.get(event.source_index))
+                .copied()
+        });
+
+        if self.sanitize && check::is_out_of_spec(&event.event.kind) {
+            self.invalid_events += 1;
+            self.extra_delta += event.real_delta as u64;
+            return PlayerResult::Ignored;
+        }
+
+        if self.snapshot.is_some() {
+            let tempo = self.tempo();
+            if let Some(snapshot) = self.snapshot.as_mut() {
+                snapshot.play(event.absolute_tick, &event.event.kind, tempo);
+            }
+        }
+
+        let mut prefix = None;
+        if let Some(prefixes) = self.prefixes.as_mut() {
+            let state = prefixes.entry(event.source_track).or_default();
+            match event.event.kind {
+                midly::TrackEventKind::Meta(midly::MetaMessage::MidiChannel(channel)) => {
+                    state.channel = Some(channel.as_int());
+                    self.extra_delta += event.real_delta as u64;
+                    return PlayerResult::Ignored;
+                },
+                midly::TrackEventKind::Meta(midly::MetaMessage::MidiPort(port)) => {
+                    state.port = Some(port.as_int());
+                    self.extra_delta += event.real_delta as u64;
+                    return PlayerResult::Ignored;
+                },
+                midly::TrackEventKind::Midi { .. } => state.channel = None,
+                _ => prefix = Some(*state),
+            }
+        }
+
+        let result = match event.event.kind {
             midly::TrackEventKind::Midi { channel, message } => self.handle_midi(
                 event.source_track,
                 channel.as_int(),
@@ -62,39 +730,209 @@ impl<'data, 'smf> MidiPlayer<'data, 'smf> {
             midly::TrackEventKind::Meta(message) => {
                 self.handle_meta(event.source_track, message, event.real_delta as u64)
             },
+        };
+
+        if let PlayerResult::Event(_) = result {
+            self.emitted += 1;
+        }
+
+        if let PlayerResult::Event(model::Event::Midi { data, track, .. }) = &result {
+            self.track_rests(data, *track);
+        }
+
+        result.map(|mut ev| {
+            let info = ev.info_mut();
+            if self.emit_index {
+                info.index = Some(event.index);
+                info.emitted_index = Some(self.emitted - 1);
+            }
+            if let Some(raw) = raw {
+                info.bytes = Some(model::to_hex(raw));
+                info.running_status = Some(raw.first().is_some_and(|status| *status < 0x80));
+            }
+            if self.include_raw {
+                info.raw = Some(format!("{:?}", event.event.kind));
+            }
+            if let Some(prefix) = prefix {
+                info.channel_prefix = prefix.channel;
+                info.port_prefix = prefix.port;
+            }
+            ev
+        })
+    }
+
+    /// Count the notes playing on the channel of a note event, and queue a
+    /// rest if a note on ended a long enough gap
+    fn track_rests(&mut self, data: &MidiEvent, track: u32) {
+        let Some(threshold) = self.emit_rests else {
+            return;
+        };
+        let now = self.timing.elapsed().1;
+
+        match *data {
+            MidiEvent::NoteOn {
+                chan,
+                velocity: 1..,
+                ..
+            } => {
+                let (playing, last_end) = self.channel_activity.entry(chan).or_default();
+                *playing += 1;
+                let gap = match last_end.take() {
+                    Some(end) if *playing == 1 && now - end > (threshold * 1000) as f64 => {
+                        now - end
+                    },
+                    _ => return,
+                };
+
+                let time = self.make_time_info(0);
+                let mut info = model::EventInfo::default();
+                if self.emit_index {
+                    info.emitted_index = Some(self.emitted);
+                }
+                self.pending_rest = Some(model::Event::Midi {
+                    time,
+                    data: MidiEvent::Rest {
+                        chan,
+                        duration_micros: self.rounding.apply(gap),
+                    },
+                    track,
+                    info,
+                });
+            },
+            MidiEvent::NoteOn { chan, .. } | MidiEvent::NoteOff { chan, .. } => {
+                let (playing, last_end) = self.channel_activity.entry(chan).or_default();
+                // a note off without a note playing does not end anything
+                if *playing > 0 {
+                    *playing -= 1;
+                    if *playing == 0 {
+                        *last_end = Some(now);
+                    }
+                }
+            },
+            _ => {},
         }
     }
 
     pub fn make_time_info(&mut self, delta: u64) -> TimeInfo {
-        macro_rules! micros_to_secs {
-            ($e:expr) => {{
-                let __value = ($e as f64 / crate::model::MICROS_PER_SECOND as f64);
-                (if (__value.fract() >= 0.5) {
-                    __value.ceil()
-                } else {
-                    __value.floor()
-                }) as f32
-            }};
+        let mut info = self.advance(delta);
+        if self.beat_delta {
+            // from the absolute ticks, events that are played but not emitted
+            // do not always pass their delta on
+            let tick = self.timing.elapsed().0;
+            let delta_tick = tick - std::mem::replace(&mut self.last_emitted_tick, tick);
+            info.delta_beats = Some(
+                self.timing
+                    .ppqn()
+                    .map(|ppqn| self.round_float(delta_tick as f64 / ppqn)),
+            );
+        }
+        if self.quarters {
+            let (tick, micros) = self.timing.elapsed();
+            let quarters = match (self.timing.ppqn(), self.smpte_beats.as_ref()) {
+                (Some(ppqn), _) => Some(tick as f64 / ppqn),
+                (None, Some(beats)) => Some(beats.quarters(micros)),
+                (None, None) => None,
+            };
+            info.abs_quarters = Some(quarters.map(|quarters| self.round_float(quarters)));
         }
+        info
+    }
 
-        let time_info = self.timing.next_tick(self.extra_delta + delta);
+    /// Advance the clock by `delta` ticks and whatever was skipped since the
+    /// last emitted event
+    fn advance(&mut self, delta: u64) -> TimeInfo {
+        let delta = self.extra_delta + delta;
         self.extra_delta = 0;
 
+        if self.no_time {
+            let abs_tick = self.timing.advance_ticks(delta);
+            return TimeInfo {
+                tick:         if self.emit_delta_times {
+                    delta
+                } else {
+                    abs_tick
+                },
+                micros:       None,
+                seconds:      None,
+                abs_quarters: None,
+                delta_beats:  None,
+            };
+        }
+
+        let time_info = self.timing.next_tick(delta);
         if self.emit_delta_times {
             TimeInfo {
-                tick:    time_info.delta_tick,
-                micros:  time_info.delta_micros as u64,
-                seconds: micros_to_secs!(time_info.delta_micros),
+                tick:         time_info.delta_tick,
+                micros:       Some(self.rounding.apply(time_info.delta_micros)),
+                seconds:      Some(self.to_seconds(time_info.delta_micros)),
+                abs_quarters: None,
+                delta_beats:  None,
             }
         } else {
             TimeInfo {
-                tick:    time_info.abs_tick,
-                micros:  time_info.abs_micros as u64,
-                seconds: micros_to_secs!(time_info.abs_micros),
+                tick:         time_info.abs_tick,
+                micros:       Some(self.rounding.apply(time_info.abs_micros)),
+                seconds:      Some(self.to_seconds(time_info.abs_micros)),
+                abs_quarters: None,
+                delta_beats:  None,
             }
         }
     }
 
+    /// Convert micros to seconds, rounded to whole seconds unless a float
+    /// precision was configured
+    fn to_seconds(&self, micros: f64) -> f32 {
+        match self.float_precision {
+            Some(_) => self.round_float(micros / model::MICROS_PER_SECOND as f64) as f32,
+            None => micros_to_secs!(micros),
+        }
+    }
+
+    /// Round a float field to the configured number of decimals
+    fn round_float(&self, value: f64) -> f64 {
+        match self.float_precision {
+            Some(precision) => {
+                let scale = 10f64.powi(precision as i32);
+                (value * scale).round() / scale
+            },
+            None => value,
+        }
+    }
+
+    /// Absolute time of the last emitted event
+    pub fn elapsed(&self) -> TimeInfo {
+        let (tick, micros) = self.timing.elapsed();
+        self.time_span(tick, micros)
+    }
+
+    /// Time info for a length in ticks and micros
+    fn time_span(&self, tick: u64, micros: f64) -> TimeInfo {
+        TimeInfo {
+            tick,
+            micros: Some(self.rounding.apply(micros)).filter(|_| !self.no_time),
+            seconds: Some(self.to_seconds(micros)).filter(|_| !self.no_time),
+            abs_quarters: None,
+            delta_beats: None,
+        }
+    }
+
+    /// Absolute time of the last emitted event in micros, without rounding
+    pub fn elapsed_micros(&self) -> f64 { self.timing.elapsed().1 }
+
+    /// The state of playback at the snapshot tick, `None` without one
+    pub fn snapshot(&self) -> Option<Snapshot> {
+        self.snapshot
+            .as_ref()
+            .map(|snapshot| snapshot.snapshot(self.tempo()))
+    }
+
+    /// Microseconds per quarter note in effect, `None` for SMPTE timed files
+    fn tempo(&self) -> Option<u32> {
+        self.timing
+            .ppqn()
+            .map(|_| self.last_tempo.unwrap_or(500_000))
+    }
+
     fn handle_midi(
         &mut self,
         track: u32,
@@ -102,21 +940,65 @@ impl<'data, 'smf> MidiPlayer<'data, 'smf> {
         message: midly::MidiMessage,
         delta: u64,
     ) -> PlayerResult<model::Event> {
-        let converted_msg = match message {
+        // followed before any filter, dropping a program change from the
+        // output does not change the instrument that plays
+        if let (Some(programs), midly::MidiMessage::ProgramChange { program }) =
+            (self.programs.as_mut(), message)
+        {
+            programs[channel as usize] = Some(program.as_int());
+        }
+
+        let filtered = self
+            .channels
+            .as_ref()
+            .is_some_and(|channels| !channels.contains(&channel));
+        if self.conductor_only
+            || filtered
+            || self.off_tick_grid(message, delta)
+            || self.drop_by_velocity(channel, message)
+            || self.drop_by_note(message)
+        {
+            self.extra_delta += delta;
+            return PlayerResult::Ignored;
+        }
+
+        let mut converted_msg = match message {
             midly::MidiMessage::NoteOff { key, vel } => MidiEvent::NoteOff {
-                chan:     channel,
-                note:     key.as_int(),
-                velocity: vel.as_int(),
+                chan:          channel,
+                note:          key.as_int(),
+                note_name:     self.note_names.map(|naming| naming.name(key.as_int())),
+                velocity:      if self.collapse_note_off_velocity {
+                    0
+                } else {
+                    vel.as_int()
+                },
+                note_on_index: None,
             },
             midly::MidiMessage::NoteOn { key, vel } => MidiEvent::NoteOn {
-                chan:     channel,
-                note:     key.as_int(),
-                velocity: vel.as_int(),
+                chan:                 channel,
+                note:                 key.as_int(),
+                note_name:            self.note_names.map(|naming| naming.name(key.as_int())),
+                velocity:             match self.velocity_curve.as_ref() {
+                    Some(curve) => curve.apply(vel.as_int()),
+                    None => vel.as_int(),
+                },
+                interval:             None,
+                time_since_last_note: None,
+                implied_note_off:     (self.annotate_note_off && vel == 0).then_some(true),
+                note_on_index:        None,
+                active_program:       self.programs.map(|programs| programs[channel as usize]),
+                active_instrument:    self.programs.map(|programs| {
+                    match programs[channel as usize] {
+                        Some(program) => Some(gm::instrument_name(channel, program)),
+                        None => (channel == DRUM_CHANNEL).then_some("Drum Kit"),
+                    }
+                }),
             },
             midly::MidiMessage::Aftertouch { key, vel } => MidiEvent::Aftertouch {
                 chan:     channel,
                 note:     key.as_int(),
                 velocity: vel.as_int(),
+                scope:    self.aftertouch_verbose.then_some(AftertouchScope::Note),
             },
             midly::MidiMessage::Controller { controller, value } => MidiEvent::Controller {
                 chan:  channel,
@@ -130,6 +1012,7 @@ impl<'data, 'smf> MidiPlayer<'data, 'smf> {
             midly::MidiMessage::ChannelAftertouch { vel } => MidiEvent::ChannelAftertouch {
                 chan:     channel,
                 velocity: vel.as_int(),
+                scope:    self.aftertouch_verbose.then_some(AftertouchScope::Channel),
             },
             midly::MidiMessage::PitchBend {
                 bend: midly::PitchBend(bend),
@@ -139,20 +1022,305 @@ impl<'data, 'smf> MidiPlayer<'data, 'smf> {
             },
         };
 
+        if self.drop_note_off
+            && matches!(
+                converted_msg,
+                MidiEvent::NoteOff { .. } | MidiEvent::NoteOn { velocity: 0, .. }
+            )
+        {
+            self.extra_delta += delta;
+            return PlayerResult::Ignored;
+        }
+
+        if let Some(thinner) = self.aftertouch_thinning.as_mut() {
+            let key = match converted_msg {
+                MidiEvent::Aftertouch { chan, note, .. } => Some((chan, Some(note))),
+                MidiEvent::ChannelAftertouch { chan, .. } => Some((chan, None)),
+                _ => None,
+            };
+
+            if let Some(key) = key {
+                let at = self.timing.peek_micros(self.extra_delta + delta);
+                if !thinner.admit(key, at) {
+                    self.extra_delta += delta;
+                    return PlayerResult::Ignored;
+                }
+            }
+        }
+
+        if let (Some(last_values), MidiEvent::Controller { chan, ctrl, value }) =
+            (self.cc_values.as_mut(), &converted_msg)
+        {
+            if last_values.insert((*chan, *ctrl), *value) == Some(*value) {
+                self.extra_delta += delta;
+                return PlayerResult::Ignored;
+            }
+        }
+
+        if let Some(thinner) = self.cc_thinning.as_mut() {
+            let key = match converted_msg {
+                MidiEvent::Controller { chan, ctrl, .. } => Some((chan, Some(ctrl))),
+                MidiEvent::PitchBend { chan, .. } => Some((chan, None)),
+                _ => None,
+            };
+
+            if let Some(key) = key {
+                let at = self.timing.peek_micros(self.extra_delta + delta);
+                if !thinner.admit(key, at)
+                    && !Self::is_last_of_run(
+                        &mut self.events,
+                        &self.timing,
+                        thinner.interval(),
+                        key,
+                        self.extra_delta + delta,
+                    )
+                {
+                    self.extra_delta += delta;
+                    return PlayerResult::Ignored;
+                }
+            }
+        }
+
+        if let (
+            Some(history),
+            MidiEvent::NoteOn {
+                chan,
+                note,
+                velocity: 1..,
+                interval,
+                ..
+            },
+        ) = (self.note_history.as_mut(), &mut converted_msg)
+        {
+            let tick = self.timing.elapsed().0 + self.extra_delta + delta;
+            *interval = Some(history.entry(*chan).or_default().interval(tick, *note));
+        }
+
+        if let MidiEvent::Controller { chan, ctrl, value } = converted_msg {
+            if let Some(pedal) = self.pedals.iter().find(|pedal| pedal.controller() == ctrl) {
+                converted_msg = pedal.event(chan, value);
+            }
+        }
+
         let time = self.make_time_info(delta);
 
+        if let (
+            Some(scope),
+            MidiEvent::NoteOn {
+                chan,
+                velocity: 1..,
+                time_since_last_note,
+                ..
+            },
+        ) = (self.note_delta, &mut converted_msg)
+        {
+            let key = (scope == NoteDeltaScope::Channel).then_some(*chan);
+            let (tick, micros) = self.timing.elapsed();
+            *time_since_last_note = Some(self.last_note_times.insert(key, (tick, micros)).map(
+                |(last_tick, last_micros)| self.time_span(tick - last_tick, micros - last_micros),
+            ));
+        }
+
+        // the event is emitted from here on, so it gets the next emitted index
+        let note_match = self.note_match;
+        if let Some(open) = self.note_pairs.as_mut() {
+            match &mut converted_msg {
+                MidiEvent::NoteOn {
+                    chan,
+                    note,
+                    velocity: 1..,
+                    ..
+                } => open
+                    .entry((*chan, *note))
+                    .or_default()
+                    .push_back(self.emitted),
+                MidiEvent::NoteOn {
+                    chan,
+                    note,
+                    note_on_index,
+                    ..
+                }
+                | MidiEvent::NoteOff {
+                    chan,
+                    note,
+                    note_on_index,
+                    ..
+                } => {
+                    *note_on_index = Some(
+                        open.get_mut(&(*chan, *note))
+                            .and_then(|playing| note_match.take(playing)),
+                    )
+                },
+                _ => {},
+            }
+        }
+
         PlayerResult::Event(model::Event::Midi {
             time,
             data: converted_msg,
             track,
+            info: EventInfo::default(),
         })
     }
+
+    /// Check if a channel event `delta` ticks from now is off the tick modulo
+    /// grid and not a note that is always kept
+    fn off_tick_grid(&self, message: midly::MidiMessage, delta: u64) -> bool {
+        let Some(modulo) = self.tick_modulo else {
+            return false;
+        };
+        let is_note = matches!(
+            message,
+            midly::MidiMessage::NoteOn { .. } | midly::MidiMessage::NoteOff { .. }
+        );
+        if is_note && !self.tick_modulo_notes {
+            return false;
+        }
+
+        let tick = self.timing.elapsed().0 + self.extra_delta + delta;
+        !tick.is_multiple_of(modulo)
+    }
+
+    /// Check if a note is outside the velocity range. The first note off after
+    /// a dropped note on of the same key is dropped as well
+    fn drop_by_velocity(&mut self, channel: u8, message: midly::MidiMessage) -> bool {
+        let Some(range) = self.velocity_range.as_ref() else {
+            return false;
+        };
+
+        match message {
+            midly::MidiMessage::NoteOn { key, vel } if vel > 0 => {
+                if range.contains(&vel.as_int()) {
+                    return false;
+                }
+                *self
+                    .dropped_notes
+                    .entry((channel, key.as_int()))
+                    .or_default() += 1;
+                true
+            },
+            midly::MidiMessage::NoteOn { key, .. } | midly::MidiMessage::NoteOff { key, .. } => {
+                match self.dropped_notes.get_mut(&(channel, key.as_int())) {
+                    Some(count) if *count > 0 => {
+                        *count -= 1;
+                        true
+                    },
+                    _ => false,
+                }
+            },
+            _ => false,
+        }
+    }
+
+    /// Check if a message is not a note on or note off of one of `notes`
+    fn drop_by_note(&self, message: midly::MidiMessage) -> bool {
+        let Some(notes) = self.notes.as_ref() else {
+            return false;
+        };
+
+        match message {
+            midly::MidiMessage::NoteOn { key, .. } | midly::MidiMessage::NoteOff { key, .. } => {
+                !notes.contains(&key.as_int())
+            },
+            _ => self.notes_only,
+        }
+    }
+
+    /// Check if no channel event follows anymore
+    fn is_trailing(&mut self) -> bool {
+        self.events.reset_peek();
+        while let Some(next) = self.events.peek() {
+            if let midly::TrackEventKind::Midi { .. } = next.event.kind {
+                return false;
+            }
+        }
+
+        true
+    }
+
+    /// Check if no other controller event with the same key follows within
+    /// `interval` micros. Tempo changes inside the interval are not taken into
+    /// account.
+    fn is_last_of_run(
+        events: &mut MultiPeek<TrackMode<'data, 'smf>>,
+        timing: &PlayerTimingInfo,
+        interval: f64,
+        key: (u8, Option<u8>),
+        delta: u64,
+    ) -> bool {
+        let start = timing.peek_micros(delta);
+        let mut ticks = delta;
+
+        events.reset_peek();
+        while let Some(next) = events.peek() {
+            ticks += next.real_delta as u64;
+            if timing.peek_micros(ticks) - start >= interval {
+                break;
+            }
+
+            if let midly::TrackEventKind::Midi { channel, message } = next.event.kind {
+                let next_key = match message {
+                    midly::MidiMessage::Controller { controller, .. } => {
+                        Some((channel.as_int(), Some(controller.as_int())))
+                    },
+                    midly::MidiMessage::PitchBend { .. } => Some((channel.as_int(), None)),
+                    _ => None,
+                };
+
+                if next_key == Some(key) {
+                    return false;
+                }
+            }
+        }
+
+        true
+    }
+
     fn handle_meta(
         &mut self,
         track: u32,
         message: midly::MetaMessage,
         delta: u64,
     ) -> PlayerResult<model::Event> {
+        if let midly::MetaMessage::Unknown(..) = message {
+            self.unknown_events += 1;
+        }
+
+        if let midly::MetaMessage::TimeSignature(numerator, denominator, ..) = message {
+            let tick = self.timing.elapsed().0 + self.extra_delta + delta;
+            if let Some(metronome) = self.metronome.as_mut() {
+                metronome.time_signature(tick, denominator);
+            }
+            if let Some(grid) = self.beat_grid.as_mut() {
+                grid.time_signature(tick, numerator, denominator);
+            }
+        }
+
+        // the silence between the last channel event and a final end of track
+        // is dropped, whatever was skipped since the last emitted event included
+        let delta = if self.trim_trailing_silence
+            && matches!(message, midly::MetaMessage::EndOfTrack)
+            && self.is_trailing()
+        {
+            self.extra_delta = 0;
+            0
+        } else {
+            delta
+        };
+
+        if self.conductor_only
+            && !matches!(
+                message,
+                midly::MetaMessage::Tempo(_)
+                    | midly::MetaMessage::TimeSignature(..)
+                    | midly::MetaMessage::KeySignature(..)
+                    | midly::MetaMessage::SmpteOffset(_)
+            )
+        {
+            self.extra_delta += delta;
+            return PlayerResult::Ignored;
+        }
+
         let parsed = match message {
             // normal meta messages, only emitted when emit_meta
             midly::MetaMessage::TrackNumber(tn) if self.emit_meta => {
@@ -196,8 +1364,23 @@ impl<'data, 'smf> MidiPlayer<'data, 'smf> {
             midly::MetaMessage::KeySignature(ksig, minor) if self.emit_meta => {
                 Some(MetaEvent::KeySignature(ksig, minor))
             },
-            midly::MetaMessage::Unknown(event, data) if self.emit_meta => {
-                Some(MetaEvent::Unknown(event, Vec::from(data)))
+            midly::MetaMessage::Unknown(meta_type, data) if self.emit_meta || self.emit_unknown => {
+                Some(MetaEvent::Unknown {
+                    meta_type,
+                    payload: model::to_hex(data),
+                })
+            },
+
+            // only part of the conductor track
+            midly::MetaMessage::SmpteOffset(smpte) if self.conductor_only => {
+                Some(MetaEvent::SmpteOffset {
+                    fps:      smpte.fps().as_f32(),
+                    hour:     smpte.hour(),
+                    minute:   smpte.minute(),
+                    second:   smpte.second(),
+                    frame:    smpte.frame(),
+                    subframe: smpte.subframe(),
+                })
             },
 
             // explicitly ignored meta messages
@@ -206,14 +1389,31 @@ impl<'data, 'smf> MidiPlayer<'data, 'smf> {
 
             // tempo
             midly::MetaMessage::Tempo(tpb) => {
-                // resets extra_delta and adds current delta
-                let time = self.make_time_info(delta);
-                self.timing.update_mpt(tpb.as_int());
+                let applies = self
+                    .tempo_track
+                    .is_none_or(|tempo_track| tempo_track == track as usize);
+                if self.dedupe_tempo && applies && self.last_tempo == Some(tpb.as_int()) {
+                    self.extra_delta += delta;
+                    return PlayerResult::Ignored;
+                }
+
+                // resets extra_delta and adds current delta. The clock has to
+                // reach the tempo change even if it is not emitted
+                let time = if self.emit_meta {
+                    self.make_time_info(delta)
+                } else {
+                    self.advance(delta)
+                };
+                if applies {
+                    self.timing.update_mpt(tpb.as_int());
+                    self.last_tempo = Some(tpb.as_int());
+                }
                 if self.emit_meta {
                     return PlayerResult::Event(model::Event::Meta {
                         time,
                         track,
                         data: MetaEvent::Tempo(tpb.as_int()),
+                        info: EventInfo::default(),
                     });
                 } else {
                     return PlayerResult::Ignored;
@@ -235,26 +1435,773 @@ impl<'data, 'smf> MidiPlayer<'data, 'smf> {
                     time,
                     track,
                     data: event,
+                    info: EventInfo::default(),
                 })
             },
         }
     }
-    fn handle_escape(
-        &mut self,
-        _track: u32,
-        _data: &[u8],
-        delta: u64,
-    ) -> PlayerResult<model::Event> {
-        self.extra_delta += delta;
-        PlayerResult::Ignored
+
+    fn handle_escape(&mut self, track: u32, data: &[u8], delta: u64) -> PlayerResult<model::Event> {
+        if let Some(message) = LiveMessage::from_escape(data) {
+            if self.emit_live && !self.conductor_only {
+                let time = self.make_time_info(delta);
+                return PlayerResult::Event(model::Event::Live {
+                    time,
+                    data: message,
+                    track,
+                    info: EventInfo::default(),
+                });
+            }
+        }
+
+        if !self.emit_escape || self.conductor_only {
+            self.extra_delta += delta;
+            return PlayerResult::Ignored;
+        }
+
+        let time = self.make_time_info(delta);
+        PlayerResult::Event(model::Event::Escape {
+            time,
+            data: EscapeEvent {
+                payload: model::to_hex(data),
+            },
+            track,
+            info: EventInfo::default(),
+        })
     }
-    fn handle_sysex(
-        &mut self,
-        _track: u32,
-        _data: &[u8],
-        delta: u64,
-    ) -> PlayerResult<model::Event> {
-        self.extra_delta += delta;
-        PlayerResult::Ignored
+
+    fn handle_sysex(&mut self, track: u32, data: &[u8], delta: u64) -> PlayerResult<model::Event> {
+        let encoding = match self.sysex_encoding {
+            Some(encoding) if !self.conductor_only => encoding,
+            _ => {
+                self.extra_delta += delta;
+                return PlayerResult::Ignored;
+            },
+        };
+
+        let time = self.make_time_info(delta);
+        PlayerResult::Event(model::Event::Sysex {
+            time,
+            data: SysexEvent {
+                manufacturer: sysex::manufacturer(data),
+                payload:      encoding.encode(data),
+            },
+            track,
+            info: EventInfo::default(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use midly::{Format, Header, MidiMessage, Timing, TrackEvent, TrackEventKind};
+
+    fn midi(delta: u32, channel: u8, message: MidiMessage) -> TrackEvent<'static> {
+        TrackEvent {
+            delta: delta.into(),
+            kind:  TrackEventKind::Midi {
+                channel: channel.into(),
+                message,
+            },
+        }
+    }
+
+    fn note_on(delta: u32, channel: u8, key: u8, vel: u8) -> TrackEvent<'static> {
+        midi(delta, channel, MidiMessage::NoteOn {
+            key: key.into(),
+            vel: vel.into(),
+        })
+    }
+
+    fn note_off(delta: u32, channel: u8, key: u8) -> TrackEvent<'static> {
+        midi(delta, channel, MidiMessage::NoteOff {
+            key: key.into(),
+            vel: 64.into(),
+        })
+    }
+
+    fn end(delta: u32) -> TrackEvent<'static> {
+        TrackEvent {
+            delta: delta.into(),
+            kind:  TrackEventKind::Meta(midly::MetaMessage::EndOfTrack),
+        }
+    }
+
+    fn header(format: Format) -> Header { Header::new(format, Timing::Metrical(480.into())) }
+
+    fn smf(format: Format, tracks: Vec<Vec<TrackEvent<'static>>>) -> Smf<'static> {
+        Smf {
+            header: header(format),
+            tracks,
+        }
+    }
+
+    /// A file with raw track chunks, deltas and running status included
+    fn file(format: u16, tracks: &[&[u8]]) -> Vec<u8> {
+        let mut bytes = b"MThd".to_vec();
+        bytes.extend(6u32.to_be_bytes());
+        bytes.extend(format.to_be_bytes());
+        bytes.extend((tracks.len() as u16).to_be_bytes());
+        bytes.extend(480u16.to_be_bytes());
+        for track in tracks {
+            bytes.extend(b"MTrk");
+            bytes.extend((track.len() as u32).to_be_bytes());
+            bytes.extend(*track);
+        }
+        bytes
+    }
+
+    fn play(smf: &Smf, options: PlayerOptions) -> Vec<model::Event> {
+        MidiPlayer::new(smf, options)
+            .unwrap()
+            .into_iter()
+            .filter_map(|result| match result {
+                PlayerResult::Event(event) => Some(event),
+                PlayerResult::Ignored => None,
+            })
+            .collect()
+    }
+
+    fn channels(events: &[model::Event]) -> Vec<u8> {
+        events
+            .iter()
+            .filter_map(|event| match event {
+                model::Event::Midi { data, .. } => Some(data.channel()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn merged<'smf>(real_delta: usize, absolute_tick: u64) -> CDTrackEvent<'smf> {
+        CDTrackEvent {
+            real_delta,
+            absolute_tick,
+            source_track: 0,
+            source_index: 0,
+            index: 0,
+            event: note_on(0, 0, 60, 100),
+        }
+    }
+
+    /// Play already merged events, returning the violations found
+    fn violations(events: Vec<CDTrackEvent<'static>>) -> Vec<(usize, u64, u64)> {
+        let header = header(Format::SingleTrack);
+        let mut player = MidiPlayer::with_events(
+            header,
+            HeaderInfo::new(header, 1),
+            TrackMode::from_events(events),
+            PlayerOptions {
+                validate_deltas: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        while player.next_event().is_some() {}
+
+        player
+            .violations()
+            .iter()
+            .map(|v| (v.index, v.tick, v.played_tick))
+            .collect()
+    }
+
+    #[test]
+    fn deltas_adding_up_are_valid() {
+        assert!(violations(vec![merged(100, 100), merged(0, 100), merged(50, 150)]).is_empty());
+    }
+
+    #[test]
+    fn event_out_of_order_is_a_violation() {
+        // clamped to a delta of 0, the event at tick 50 is played at tick 100
+        assert_eq!(
+            violations(vec![merged(100, 100), merged(0, 50), merged(50, 150)]),
+            vec![(1, 50, 100)]
+        );
+    }
+
+    #[test]
+    fn lost_delta_is_a_violation() {
+        // every later event is played too early once a delta is lost
+        assert_eq!(violations(vec![merged(0, 150), merged(100, 250)]), vec![
+            (0, 150, 0),
+            (1, 250, 100)
+        ]);
+    }
+
+    #[test]
+    fn snapshot_of_late_tracks() {
+        let smf = smf(Format::Parallel, vec![
+            vec![end(0)],
+            vec![note_on(100, 0, 60, 100), note_off(100, 0, 60), end(0)],
+            vec![note_on(150, 1, 61, 90), note_off(100, 1, 61), end(0)],
+        ]);
+        let mut player = MidiPlayer::new(&smf, PlayerOptions {
+            snapshot_tick: Some(120),
+            ..Default::default()
+        })
+        .unwrap();
+        while player.next_event().is_some() {}
+
+        let snapshot = player.snapshot().unwrap();
+        assert_eq!(snapshot.tick, 120);
+        // the note on channel 1 is only struck at tick 150
+        assert!(!snapshot.channels.contains_key(&1));
+        let held = &snapshot.channels[&0].notes;
+        assert_eq!(held.len(), 1);
+        assert_eq!(
+            (held[0].note, held[0].velocity, held[0].tick),
+            (60, 100, 100)
+        );
+    }
+
+    #[test]
+    fn running_status_keeps_the_channel() {
+        #[rustfmt::skip]
+        let track: &[u8] = &[
+            0x00, 0x90, 60, 100,
+            0x00, 64, 100,              // running note on, channel 0
+            0x60, 60, 0,                // running note on with velocity 0
+            0x00, 64, 0,
+            0x00, 0x93, 62, 90,         // channel 3
+            0x00, 65, 90,
+            0x60, 0x83, 62, 64,         // note off, channel 3
+            0x00, 65, 64,
+            0x00, 0xB3, 7, 100,         // controllers, channel 3
+            0x00, 10, 64,
+            0x00, 0xE3, 0x00, 0x40,     // pitch bend, channel 3
+            0x10, 0x00, 0x41,
+            0x00, 0x9F, 36, 127,        // back to a note on, channel 15
+            0x60, 36, 0,
+            0x00, 0xC1, 5,              // program change, channel 1
+            0x00, 6,
+            0x00, 0xFF, 0x2F, 0x00,
+        ];
+        let bytes = file(0, &[track]);
+        let smf = Smf::parse(&bytes).unwrap();
+
+        let events = play(&smf, PlayerOptions::default());
+        assert_eq!(channels(&events), vec![
+            0, 0, 0, 0, 3, 3, 3, 3, 3, 3, 3, 3, 15, 15, 1, 1
+        ]);
+    }
+
+    #[test]
+    fn running_status_across_tracks() {
+        // every track starts without a running status of its own
+        #[rustfmt::skip]
+        let first: &[u8] = &[
+            0x00, 0x92, 60, 100,
+            0x60, 60, 0,
+            0x00, 0xFF, 0x2F, 0x00,
+        ];
+        #[rustfmt::skip]
+        let second: &[u8] = &[
+            0x30, 0x95, 48, 100,
+            0x60, 48, 0,
+            0x00, 0xFF, 0x2F, 0x00,
+        ];
+        let bytes = file(1, &[first, second]);
+        let smf = Smf::parse(&bytes).unwrap();
+
+        let events = play(&smf, PlayerOptions::default());
+        assert_eq!(channels(&events), vec![2, 5, 2, 5]);
+    }
+
+    fn controller(delta: u32, channel: u8, ctrl: u8, value: u8) -> TrackEvent<'static> {
+        midi(delta, channel, MidiMessage::Controller {
+            controller: ctrl.into(),
+            value:      value.into(),
+        })
+    }
+
+    fn ticks(events: &[model::Event]) -> Vec<u64> {
+        events.iter().map(|event| event.time().tick).collect()
+    }
+
+    #[test]
+    fn thin_cc_keeps_first_and_last() {
+        // a tick is about 1ms, a sweep of 20 values one tick apart
+        let mut track = (0..20)
+            .map(|value| controller((value > 0) as u32, 0, 7, value))
+            .collect::<Vec<_>>();
+        track.push(end(0));
+        let smf = smf(Format::SingleTrack, vec![track]);
+
+        let events = play(&smf, PlayerOptions {
+            thin_cc: Some(5.0),
+            ..Default::default()
+        });
+        assert_eq!(ticks(&events), vec![0, 5, 10, 15, 19]);
+    }
+
+    #[test]
+    fn thin_aftertouch_by_note() {
+        let aftertouch = |delta: u32, key: u8| {
+            midi(delta, 0, MidiMessage::Aftertouch {
+                key: key.into(),
+                vel: 50.into(),
+            })
+        };
+        let mut track = (0..10)
+            .flat_map(|tick| [aftertouch((tick > 0) as u32, 60), aftertouch(0, 64)])
+            .collect::<Vec<_>>();
+        track.push(end(0));
+        let smf = smf(Format::SingleTrack, vec![track]);
+
+        let events = play(&smf, PlayerOptions {
+            thin_aftertouch: Some(5.0),
+            ..Default::default()
+        });
+        // every note is thinned on its own
+        assert_eq!(ticks(&events), vec![0, 0, 5, 5]);
+    }
+
+    fn micros(events: &[model::Event]) -> Vec<u64> {
+        events
+            .iter()
+            .map(|event| event.time().micros.unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn ppqn_override() {
+        let smf = smf(Format::SingleTrack, vec![vec![
+            note_on(0, 0, 60, 100),
+            note_off(480, 0, 60),
+            end(0),
+        ]]);
+
+        let events = play(&smf, PlayerOptions::default());
+        assert_eq!(micros(&events), vec![0, 500_000]);
+
+        let events = play(&smf, PlayerOptions {
+            ppqn: Some(960),
+            ..Default::default()
+        });
+        assert_eq!(ticks(&events), vec![0, 480]);
+        assert_eq!(micros(&events), vec![0, 250_000]);
+    }
+
+    #[test]
+    fn ppqn_override_needs_metrical_timing() {
+        let smf = Smf {
+            header: Header::new(Format::SingleTrack, Timing::Timecode(midly::Fps::Fps25, 40)),
+            tracks: vec![vec![end(0)]],
+        };
+        let options = PlayerOptions {
+            ppqn: Some(960),
+            ..Default::default()
+        };
+        assert!(MidiPlayer::new(&smf, options).is_err());
+
+        let smf = self::smf(Format::SingleTrack, vec![vec![end(0)]]);
+        let options = PlayerOptions {
+            ppqn: Some(0),
+            ..Default::default()
+        };
+        assert!(MidiPlayer::new(&smf, options).is_err());
+    }
+
+    #[test]
+    fn drop_note_off_keeps_timing() {
+        let smf = smf(Format::SingleTrack, vec![vec![
+            note_on(0, 0, 60, 100),
+            note_off(100, 0, 60),
+            note_on(50, 0, 62, 100),
+            note_on(100, 0, 62, 0),
+            controller(50, 0, 7, 100),
+            end(0),
+        ]]);
+
+        let events = play(&smf, PlayerOptions {
+            drop_note_off: true,
+            ..Default::default()
+        });
+        assert_eq!(ticks(&events), vec![0, 150, 300]);
+
+        let events = play(&smf, PlayerOptions {
+            drop_note_off: true,
+            delta_times: true,
+            ..Default::default()
+        });
+        assert_eq!(ticks(&events), vec![0, 150, 150]);
+    }
+
+    #[test]
+    fn rounding_does_not_accumulate() {
+        // a tick is 1041.67 micros, an error of a third of a micro per event
+        // would add up to over 3000 micros by the end
+        let mut track = (0..10_000)
+            .map(|i| controller(1, 0, 7, (i % 128) as u8))
+            .collect::<Vec<_>>();
+        track.push(end(0));
+        let smf = smf(Format::SingleTrack, vec![track]);
+
+        for (rounding, offset) in [
+            (Rounding::Floor, 0),
+            (Rounding::Round, 240),
+            (Rounding::Ceil, 479),
+        ] {
+            let events = play(&smf, PlayerOptions {
+                rounding,
+                ..Default::default()
+            });
+            assert_eq!(events.len(), 10_000);
+            for event in events.iter() {
+                let exact = event.time().tick * 500_000;
+                assert_eq!(event.time().micros, Some((exact + offset) / 480));
+            }
+        }
+    }
+
+    /// Tick, type and note of the emitted note events
+    fn notes(events: &[model::Event]) -> Vec<(u64, &'static str, u8)> {
+        events
+            .iter()
+            .filter_map(|event| match event {
+                model::Event::Midi {
+                    time,
+                    data: MidiEvent::NoteOn { note, .. },
+                    ..
+                } => Some((time.tick, "on", *note)),
+                model::Event::Midi {
+                    time,
+                    data: MidiEvent::NoteOff { note, .. },
+                    ..
+                } => Some((time.tick, "off", *note)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn velocity_range_drops_note_offs() {
+        let smf = smf(Format::SingleTrack, vec![vec![
+            note_on(0, 0, 60, 5),
+            note_on(0, 0, 62, 100),
+            note_off(100, 0, 60),
+            note_off(0, 0, 62),
+            // a loud note on the key of the dropped one
+            note_on(100, 0, 60, 100),
+            note_on(100, 0, 60, 0),
+            // too loud
+            note_on(0, 0, 64, 127),
+            note_off(100, 0, 64),
+            end(0),
+        ]]);
+
+        let events = play(&smf, PlayerOptions {
+            velocity_range: Some(10..=120),
+            ..Default::default()
+        });
+        assert_eq!(notes(&events), vec![
+            (0, "on", 62),
+            (100, "off", 62),
+            (200, "on", 60),
+            (300, "on", 60),
+        ]);
+    }
+
+    /// Tick, channel, controller and value of the emitted controller events
+    fn controllers(events: &[model::Event]) -> Vec<(u64, u8, u8, u8)> {
+        events
+            .iter()
+            .filter_map(|event| match event {
+                model::Event::Midi {
+                    time,
+                    data: MidiEvent::Controller { chan, ctrl, value },
+                    ..
+                } => Some((time.tick, *chan, *ctrl, *value)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn merge_adjacent_cc() {
+        let smf = smf(Format::SingleTrack, vec![vec![
+            controller(0, 0, 7, 100),
+            controller(10, 0, 7, 100),
+            controller(10, 0, 7, 100),
+            // other controllers and channels do not break the run
+            controller(0, 0, 10, 100),
+            controller(0, 1, 7, 100),
+            controller(10, 0, 7, 100),
+            controller(10, 0, 7, 90),
+            controller(10, 0, 7, 100),
+            note_on(10, 0, 60, 100),
+            end(0),
+        ]]);
+
+        let events = play(&smf, PlayerOptions {
+            merge_adjacent_cc: true,
+            ..Default::default()
+        });
+        assert_eq!(controllers(&events), vec![
+            (0, 0, 7, 100),
+            (20, 0, 10, 100),
+            (20, 1, 7, 100),
+            (40, 0, 7, 90),
+            (50, 0, 7, 100),
+        ]);
+        assert_eq!(notes(&events), vec![(60, "on", 60)]);
+    }
+
+    fn tempo(delta: u32, mpqn: u32) -> TrackEvent<'static> {
+        TrackEvent {
+            delta: delta.into(),
+            kind:  TrackEventKind::Meta(midly::MetaMessage::Tempo(mpqn.into())),
+        }
+    }
+
+    /// Tick and value of the emitted tempo events
+    fn tempos(events: &[model::Event]) -> Vec<(u64, u32)> {
+        events
+            .iter()
+            .filter_map(|event| match event {
+                model::Event::Meta {
+                    time,
+                    data: MetaEvent::Tempo(mpqn),
+                    ..
+                } => Some((time.tick, *mpqn)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn dedupe_tempo() {
+        let smf = smf(Format::SingleTrack, vec![vec![
+            tempo(0, 500_000),
+            tempo(100, 500_000),
+            tempo(100, 400_000),
+            tempo(100, 400_000),
+            tempo(100, 500_000),
+            note_on(100, 0, 60, 100),
+            end(0),
+        ]]);
+
+        let events = play(&smf, PlayerOptions {
+            emit_meta: true,
+            dedupe_tempo: true,
+            ..Default::default()
+        });
+        assert_eq!(tempos(&events), vec![
+            (0, 500_000),
+            (200, 400_000),
+            (400, 500_000)
+        ]);
+        // 300 ticks at 120 bpm and 200 at 150 bpm, as without deduping
+        assert_eq!(notes(&events), vec![(500, "on", 60)]);
+        assert_eq!(events[3].time().micros, Some(479_166));
+
+        let events = play(&smf, PlayerOptions {
+            emit_meta: true,
+            ..Default::default()
+        });
+        assert_eq!(tempos(&events).len(), 5);
+        assert_eq!(events[5].time().micros, Some(479_166));
+    }
+
+    #[test]
+    fn trim_trailing_silence() {
+        let smf = smf(Format::Parallel, vec![
+            vec![tempo(0, 500_000), end(50)],
+            vec![note_on(0, 0, 60, 100), note_off(100, 0, 60), end(900)],
+            vec![end(1000)],
+        ]);
+        let play = |trim_trailing_silence| {
+            let mut player = MidiPlayer::new(&smf, PlayerOptions {
+                emit_meta: true,
+                trim_trailing_silence,
+                ..Default::default()
+            })
+            .unwrap();
+            let mut ticks = Vec::new();
+            while let Some(result) = player.next_event() {
+                if let PlayerResult::Event(event) = result {
+                    ticks.push(event.time().tick);
+                }
+            }
+            (ticks, player.elapsed().tick)
+        };
+
+        assert_eq!(play(false), (vec![0, 0, 50, 100, 1000, 1000], 1000));
+        // the end of the conductor track comes before the last note
+        assert_eq!(play(true), (vec![0, 0, 50, 100, 100, 100], 100));
+    }
+
+    #[test]
+    fn quarters() {
+        let track = vec![
+            note_on(0, 0, 60, 100),
+            note_off(960, 0, 60),
+            note_on(240, 0, 62, 100),
+            end(0),
+        ];
+        let options = || PlayerOptions {
+            quarters: true,
+            ..Default::default()
+        };
+
+        let smf = smf(Format::SingleTrack, vec![track.clone()]);
+        let quarters = play(&smf, options())
+            .iter()
+            .map(|event| event.time().abs_quarters)
+            .collect::<Vec<_>>();
+        assert_eq!(quarters, vec![
+            Some(Some(0.0)),
+            Some(Some(2.0)),
+            Some(Some(2.5))
+        ]);
+
+        // no quarter notes in SMPTE timed files
+        let smf = Smf {
+            header: Header::new(Format::SingleTrack, Timing::Timecode(midly::Fps::Fps25, 40)),
+            tracks: vec![track],
+        };
+        let quarters = play(&smf, options())
+            .iter()
+            .map(|event| event.time().abs_quarters)
+            .collect::<Vec<_>>();
+        assert_eq!(quarters, vec![Some(None); 3]);
+    }
+
+    #[test]
+    fn note_match_policies() {
+        // the same key struck twice before it is released
+        let smf = smf(Format::SingleTrack, vec![vec![
+            note_on(0, 0, 60, 100),
+            note_on(100, 0, 60, 90),
+            note_off(100, 0, 60),
+            note_on(100, 0, 60, 0),
+            // a note off without a note playing
+            note_off(100, 0, 60),
+            end(0),
+        ]]);
+        let linked = |note_match| {
+            play(&smf, PlayerOptions {
+                link_note_pairs: true,
+                note_match,
+                ..Default::default()
+            })
+            .into_iter()
+            .filter_map(|event| match event {
+                model::Event::Midi {
+                    data:
+                        MidiEvent::NoteOff { note_on_index, .. }
+                        | MidiEvent::NoteOn {
+                            velocity: 0,
+                            note_on_index,
+                            ..
+                        },
+                    ..
+                } => note_on_index,
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+        };
+
+        assert_eq!(linked(NoteMatch::Fifo), vec![Some(0), Some(1), None]);
+        assert_eq!(linked(NoteMatch::Lifo), vec![Some(1), Some(0), None]);
+    }
+
+    #[test]
+    fn extreme_deltas() {
+        // the largest delta a file can hold, at the slowest tempo
+        let smf = smf(Format::SingleTrack, vec![vec![
+            tempo(0, 0xFF_FFFF),
+            note_on(0x0FFF_FFFF, 0, 60, 100),
+            note_off(0x0FFF_FFFF, 0, 60),
+            end(0),
+        ]]);
+        let events = play(&smf, PlayerOptions {
+            delta_times: true,
+            ..Default::default()
+        });
+        // 2^28 - 1 ticks of 34952.53 micros, more than 100 days
+        assert_eq!(micros(&events), vec![9_382_498_629_495; 2]);
+
+        for timing in [
+            Timing::Metrical(0.into()),
+            Timing::Timecode(midly::Fps::Fps25, 0),
+        ] {
+            let smf = Smf {
+                header: Header::new(Format::SingleTrack, timing),
+                tracks: vec![vec![note_on(10, 0, 60, 100), end(0)]],
+            };
+            let error = MidiPlayer::new(&smf, PlayerOptions::default())
+                .map(|_| ())
+                .unwrap_err();
+            assert!(error.to_string().contains("division"), "{}", error);
+
+            // ticks only
+            let events = play(&smf, PlayerOptions {
+                no_time: true,
+                ..Default::default()
+            });
+            assert_eq!(ticks(&events), vec![10]);
+        }
+    }
+
+    #[test]
+    fn notes_filter() {
+        let naming = NoteNaming {
+            middle_c: crate::notes::MiddleC::C3,
+            flats:    false,
+        };
+        let (kick, snare) = (naming.parse("C1").unwrap(), naming.parse("D1").unwrap());
+        assert_eq!((kick, snare), (36, 38));
+
+        let hihat = 42;
+        let smf = smf(Format::SingleTrack, vec![vec![
+            note_on(0, 9, kick, 100),
+            note_on(0, 9, hihat, 80),
+            note_off(60, 9, kick),
+            note_off(0, 9, hihat),
+            note_on(60, 9, hihat, 80),
+            controller(0, 9, 7, 100),
+            note_off(60, 9, hihat),
+            note_on(60, 9, snare, 110),
+            note_off(60, 9, snare),
+            end(0),
+        ]]);
+        let options = |notes_only| PlayerOptions {
+            notes: Some(vec![kick, snare]),
+            notes_only,
+            ..Default::default()
+        };
+
+        let events = play(&smf, options(false));
+        assert_eq!(notes(&events), vec![
+            (0, "on", kick),
+            (60, "off", kick),
+            (240, "on", snare),
+            (300, "off", snare),
+        ]);
+        assert_eq!(controllers(&events), vec![(120, 9, 7, 100)]);
+
+        let events = play(&smf, options(true));
+        assert_eq!(events.len(), 4);
+        assert!(controllers(&events).is_empty());
+    }
+
+    #[test]
+    fn size_hint_of_a_parsed_file() {
+        let mut bytes = Vec::new();
+        smf(Format::Parallel, vec![
+            vec![note_on(0, 0, 60, 100), note_off(480, 0, 60), end(0)],
+            vec![note_on(240, 1, 62, 100), note_off(480, 1, 62), end(0)],
+        ])
+        .write_std(&mut bytes)
+        .unwrap();
+        let smf = Smf::parse(&bytes).unwrap();
+
+        let player = MidiPlayer::new(&smf, PlayerOptions::default())
+            .unwrap()
+            .into_iter();
+        assert_eq!(player.size_hint(), (6, Some(6)));
+        assert_eq!(player.count(), 6);
     }
 }
@@ -0,0 +1,165 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+use crate::notes::NoteMatch;
+
+/// The state of playback at a tick, everything needed to start playing from
+/// there: the notes held, the program and controllers of every channel and
+/// the tempo and time signature in effect. Events on the tick itself are
+/// included
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Snapshot {
+    pub tick:           u64,
+    /// Microseconds per quarter note, `null` for SMPTE timed files
+    pub tempo:          Option<u32>,
+    /// `null` before the first time signature, which means 4/4
+    pub time_signature: Option<TimeSignature>,
+    /// Channels with any state, by channel
+    pub channels:       BTreeMap<u8, ChannelState>,
+}
+
+#[derive(Debug, Clone, Copy, serde::Serialize)]
+pub struct TimeSignature {
+    pub numerator:   u8,
+    /// The note value of a beat, 4 for quarter notes
+    pub denominator: u32,
+}
+
+#[derive(Debug, Clone, Default, serde::Serialize)]
+pub struct ChannelState {
+    /// The notes held down, ordered by the tick they were struck on
+    pub notes:       Vec<HeldNote>,
+    /// `null` if there was no program change yet
+    pub program:     Option<u8>,
+    /// The last value of every controller that was set, by controller number.
+    /// Notes held only by the sustain pedal are not in `notes`, the pedal is
+    /// controller 64
+    pub controllers: BTreeMap<u8, u8>,
+    /// `null` if the pitch wheel was not moved yet, 8192 is the center
+    pub pitch_bend:  Option<u16>,
+}
+
+#[derive(Debug, Clone, Copy, serde::Serialize)]
+pub struct HeldNote {
+    pub note:     u8,
+    /// The velocity in the file, before any velocity curve
+    pub velocity: u8,
+    /// The tick the note was struck on
+    pub tick:     u64,
+}
+
+/// Follows the events of a file to take a [`Snapshot`] at a tick
+#[derive(Debug)]
+pub struct SnapshotRecorder {
+    at:             u64,
+    note_match:     NoteMatch,
+    time_signature: Option<TimeSignature>,
+    channels:       BTreeMap<u8, ChannelState>,
+    /// Notes held by channel and key, the oldest first
+    held:           HashMap<(u8, u8), VecDeque<HeldNote>>,
+    taken:          Option<Snapshot>,
+}
+
+impl SnapshotRecorder {
+    pub fn new(at: u64, note_match: NoteMatch) -> Self {
+        Self {
+            at,
+            note_match,
+            time_signature: None,
+            channels: BTreeMap::new(),
+            held: HashMap::new(),
+            taken: None,
+        }
+    }
+
+    /// Follow an event played at `tick`. The first event after the snapshot
+    /// tick takes the snapshot, with `tempo` as the tempo in effect before it
+    pub fn play(&mut self, tick: u64, kind: &midly::TrackEventKind, tempo: Option<u32>) {
+        if self.taken.is_some() {
+            return;
+        }
+        if tick > self.at {
+            self.taken = Some(self.snapshot(tempo));
+            return;
+        }
+
+        match *kind {
+            midly::TrackEventKind::Midi { channel, message } => {
+                self.play_midi(tick, channel.as_int(), message)
+            },
+            midly::TrackEventKind::Meta(midly::MetaMessage::TimeSignature(
+                numerator,
+                denominator,
+                ..,
+            )) => {
+                self.time_signature = Some(TimeSignature {
+                    numerator,
+                    denominator: 1u32.checked_shl(denominator as u32).unwrap_or(0),
+                })
+            },
+            _ => {},
+        }
+    }
+
+    fn play_midi(&mut self, tick: u64, channel: u8, message: midly::MidiMessage) {
+        let state = self.channels.entry(channel).or_default();
+        match message {
+            midly::MidiMessage::NoteOn { key, vel } if vel > 0 => self
+                .held
+                .entry((channel, key.as_int()))
+                .or_default()
+                .push_back(HeldNote {
+                    note: key.as_int(),
+                    velocity: vel.as_int(),
+                    tick,
+                }),
+            midly::MidiMessage::NoteOn { key, .. } | midly::MidiMessage::NoteOff { key, .. } => {
+                if let Some(held) = self.held.get_mut(&(channel, key.as_int())) {
+                    self.note_match.take(held);
+                }
+            },
+            midly::MidiMessage::Controller { controller, value } => {
+                state
+                    .controllers
+                    .insert(controller.as_int(), value.as_int());
+            },
+            midly::MidiMessage::ProgramChange { program } => state.program = Some(program.as_int()),
+            midly::MidiMessage::PitchBend {
+                bend: midly::PitchBend(bend),
+            } => state.pitch_bend = Some(bend.as_int()),
+            midly::MidiMessage::Aftertouch { .. }
+            | midly::MidiMessage::ChannelAftertouch { .. } => {},
+        }
+    }
+
+    /// The snapshot, taken from the current state if no event after the
+    /// snapshot tick was played, as the file ended before it
+    pub fn snapshot(&self, tempo: Option<u32>) -> Snapshot {
+        if let Some(taken) = self.taken.as_ref() {
+            return taken.clone();
+        }
+
+        let mut channels = self.channels.clone();
+        let mut held = self
+            .held
+            .iter()
+            .flat_map(|(&(channel, _), notes)| notes.iter().map(move |note| (channel, *note)))
+            .collect::<Vec<_>>();
+        held.sort_by_key(|(channel, note)| (*channel, note.tick, note.note));
+        for (channel, note) in held {
+            channels.entry(channel).or_default().notes.push(note);
+        }
+        channels.retain(|_, state| {
+            !state.notes.is_empty()
+                || state.program.is_some()
+                || !state.controllers.is_empty()
+                || state.pitch_bend.is_some()
+        });
+
+        Snapshot {
+            tick: self.at,
+            tempo,
+            time_signature: self.time_signature,
+            channels,
+        }
+    }
+}
@@ -0,0 +1,330 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+use crate::{
+    gm::{self, ProgramMap, DRUM_CHANNEL},
+    key::{self, DetectedKey},
+    model::{Event, MidiEvent, TimeInfo, MICROS_PER_SECOND},
+    notes::NoteMatch,
+};
+
+/// Aggregated information about a file, written instead of its events
+#[derive(Debug, serde::Serialize)]
+pub struct Summary {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub generated:        Option<String>,
+    pub source_file:      String,
+    /// See [`Track::source_sha256`](crate::model::Track::source_sha256)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source_sha256:    Option<String>,
+    pub events_processed: usize,
+    pub events_emitted:   usize,
+    pub duration:         TimeInfo,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub channel_activity: Option<Vec<ChannelActivity>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub channel_stats:    Option<Vec<ChannelStats>>,
+    /// `null` if no key could be detected
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detected_key:     Option<Option<DetectedKey>>,
+    /// Sum of velocity times length in seconds over all notes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total_energy:     Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub channel_energy:   Option<Vec<ChannelEnergy>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub program_map:      Option<ProgramMap>,
+}
+
+/// The region of a channel from its first to its last note on
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct ChannelActivity {
+    pub channel:    u8,
+    pub first_tick: u64,
+    pub last_tick:  u64,
+    pub note_count: usize,
+}
+
+/// Note range, polyphony and velocity of the notes of a single channel
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct ChannelStats {
+    pub channel:          u8,
+    pub note_count:       usize,
+    pub low_note:         u8,
+    pub high_note:        u8,
+    /// Most notes playing at the same time
+    pub max_polyphony:    u32,
+    pub average_velocity: f64,
+}
+
+/// A [`ChannelStats`] being collected
+#[derive(Debug, Clone)]
+struct StatsBuilder {
+    stats:        ChannelStats,
+    velocity_sum: u64,
+    /// Number of notes currently playing
+    playing:      u32,
+}
+
+/// The energy of the notes of a single channel
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct ChannelEnergy {
+    pub channel: u8,
+    pub energy:  f64,
+}
+
+/// Which aggregations a [`SummaryBuilder`] collects
+#[derive(Debug, Default, Clone)]
+pub struct SummaryOptions {
+    pub channel_activity: bool,
+    /// Collect the note range, polyphony and velocity of every channel
+    pub channel_stats:    bool,
+    /// Guess the key from the pitch classes, weighted by duration
+    pub detect_key:       bool,
+    /// Sum up velocity times length of the notes
+    pub energy:           bool,
+    /// Collect the instrument of every channel
+    pub program_map:      bool,
+    /// Which note a note off ends for the energy
+    pub note_match:       NoteMatch,
+}
+
+/// Collects the aggregations of a [`Summary`] while events are played
+#[derive(Debug, Default)]
+pub struct SummaryBuilder {
+    channel_activity: Option<BTreeMap<u8, ChannelActivity>>,
+    channel_stats:    Option<BTreeMap<u8, StatsBuilder>>,
+    /// Number of notes currently playing by channel and note, for the channel
+    /// stats. Note offs without a playing note do not lower the polyphony
+    sounding:         HashMap<(u8, u8), u32>,
+    /// Total length of every pitch class in ticks. Note ons subtract their
+    /// tick and note offs add theirs, so notes do not have to be matched up
+    pitch_classes:    Option<[i64; 12]>,
+    /// Number of notes currently playing, by channel and note
+    open_notes:       HashMap<(u8, u8), u32>,
+    /// Energy by channel, if collected
+    energy:           Option<BTreeMap<u8, f64>>,
+    /// Velocity and start in micros of the notes currently playing, oldest
+    /// first
+    playing:          HashMap<(u8, u8), VecDeque<(u8, f64)>>,
+    note_match:       NoteMatch,
+    program_map:      Option<ProgramMap>,
+}
+
+impl SummaryBuilder {
+    pub fn new(options: SummaryOptions) -> Self {
+        Self {
+            channel_activity: options.channel_activity.then(BTreeMap::new),
+            channel_stats:    options.channel_stats.then(BTreeMap::new),
+            sounding:         HashMap::new(),
+            pitch_classes:    options.detect_key.then_some([0; 12]),
+            open_notes:       HashMap::new(),
+            energy:           options.energy.then(BTreeMap::new),
+            playing:          HashMap::new(),
+            note_match:       options.note_match,
+            program_map:      options.program_map.then(ProgramMap::new),
+        }
+    }
+
+    /// Account for an emitted event at the absolute tick `tick` and time
+    /// `micros`
+    pub fn add(&mut self, event: &Event, tick: u64, micros: f64) {
+        if let Some(programs) = self.program_map.as_mut() {
+            gm::add_to_program_map(programs, event);
+        }
+
+        let Event::Midi { data, .. } = event else {
+            return;
+        };
+
+        if let (
+            Some(activity),
+            MidiEvent::NoteOn {
+                chan,
+                velocity: 1..,
+                ..
+            },
+        ) = (self.channel_activity.as_mut(), data)
+        {
+            let entry = activity.entry(*chan).or_insert(ChannelActivity {
+                channel:    *chan,
+                first_tick: tick,
+                last_tick:  tick,
+                note_count: 0,
+            });
+            entry.last_tick = tick;
+            entry.note_count += 1;
+        }
+
+        if let Some(channels) = self.channel_stats.as_mut() {
+            match *data {
+                MidiEvent::NoteOn {
+                    chan,
+                    note,
+                    velocity: velocity @ 1..,
+                    ..
+                } => {
+                    let entry = channels.entry(chan).or_insert(StatsBuilder {
+                        stats:        ChannelStats {
+                            channel:          chan,
+                            note_count:       0,
+                            low_note:         note,
+                            high_note:        note,
+                            max_polyphony:    0,
+                            average_velocity: 0.0,
+                        },
+                        velocity_sum: 0,
+                        playing:      0,
+                    });
+                    entry.stats.note_count += 1;
+                    entry.stats.low_note = entry.stats.low_note.min(note);
+                    entry.stats.high_note = entry.stats.high_note.max(note);
+                    entry.velocity_sum += velocity as u64;
+                    entry.playing += 1;
+                    entry.stats.max_polyphony = entry.stats.max_polyphony.max(entry.playing);
+                    *self.sounding.entry((chan, note)).or_default() += 1;
+                },
+                MidiEvent::NoteOn { chan, note, .. } | MidiEvent::NoteOff { chan, note, .. } => {
+                    if let Some(open) = self.sounding.get_mut(&(chan, note)).filter(|n| **n > 0) {
+                        *open -= 1;
+                        if let Some(entry) = channels.get_mut(&chan) {
+                            entry.playing -= 1;
+                        }
+                    }
+                },
+                _ => {},
+            }
+        }
+
+        if let Some(pitch_classes) = self.pitch_classes.as_mut() {
+            match *data {
+                MidiEvent::NoteOn {
+                    chan,
+                    note,
+                    velocity: 1..,
+                    ..
+                } if chan != DRUM_CHANNEL => {
+                    pitch_classes[(note % 12) as usize] -= tick as i64;
+                    *self.open_notes.entry((chan, note)).or_default() += 1;
+                },
+                MidiEvent::NoteOn { chan, note, .. } | MidiEvent::NoteOff { chan, note, .. } => {
+                    if let Some(open) = self.open_notes.get_mut(&(chan, note)).filter(|n| **n > 0) {
+                        *open -= 1;
+                        pitch_classes[(note % 12) as usize] += tick as i64;
+                    }
+                },
+                _ => {},
+            }
+        }
+
+        if let Some(energy) = self.energy.as_mut() {
+            match *data {
+                MidiEvent::NoteOn {
+                    chan,
+                    note,
+                    velocity: velocity @ 1..,
+                    ..
+                } => {
+                    energy.entry(chan).or_default();
+                    self.playing
+                        .entry((chan, note))
+                        .or_default()
+                        .push_back((velocity, micros));
+                },
+                MidiEvent::NoteOn { chan, note, .. } | MidiEvent::NoteOff { chan, note, .. } => {
+                    if let Some((velocity, start)) = self
+                        .playing
+                        .get_mut(&(chan, note))
+                        .and_then(|playing| self.note_match.take(playing))
+                    {
+                        *energy.entry(chan).or_default() += note_energy(velocity, start, micros);
+                    }
+                },
+                _ => {},
+            }
+        }
+    }
+
+    /// Assemble the summary of a file played to completion. `end_micros` is
+    /// the absolute time the file ends at, notes still playing are ended there
+    pub fn build(
+        &self,
+        generated: Option<String>,
+        source_file: String,
+        events_processed: usize,
+        events_emitted: usize,
+        duration: TimeInfo,
+        end_micros: f64,
+    ) -> Summary {
+        let channel_energy = self.channel_energy(end_micros);
+        Summary {
+            generated,
+            source_file,
+            source_sha256: None,
+            events_processed,
+            events_emitted,
+            channel_activity: self.channel_activity(),
+            channel_stats: self.channel_stats(),
+            detected_key: self.detected_key(duration.tick),
+            total_energy: channel_energy
+                .as_ref()
+                .map(|channels| channels.iter().map(|c| c.energy).sum()),
+            channel_energy,
+            program_map: self.program_map.clone(),
+            duration,
+        }
+    }
+
+    /// Per channel activity ordered by channel, if it was collected
+    pub fn channel_activity(&self) -> Option<Vec<ChannelActivity>> {
+        self.channel_activity
+            .as_ref()
+            .map(|activity| activity.values().cloned().collect())
+    }
+
+    /// Per channel note statistics ordered by channel, if they were collected
+    pub fn channel_stats(&self) -> Option<Vec<ChannelStats>> {
+        self.channel_stats.as_ref().map(|channels| {
+            channels
+                .values()
+                .map(|entry| ChannelStats {
+                    average_velocity: entry.velocity_sum as f64 / entry.stats.note_count as f64,
+                    ..entry.stats.clone()
+                })
+                .collect()
+        })
+    }
+
+    /// The most likely key, if key detection is enabled. Notes still playing
+    /// are ended at `end_tick`
+    pub fn detected_key(&self, end_tick: u64) -> Option<Option<DetectedKey>> {
+        let mut pitch_classes = self.pitch_classes?;
+        for (&(_, note), &open) in self.open_notes.iter() {
+            pitch_classes[(note % 12) as usize] += open as i64 * end_tick as i64;
+        }
+
+        Some(key::detect_key(&pitch_classes.map(|ticks| ticks as f64)))
+    }
+
+    /// Energy by channel ordered by channel, if it was collected. Notes still
+    /// playing are ended at `end_micros`
+    pub fn channel_energy(&self, end_micros: f64) -> Option<Vec<ChannelEnergy>> {
+        let mut energy = self.energy.clone()?;
+        for (&(chan, _), notes) in self.playing.iter() {
+            for &(velocity, start) in notes {
+                *energy.entry(chan).or_default() += note_energy(velocity, start, end_micros);
+            }
+        }
+
+        Some(
+            energy
+                .into_iter()
+                .map(|(channel, energy)| ChannelEnergy { channel, energy })
+                .collect(),
+        )
+    }
+}
+
+/// Velocity times length in seconds
+fn note_energy(velocity: u8, start: f64, end: f64) -> f64 {
+    velocity as f64 * (end - start) / MICROS_PER_SECOND as f64
+}
@@ -0,0 +1,80 @@
+use base64::Engine;
+use std::str::FromStr;
+
+use crate::model;
+
+/// How SysEx payloads are written to the output
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SysexEncoding {
+    #[default]
+    Hex,
+    Base64,
+}
+
+impl SysexEncoding {
+    pub fn encode(self, data: &[u8]) -> String {
+        match self {
+            SysexEncoding::Hex => model::to_hex(data),
+            SysexEncoding::Base64 => base64::engine::general_purpose::STANDARD.encode(data),
+        }
+    }
+}
+
+impl FromStr for SysexEncoding {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hex" => Ok(SysexEncoding::Hex),
+            "base64" => Ok(SysexEncoding::Base64),
+            _ => anyhow::bail!("unknown sysex encoding {:?} (expected hex or base64)", s),
+        }
+    }
+}
+
+/// Name the manufacturer of a SysEx message from its leading id bytes.
+///
+/// Ids missing from the table are returned as hex.
+pub fn manufacturer(data: &[u8]) -> String {
+    let name = match data {
+        [0x00, a, b, ..] => match (a, b) {
+            (0x00, 0x0E) => Some("Alesis"),
+            (0x00, 0x66) => Some("Mackie"),
+            (0x01, 0x05) => Some("M-Audio"),
+            (0x20, 0x29) => Some("Focusrite/Novation"),
+            (0x20, 0x32) => Some("Behringer"),
+            (0x20, 0x33) => Some("Access"),
+            (0x20, 0x3C) => Some("Elektron"),
+            (0x20, 0x6B) => Some("Arturia"),
+            (0x21, 0x09) => Some("Native Instruments"),
+            _ => None,
+        },
+        [id, ..] => match id {
+            0x01 => Some("Sequential Circuits"),
+            0x04 => Some("Moog"),
+            0x06 => Some("Lexicon"),
+            0x07 => Some("Kurzweil"),
+            0x0F => Some("Ensoniq"),
+            0x10 => Some("Oberheim"),
+            0x18 => Some("E-mu"),
+            0x40 => Some("Kawai"),
+            0x41 => Some("Roland"),
+            0x42 => Some("Korg"),
+            0x43 => Some("Yamaha"),
+            0x44 => Some("Casio"),
+            0x47 => Some("Akai"),
+            0x7D => Some("Non-Commercial"),
+            0x7E => Some("Universal Non-Realtime"),
+            0x7F => Some("Universal Realtime"),
+            _ => None,
+        },
+        [] => return String::from("none"),
+    };
+
+    match (name, data) {
+        (Some(name), _) => String::from(name),
+        (None, [0x00, a, b, ..]) => format!("0x00{:02x}{:02x}", a, b),
+        (None, [id, ..]) => format!("0x{:02x}", id),
+        (None, []) => unreachable!(),
+    }
+}
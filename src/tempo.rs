@@ -0,0 +1,264 @@
+use anyhow::{ensure, Context};
+use midly::{MetaMessage, Smf, TrackEvent, TrackEventKind};
+use std::{collections::VecDeque, fs, path::Path};
+
+use crate::{
+    beats::BeatGrid,
+    model::{TimingData, MICROS_PER_SECOND},
+    trackmode::{TieBreak, TrackMode},
+};
+
+/// Random access tick to time conversion.
+///
+/// Unlike [`PlayerTimingInfo`](crate::model::PlayerTimingInfo), which only
+/// moves forward while events are played, the tempo map knows about all tempo
+/// changes of a file up front and can convert any tick.
+#[derive(Debug, Clone)]
+pub struct TempoMap {
+    initial: TimingData,
+    changes: Vec<TempoChange>,
+}
+
+/// Beats per minute of a tempo in microseconds per quarter note. A tempo of 0
+/// is treated as 1
+pub fn bpm(mpqn: u32) -> f64 { 60.0 * MICROS_PER_SECOND as f64 / mpqn.max(1) as f64 }
+
+/// Guess the conductor track: the track with the most tempo changes, the
+/// first one on a tie. `None` if the file has no tempo changes at all
+pub fn detect_tempo_track<'smf, I>(tracks: impl IntoIterator<Item = I>) -> Option<usize>
+where
+    I: IntoIterator<Item = TrackEvent<'smf>>,
+{
+    tracks
+        .into_iter()
+        .map(|track| {
+            track
+                .into_iter()
+                .filter(|event| matches!(event.kind, TrackEventKind::Meta(MetaMessage::Tempo(_))))
+                .count()
+        })
+        .enumerate()
+        .filter(|(_, count)| *count > 0)
+        .max_by(|(l_idx, l), (r_idx, r)| l.cmp(r).then(r_idx.cmp(l_idx)))
+        .map(|(idx, _)| idx)
+}
+
+/// A tempo change and the absolute time it happens at
+#[derive(Debug, Clone, Copy)]
+pub struct TempoChange {
+    pub tick:   u64,
+    pub micros: f64,
+    /// Microseconds per quarter note from this tick on
+    pub npt:    u32,
+    timing:     TimingData,
+}
+
+impl TempoMap {
+    /// Create an empty tempo map, using the default tempo of 120 bpm for
+    /// metrical timing
+    pub fn new(timing: midly::Timing) -> Self {
+        Self {
+            initial: TimingData::from(timing),
+            changes: Vec::new(),
+        }
+    }
+
+    /// Collect all tempo changes of a file, merging tracks the same way the
+    /// player does
+    pub fn from_smf(smf: &Smf) -> Self {
+        let mut map = Self::new(smf.header.timing);
+        let mut tick = 0u64;
+
+        // a file without tracks has no tempo changes
+        for event in TrackMode::from_smf(smf, TieBreak::default())
+            .into_iter()
+            .flatten()
+        {
+            tick += event.real_delta as u64;
+            if let TrackEventKind::Meta(MetaMessage::Tempo(npt)) = event.event.kind {
+                map.insert(tick, npt.as_int());
+            }
+        }
+
+        map
+    }
+
+    /// Record a tempo change. Changes have to be inserted in tick order,
+    /// tempo changes are ignored for SMPTE timed files.
+    pub fn insert(&mut self, tick: u64, npt: u32) {
+        let TimingData::Metric { ppqn, .. } = self.initial else {
+            return;
+        };
+
+        let micros = self.micros_at_tick(tick);
+        self.changes.push(TempoChange {
+            tick,
+            micros,
+            npt,
+            timing: TimingData::Metric {
+                ppqn,
+                npt: npt as f64,
+            },
+        });
+    }
+
+    /// All recorded tempo changes, in tick order
+    pub fn changes(&self) -> &[TempoChange] { &self.changes }
+
+    /// Absolute time of `tick` in microseconds, accounting for all tempo
+    /// changes before it
+    pub fn micros_at_tick(&self, tick: u64) -> f64 {
+        let idx = self.changes.partition_point(|change| change.tick <= tick);
+
+        match idx.checked_sub(1).map(|idx| &self.changes[idx]) {
+            Some(change) => change.micros + change.timing.get_len(tick - change.tick),
+            None => self.initial.get_len(tick),
+        }
+    }
+}
+
+/// Tempo changes and time signatures for an SMPTE timed file, which has no
+/// musical tempo of its own. Read from a json file like
+/// `{"tempos": [{"seconds": 0.0, "bpm": 90.0}], "time_signatures": [{"seconds":
+/// 0.0, "numerator": 3, "denominator": 4}]}`
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TempoMapFile {
+    /// Ticks per quarter note of the beat positions, 480 by default
+    #[serde(default = "TempoMapFile::default_ppqn")]
+    pub ppqn:            u16,
+    /// 120 bpm until the first change
+    #[serde(default)]
+    pub tempos:          Vec<TempoMapTempo>,
+    /// 4/4 until the first change
+    #[serde(default)]
+    pub time_signatures: Vec<TempoMapTimeSignature>,
+}
+
+#[derive(Debug, Clone, Copy, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TempoMapTempo {
+    pub seconds: f64,
+    pub bpm:     f64,
+}
+
+#[derive(Debug, Clone, Copy, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TempoMapTimeSignature {
+    pub seconds:     f64,
+    pub numerator:   u8,
+    /// The note value of a beat, 4 for quarter notes
+    pub denominator: u8,
+}
+
+impl TempoMapFile {
+    fn default_ppqn() -> u16 { 480 }
+
+    /// Load and check a tempo map
+    pub fn from_file(path: &Path) -> anyhow::Result<Self> {
+        let content = fs::read_to_string(path).context("failed to read tempo map")?;
+        let map: Self = serde_json::from_str(&content).context("invalid tempo map")?;
+
+        ensure!(map.ppqn > 0, "the ppqn of the tempo map must be at least 1");
+        let valid_time = |seconds: f64| seconds.is_finite() && seconds >= 0.0;
+        for tempo in map.tempos.iter() {
+            ensure!(
+                valid_time(tempo.seconds) && tempo.bpm.is_finite() && tempo.bpm > 0.0,
+                "invalid tempo {} bpm at {} seconds in the tempo map",
+                tempo.bpm,
+                tempo.seconds
+            );
+        }
+        for signature in map.time_signatures.iter() {
+            ensure!(
+                valid_time(signature.seconds)
+                    && signature.numerator > 0
+                    && signature.denominator.is_power_of_two(),
+                "invalid time signature {}/{} at {} seconds in the tempo map",
+                signature.numerator,
+                signature.denominator,
+                signature.seconds
+            );
+        }
+        ensure!(
+            map.tempos.windows(2).all(|w| w[0].seconds <= w[1].seconds)
+                && map
+                    .time_signatures
+                    .windows(2)
+                    .all(|w| w[0].seconds <= w[1].seconds),
+            "the tempos and time signatures of the tempo map must be ordered by time"
+        );
+
+        Ok(map)
+    }
+}
+
+/// Beat positions of an SMPTE timed file from a [`TempoMapFile`]. The times
+/// have to be asked for in order
+#[derive(Debug)]
+pub struct SmpteBeats {
+    ppqn:       u64,
+    /// Micros, quarter notes and micros per quarter note at every tempo change
+    tempos:     Vec<(f64, f64, f64)>,
+    /// Tick, numerator and denominator (as a power of two) of the time
+    /// signatures not reached yet
+    signatures: VecDeque<(u64, u8, u8)>,
+    grid:       BeatGrid,
+}
+
+impl SmpteBeats {
+    pub fn new(map: &TempoMapFile) -> Self {
+        let mut beats = Self {
+            ppqn:       map.ppqn.max(1) as u64,
+            tempos:     vec![(0.0, 0.0, 500_000.0)],
+            signatures: VecDeque::new(),
+            grid:       BeatGrid::new(map.ppqn),
+        };
+
+        for tempo in map.tempos.iter() {
+            let micros = tempo.seconds * MICROS_PER_SECOND as f64;
+            let quarters = beats.quarters(micros);
+            // a later change on the same time wins, as in midi files
+            beats.tempos.retain(|&(at, ..)| at < micros);
+            beats.tempos.push((
+                micros,
+                quarters,
+                60.0 * MICROS_PER_SECOND as f64 / tempo.bpm,
+            ));
+        }
+        for signature in map.time_signatures.iter() {
+            let tick = beats.tick(signature.seconds * MICROS_PER_SECOND as f64);
+            let denominator = signature.denominator.trailing_zeros() as u8;
+            beats
+                .signatures
+                .push_back((tick, signature.numerator, denominator));
+        }
+
+        beats
+    }
+
+    /// Absolute time in quarter notes at `micros`
+    pub fn quarters(&self, micros: f64) -> f64 {
+        let idx = self.tempos.partition_point(|&(at, ..)| at <= micros);
+        let (at, quarters, mpq) = self.tempos[idx.saturating_sub(1)];
+        quarters + (micros - at) / mpq
+    }
+
+    /// Bar and beat (both counted from 1) and tick within the beat at
+    /// `micros`, see [`BeatGrid::position`]
+    pub fn position(&mut self, micros: f64) -> (u64, u64, u64) {
+        let tick = self.tick(micros);
+        while let Some(&(at, numerator, denominator)) = self.signatures.front() {
+            if at > tick {
+                break;
+            }
+            self.grid.time_signature(at, numerator, denominator);
+            self.signatures.pop_front();
+        }
+        self.grid.position(tick)
+    }
+
+    /// The nearest tick, so events on a beat do not fall a tick short of it
+    /// by rounding errors
+    fn tick(&self, micros: f64) -> u64 { (self.quarters(micros) * self.ppqn as f64).round() as u64 }
+}
@@ -0,0 +1,38 @@
+use std::collections::HashMap;
+
+/// Drops events that follow the last admitted event with the same key too
+/// closely in time
+#[derive(Debug)]
+pub struct Thinner<K> {
+    interval: f64,
+    last:     HashMap<K, f64>,
+}
+
+impl<K> Thinner<K>
+where
+    K: std::hash::Hash + Eq,
+{
+    /// Create a thinner admitting at most one event per key every
+    /// `interval_ms` milliseconds
+    pub fn new(interval_ms: f64) -> Self {
+        Self {
+            interval: interval_ms * 1000.0,
+            last:     HashMap::new(),
+        }
+    }
+
+    /// The minimum distance between two events in micros
+    pub fn interval(&self) -> f64 { self.interval }
+
+    /// Returns `true` if an event with `key` at the absolute time `micros`
+    /// should be kept, recording it as the last admitted event for the key
+    pub fn admit(&mut self, key: K, micros: f64) -> bool {
+        match self.last.get(&key) {
+            Some(last) if micros - last < self.interval => false,
+            _ => {
+                self.last.insert(key, micros);
+                true
+            },
+        }
+    }
+}
@@ -0,0 +1,416 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+use crate::{
+    gm::{self, DRUM_CHANNEL},
+    model::{Event, MetaEvent, MidiEvent, Track as PlayedTrack, MICROS_PER_SECOND},
+    notes::{self, NoteMatch, NoteNaming},
+    tempo,
+};
+
+/// Instrument families by program number divided by 8
+const FAMILIES: [&str; 16] = [
+    "piano",
+    "chromatic percussion",
+    "organ",
+    "guitar",
+    "bass",
+    "strings",
+    "ensemble",
+    "brass",
+    "reed",
+    "pipe",
+    "synth lead",
+    "synth pad",
+    "synth effects",
+    "world",
+    "percussive",
+    "sound effects",
+];
+
+/// Drum kits on the drum channel by program number, other programs keep the
+/// kit below them
+const DRUM_KITS: [(u8, &str); 9] = [
+    (0, "standard kit"),
+    (8, "room kit"),
+    (16, "power kit"),
+    (24, "electronic kit"),
+    (25, "tr-808 kit"),
+    (32, "jazz kit"),
+    (40, "brush kit"),
+    (48, "orchestra kit"),
+    (56, "sound fx kit"),
+];
+
+/// A file in the JSON shape of [@tonejs/midi](https://github.com/Tonejs/Midi),
+/// as written by its `toJSON` and read by `fromJSON`
+#[derive(Debug, serde::Serialize)]
+pub struct Midi {
+    pub header: Header,
+    pub tracks: Vec<Track>,
+}
+
+#[derive(Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Header {
+    /// Name of the first track
+    pub name:            String,
+    pub ppq:             u16,
+    pub tempos:          Vec<Tempo>,
+    pub time_signatures: Vec<TimeSignature>,
+    pub key_signatures:  Vec<KeySignature>,
+    pub meta:            Vec<MetaText>,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct Tempo {
+    pub ticks: u64,
+    pub bpm:   f64,
+    /// Seconds
+    pub time:  f64,
+}
+
+#[derive(Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TimeSignature {
+    pub ticks:          u64,
+    pub time_signature: [u32; 2],
+    /// Bars before this time signature, in the previous time signatures
+    pub measures:       f64,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct KeySignature {
+    pub ticks: u64,
+    pub key:   String,
+    /// `major` or `minor`
+    pub scale: &'static str,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct MetaText {
+    pub ticks: u64,
+    #[serde(rename = "type")]
+    pub kind:  &'static str,
+    pub text:  String,
+}
+
+#[derive(Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Track {
+    pub name:               String,
+    pub channel:            u8,
+    pub instrument:         Instrument,
+    pub notes:              Vec<Note>,
+    /// By controller number
+    pub control_changes:    BTreeMap<u8, Vec<ControlChange>>,
+    pub pitch_bends:        Vec<PitchBend>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub end_of_track_ticks: Option<u64>,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct Instrument {
+    pub number: u8,
+    pub name:   String,
+    pub family: &'static str,
+}
+
+#[derive(Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Note {
+    pub midi:           u8,
+    pub name:           String,
+    pub ticks:          u64,
+    /// Seconds
+    pub time:           f64,
+    pub duration_ticks: u64,
+    /// Seconds
+    pub duration:       f64,
+    /// From 0 to 1
+    pub velocity:       f64,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct ControlChange {
+    pub number: u8,
+    pub ticks:  u64,
+    pub time:   f64,
+    /// From 0 to 1
+    pub value:  f64,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct PitchBend {
+    pub ticks: u64,
+    pub time:  f64,
+    /// From -1 to 1, 0 is no bend
+    pub value: f64,
+}
+
+impl Instrument {
+    fn new(channel: u8, program: u8) -> Self {
+        if channel == DRUM_CHANNEL {
+            let (_, kit) = DRUM_KITS
+                .iter()
+                .rev()
+                .find(|(first, _)| *first <= program)
+                .unwrap_or(&DRUM_KITS[0]);
+            return Self {
+                number: program,
+                name:   kit.to_string(),
+                family: "drums",
+            };
+        }
+
+        Self {
+            number: program,
+            name:   gm::instrument_name(channel, program).to_lowercase(),
+            family: FAMILIES[(program as usize & 0x7F) / 8],
+        }
+    }
+}
+
+/// Name of the key with `sharps` sharps (flats if negative)
+fn key_name(sharps: i8, minor: bool) -> String {
+    if sharps == -7 && !minor {
+        // the only key whose tonic is not spelled with a sharp or a flat on a
+        // black key
+        return "Cb".to_string();
+    }
+    let tonic = (sharps as i32 * 7 + if minor { 9 } else { 0 }).rem_euclid(12);
+    notes::pitch_class_name(tonic as u8, sharps < 0).to_string()
+}
+
+/// Ticks and seconds of an event, events of a track are played with absolute
+/// times
+fn ticks_and_time(event: &Event) -> (u64, f64) {
+    let time = event.time();
+    (
+        time.tick,
+        time.micros.unwrap_or(0) as f64 / MICROS_PER_SECOND as f64,
+    )
+}
+
+/// Text of a meta event, which is not necessarily valid utf-8
+fn text(bytes: &[u8]) -> String { String::from_utf8_lossy(bytes).into_owned() }
+
+/// A track being collected
+#[derive(Default)]
+struct TrackBuilder {
+    name:            Option<String>,
+    channel:         Option<u8>,
+    program:         Option<u8>,
+    notes:           Vec<Note>,
+    /// Index into `notes` of the notes still playing, oldest first
+    open:            HashMap<(u8, u8), VecDeque<usize>>,
+    control_changes: BTreeMap<u8, Vec<ControlChange>>,
+    pitch_bends:     Vec<PitchBend>,
+    end_of_track:    Option<u64>,
+}
+
+impl TrackBuilder {
+    fn end_note(&mut self, chan: u8, note: u8, ticks: u64, time: f64, note_match: NoteMatch) {
+        if let Some(idx) = self
+            .open
+            .get_mut(&(chan, note))
+            .and_then(|playing| note_match.take(playing))
+        {
+            let note = &mut self.notes[idx];
+            note.duration_ticks = ticks - note.ticks;
+            note.duration = time - note.time;
+        }
+    }
+
+    fn build(mut self, end: (u64, f64)) -> Track {
+        // notes that never end last until the end of the file
+        for idx in self.open.drain().flat_map(|(_, notes)| notes) {
+            let note = &mut self.notes[idx];
+            note.duration_ticks = end.0 - note.ticks;
+            note.duration = end.1 - note.time;
+        }
+
+        let channel = self.channel.unwrap_or(0);
+        Track {
+            name: self.name.unwrap_or_default(),
+            channel,
+            instrument: Instrument::new(channel, self.program.unwrap_or(0)),
+            notes: self.notes,
+            control_changes: self.control_changes,
+            pitch_bends: self.pitch_bends,
+            end_of_track_ticks: self.end_of_track,
+        }
+    }
+}
+
+impl Midi {
+    /// Build the @tonejs/midi representation of a played file. The track has
+    /// to be played with absolute times and meta events, `ppq` is the ticks
+    /// per quarter note the ticks are in.
+    ///
+    /// Every source track becomes a track, named by its first track name and
+    /// with the channel of its first channel event and the instrument of its
+    /// first program change. `note_match` picks the note a note off ends when
+    /// several are playing on its channel and key, notes that never end last
+    /// until the end of the file.
+    pub fn from_track(track: &PlayedTrack, ppq: u16, note_match: NoteMatch) -> Self {
+        let naming = NoteNaming::default();
+        let mut header = Header {
+            name: String::new(),
+            ppq,
+            tempos: Vec::new(),
+            time_signatures: Vec::new(),
+            key_signatures: Vec::new(),
+            meta: Vec::new(),
+        };
+        // ticks, bars and ticks per bar of the last time signature
+        let mut bars = (0, 0.0, ppq as u64 * 4);
+        let mut tracks = BTreeMap::<u32, TrackBuilder>::new();
+        for idx in 0..track.info.header.num_tracks as u32 {
+            tracks.insert(idx, TrackBuilder::default());
+        }
+
+        for event in track.events.iter() {
+            let (ticks, time) = ticks_and_time(event);
+            match event {
+                Event::Meta {
+                    data, track: idx, ..
+                } => {
+                    let builder = tracks.entry(*idx).or_default();
+                    match data {
+                        MetaEvent::TrackName(name) => {
+                            builder.name.get_or_insert_with(|| text(name));
+                        },
+                        MetaEvent::EndOfTrack => builder.end_of_track = Some(ticks),
+                        MetaEvent::Tempo(mpqn) => header.tempos.push(Tempo {
+                            ticks,
+                            bpm: tempo::bpm(*mpqn),
+                            time,
+                        }),
+                        MetaEvent::TimeSignature(numerator, denominator, ..) => {
+                            let (last_ticks, last_bars, bar) = bars;
+                            let measures = last_bars + (ticks - last_ticks) as f64 / bar as f64;
+                            let beat = (ppq as u64 * 4)
+                                .checked_shr(*denominator as u32)
+                                .unwrap_or(0)
+                                .max(1);
+                            bars = (ticks, measures, beat * (*numerator).max(1) as u64);
+                            header.time_signatures.push(TimeSignature {
+                                ticks,
+                                time_signature: [
+                                    *numerator as u32,
+                                    1u32.checked_shl(*denominator as u32).unwrap_or(0),
+                                ],
+                                measures,
+                            });
+                        },
+                        MetaEvent::KeySignature(sharps, minor) => {
+                            header.key_signatures.push(KeySignature {
+                                ticks,
+                                key: key_name(*sharps, *minor),
+                                scale: if *minor { "minor" } else { "major" },
+                            })
+                        },
+                        MetaEvent::Text(bytes) => header.meta.push(MetaText {
+                            ticks,
+                            kind: "text",
+                            text: text(bytes),
+                        }),
+                        MetaEvent::Copyright(bytes) => header.meta.push(MetaText {
+                            ticks,
+                            kind: "copyright",
+                            text: text(bytes),
+                        }),
+                        MetaEvent::Lyric(bytes) => header.meta.push(MetaText {
+                            ticks,
+                            kind: "lyrics",
+                            text: text(bytes),
+                        }),
+                        MetaEvent::Marker(bytes) => header.meta.push(MetaText {
+                            ticks,
+                            kind: "marker",
+                            text: text(bytes),
+                        }),
+                        MetaEvent::CuePoint(bytes) => header.meta.push(MetaText {
+                            ticks,
+                            kind: "cuePoint",
+                            text: text(bytes),
+                        }),
+                        _ => {},
+                    }
+                },
+                Event::Midi {
+                    data, track: idx, ..
+                } => {
+                    let builder = tracks.entry(*idx).or_default();
+                    builder.channel.get_or_insert(data.channel());
+                    match *data {
+                        MidiEvent::NoteOn {
+                            chan,
+                            note,
+                            velocity: velocity @ 1..,
+                            ..
+                        } => {
+                            builder
+                                .open
+                                .entry((chan, note))
+                                .or_default()
+                                .push_back(builder.notes.len());
+                            builder.notes.push(Note {
+                                midi: note,
+                                name: naming.name(note),
+                                ticks,
+                                time,
+                                duration_ticks: 0,
+                                duration: 0.0,
+                                velocity: velocity as f64 / 127.0,
+                            });
+                        },
+                        MidiEvent::NoteOn { chan, note, .. }
+                        | MidiEvent::NoteOff { chan, note, .. } => {
+                            builder.end_note(chan, note, ticks, time, note_match)
+                        },
+                        MidiEvent::Controller { ctrl, value, .. } => builder
+                            .control_changes
+                            .entry(ctrl)
+                            .or_default()
+                            .push(ControlChange {
+                                number: ctrl,
+                                ticks,
+                                time,
+                                value: value as f64 / 127.0,
+                            }),
+                        MidiEvent::PitchBend { bend_by, .. } => {
+                            builder.pitch_bends.push(PitchBend {
+                                ticks,
+                                time,
+                                value: (bend_by as f64 - 8192.0) / 8192.0,
+                            })
+                        },
+                        MidiEvent::ProgramChange { program, .. } => {
+                            builder.program.get_or_insert(program);
+                        },
+                        _ => {},
+                    }
+                },
+                _ => {},
+            }
+        }
+
+        header.name = tracks
+            .get(&0)
+            .and_then(|first| first.name.clone())
+            .unwrap_or_default();
+        let end = (
+            track.info.duration.tick,
+            track.info.duration.micros.unwrap_or(0) as f64 / MICROS_PER_SECOND as f64,
+        );
+
+        Midi {
+            header,
+            tracks: tracks
+                .into_values()
+                .map(|builder| builder.build(end))
+                .collect(),
+        }
+    }
+}
@@ -1,58 +1,472 @@
-use crate::model::{CDTrackEvent, RepeatFirst};
+use crate::model::CDTrackEvent;
+use anyhow::bail;
 use itertools::Itertools;
-use midly::{Smf, TrackEvent};
-use std::marker::PhantomData;
+use midly::{Smf, TrackEvent, TrackEventKind};
+use std::{collections::VecDeque, marker::PhantomData};
 
 pub struct TrackMode<'data, 'smf> {
     event_index: usize,
     it:          Box<dyn Iterator<Item = CDTrackEvent<'smf>> + 'data>,
 }
 
+/// How the tracks of a file are combined into a single event stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MergeStrategy {
+    /// Only the first track is played, as in a `SingleTrack` file
+    SingleMerged,
+    /// All tracks are merged by absolute tick, as in a `Parallel` file
+    ParallelMerged,
+    /// The tracks are played one after another, as in a `Sequential` file
+    SequentialFlat,
+    /// Only the track with this index is played, on its own timeline
+    PerTrack(usize),
+    /// Only the track with this index is played. If it has no tempo events,
+    /// the tempo events of the first track (the conductor track of a
+    /// `Parallel` file) are merged into it
+    TrackWithTempo(usize),
+}
+
+impl From<midly::Format> for MergeStrategy {
+    /// The strategy a file of this format is meant to be played with
+    fn from(format: midly::Format) -> Self {
+        match format {
+            midly::Format::SingleTrack => MergeStrategy::SingleMerged,
+            midly::Format::Parallel => MergeStrategy::ParallelMerged,
+            midly::Format::Sequential => MergeStrategy::SequentialFlat,
+        }
+    }
+}
+
 impl<'data, 'smf> TrackMode<'data, 'smf> {
-    pub fn from_smf(smf: &'data Smf<'smf>) -> Self {
-        let iter: Box<dyn Iterator<Item = CDTrackEvent<'smf>> + 'data> = match smf.header.format {
-            midly::Format::SingleTrack => Box::new(smf.tracks[0].iter().map(|el| CDTrackEvent {
-                real_delta:   el.delta.as_int() as usize,
-                event:        *el,
-                source_track: 0,
-            })),
-            midly::Format::Parallel => Box::new(
-                smf.tracks
+    /// Build the event stream for the format declared in the header, see
+    /// [`with_strategy`]
+    ///
+    /// [`with_strategy`]: TrackMode::with_strategy
+    pub fn from_smf(smf: &'data Smf<'smf>, tie_break: TieBreak) -> anyhow::Result<Self> {
+        Self::with_strategy(smf, smf.header.format.into(), tie_break)
+    }
+
+    /// Build the event stream with a given strategy, whatever the format in
+    /// the header.
+    ///
+    /// `SequentialFlat` tracks are laid out end to end: every track starts
+    /// where the previous track's `EndOfTrack` is, so the clock keeps running
+    /// across track boundaries and trailing silence before an `EndOfTrack` is
+    /// kept.
+    ///
+    /// `ParallelMerged` tracks are merged by absolute tick, events of
+    /// different tracks on the same tick are ordered by `tie_break`.
+    ///
+    /// Fails for files without tracks, whatever the strategy, and for
+    /// `PerTrack` with a track that does not exist.
+    pub fn with_strategy(
+        smf: &'data Smf<'smf>,
+        strategy: MergeStrategy,
+        tie_break: TieBreak,
+    ) -> anyhow::Result<Self> {
+        Self::from_tracks(
+            strategy,
+            smf.tracks
+                .iter()
+                .map(|track| track.iter().copied())
+                .collect(),
+            tie_break,
+        )
+    }
+
+    /// Build the event stream from lazily parsed tracks, see
+    /// [`with_strategy`]
+    ///
+    /// [`with_strategy`]: TrackMode::with_strategy
+    pub fn from_tracks<I>(
+        strategy: MergeStrategy,
+        mut tracks: Vec<I>,
+        tie_break: TieBreak,
+    ) -> anyhow::Result<Self>
+    where
+        'smf: 'data,
+        I: Iterator<Item = TrackEvent<'smf>> + 'data,
+    {
+        if tracks.is_empty() {
+            bail!("file contains no tracks");
+        }
+
+        let iter: Box<dyn Iterator<Item = CDTrackEvent<'smf>> + 'data> = match strategy {
+            MergeStrategy::SingleMerged | MergeStrategy::PerTrack(_) => {
+                let track = match strategy {
+                    MergeStrategy::PerTrack(track) => track,
+                    _ => 0,
+                };
+                if track >= tracks.len() {
+                    bail!(
+                        "track {} does not exist, the file has {} tracks",
+                        track,
+                        tracks.len()
+                    );
+                }
+
+                Box::new(accumulate_ticks(tracks.swap_remove(track).enumerate().map(
+                    move |(idx, el)| CDTrackEvent {
+                        real_delta:    el.delta.as_int() as usize,
+                        absolute_tick: 0,
+                        event:         el,
+                        source_track:  track as u32,
+                        source_index:  idx,
+                        index:         0,
+                    },
+                )))
+            },
+            MergeStrategy::ParallelMerged => Box::new(merge_by_tick(
+                tracks
+                    .into_iter()
+                    .enumerate()
+                    .map(move |(idx, track)| sortable(idx as u32, track, tie_break)),
+            )),
+            MergeStrategy::TrackWithTempo(track) => {
+                if track >= tracks.len() {
+                    bail!(
+                        "track {} does not exist, the file has {} tracks",
+                        track,
+                        tracks.len()
+                    );
+                }
+
+                // the track is looked through for tempo events first
+                let events = tracks.swap_remove(track).collect::<Vec<_>>();
+                let end = events
                     .iter()
+                    .map(|event| event.delta.as_int() as usize)
+                    .sum::<usize>();
+                let has_tempo = events.iter().any(|event| {
+                    matches!(
+                        event.kind,
+                        TrackEventKind::Meta(midly::MetaMessage::Tempo(_))
+                    )
+                });
+
+                let selected: Box<dyn Iterator<Item = SortableTrackEvent<'smf>> + 'data> =
+                    Box::new(sortable(track as u32, events.into_iter(), tie_break));
+                let mut merged = vec![selected];
+                if !has_tempo && track != 0 {
+                    // tempo changes after the end of the track change nothing
+                    let tempos =
+                        sortable(0, tracks.swap_remove(0), tie_break).filter(move |event| {
+                            event.absolute_tick <= end
+                                && matches!(
+                                    event.tevent.kind,
+                                    TrackEventKind::Meta(midly::MetaMessage::Tempo(_))
+                                )
+                        });
+                    merged.push(Box::new(tempos));
+                }
+
+                Box::new(merge_by_tick(merged))
+            },
+            MergeStrategy::SequentialFlat => Box::new(accumulate_ticks(Concat::new(
+                tracks
+                    .into_iter()
                     .enumerate()
-                    .map(|(idx, e)| {
-                        let mut ioff = 0usize;
-                        e.iter().map(move |event| {
-                            ioff += event.delta.as_int() as usize;
-                            SortableTrackEvent {
-                                absolute_tick: ioff,
-                                track:         idx as u32,
-                                tevent:        *event,
-                                _p:            &PhantomData,
-                            }
+                    .map(|(track_idx, track)| {
+                        track.enumerate().map(move |(idx, el)| CDTrackEvent {
+                            real_delta:    el.delta.as_int() as usize,
+                            absolute_tick: 0,
+                            event:         el,
+                            source_track:  track_idx as u32,
+                            source_index:  idx,
+                            index:         0,
                         })
                     })
-                    .kmerge_by(|l, r| l < r)
-                    .repeat_first_n(1)
-                    .tuple_windows()
-                    .map(|(left, right)| CDTrackEvent {
-                        real_delta:   right.absolute_tick - left.absolute_tick,
-                        event:        right.tevent,
-                        source_track: right.track,
-                    }),
-            ),
-            midly::Format::Sequential => Box::new(smf.tracks.iter().flatten().enumerate().map(
-                |(idx, el)| CDTrackEvent {
-                    real_delta:   el.delta.as_int() as usize,
-                    event:        *el,
-                    source_track: idx as u32,
-                },
-            )),
+                    .collect(),
+            ))),
         };
 
-        Self {
+        Ok(Self {
             it:          iter,
             event_index: 0,
+        })
+    }
+
+    /// Play events as they are, whether their deltas add up to their absolute
+    /// ticks or not
+    #[cfg(test)]
+    pub(crate) fn from_events(events: Vec<CDTrackEvent<'smf>>) -> Self
+    where
+        'smf: 'data,
+    {
+        Self {
+            it:          Box::new(events.into_iter()),
+            event_index: 0,
+        }
+    }
+
+    /// Play the events inside a tick range multiple times, shifting all later
+    /// events back by the added length
+    pub fn expand_loop(self, region: LoopRegion) -> Self
+    where
+        'smf: 'data,
+    {
+        Self {
+            it:          Box::new(LoopExpand {
+                it: self.it,
+                region,
+                closed: false,
+                buffer: Vec::new(),
+                queue: VecDeque::new(),
+                last_tick: 0,
+            }),
+            event_index: self.event_index,
+        }
+    }
+}
+
+/// A tick range `[from, to)` to play `count` times in total
+#[derive(Debug, Clone, Copy)]
+pub struct LoopRegion {
+    pub from:  u64,
+    pub to:    u64,
+    pub count: u32,
+}
+
+impl LoopRegion {
+    fn len(&self) -> u64 { self.to - self.from }
+}
+
+struct LoopExpand<'data, 'smf> {
+    it:        Box<dyn Iterator<Item = CDTrackEvent<'smf>> + 'data>,
+    region:    LoopRegion,
+    /// set once the first event past the region was seen
+    closed:    bool,
+    /// events inside the region, as played the first time
+    buffer:    Vec<CDTrackEvent<'smf>>,
+    /// events with their shifted absolute tick, waiting to be emitted
+    queue:     VecDeque<CDTrackEvent<'smf>>,
+    last_tick: u64,
+}
+
+impl<'data, 'smf> LoopExpand<'data, 'smf> {
+    fn close(&mut self) {
+        self.closed = true;
+        for repeat in 1..self.region.count as u64 {
+            let shift = repeat * self.region.len();
+            self.queue
+                .extend(self.buffer.iter().map(|event| CDTrackEvent {
+                    absolute_tick: event.absolute_tick + shift,
+                    ..*event
+                }));
+        }
+        self.buffer = Vec::new();
+    }
+
+    fn emit(&mut self, mut event: CDTrackEvent<'smf>) -> CDTrackEvent<'smf> {
+        event.real_delta = event.absolute_tick.saturating_sub(self.last_tick) as usize;
+        self.last_tick = event.absolute_tick;
+        event
+    }
+}
+
+impl<'data, 'smf> Iterator for LoopExpand<'data, 'smf> {
+    type Item = CDTrackEvent<'smf>;
+
+    // until the region is closed, every buffered event and every event still
+    // to come may be played `count` times
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let (lo, hi) = self.it.size_hint();
+        if self.closed {
+            return (
+                self.queue.len().saturating_add(lo),
+                hi.and_then(|hi| hi.checked_add(self.queue.len())),
+            );
+        }
+
+        let repeats = self.region.count.max(1) as usize;
+        let lo = self
+            .queue
+            .len()
+            .saturating_add(lo)
+            .saturating_add(self.buffer.len().saturating_mul(repeats - 1));
+        let hi = hi
+            .and_then(|hi| hi.checked_add(self.buffer.len()))
+            .and_then(|hi| hi.checked_mul(repeats))
+            .and_then(|hi| hi.checked_add(self.queue.len()));
+        (lo, hi)
+    }
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(event) = self.queue.pop_front() {
+            return Some(self.emit(event));
+        }
+
+        match self.it.next() {
+            Some(event) if self.closed => Some(self.emit(CDTrackEvent {
+                absolute_tick: event.absolute_tick
+                    + (self.region.count as u64 - 1) * self.region.len(),
+                ..event
+            })),
+            Some(event) if event.absolute_tick >= self.region.to => {
+                self.close();
+                self.queue.push_back(CDTrackEvent {
+                    absolute_tick: event.absolute_tick
+                        + (self.region.count as u64 - 1) * self.region.len(),
+                    ..event
+                });
+                self.next()
+            },
+            Some(event) => {
+                if event.absolute_tick >= self.region.from {
+                    self.buffer.push(event);
+                }
+                Some(self.emit(event))
+            },
+            None if !self.closed => {
+                self.close();
+                self.next()
+            },
+            None => None,
+        }
+    }
+}
+
+/// Number the events of a track and fill in their absolute tick, for merging
+fn sortable<'smf>(
+    track: u32,
+    events: impl Iterator<Item = TrackEvent<'smf>>,
+    tie_break: TieBreak,
+) -> impl Iterator<Item = SortableTrackEvent<'smf>> {
+    let mut ioff = 0usize;
+    events.enumerate().map(move |(event_idx, event)| {
+        ioff += event.delta.as_int() as usize;
+        SortableTrackEvent {
+            absolute_tick: ioff,
+            priority: tie_break.priority(&event.kind),
+            track,
+            index: event_idx,
+            tevent: event,
+            _p: &PhantomData,
+        }
+    })
+}
+
+/// Merge tracks by absolute tick, events on the same tick are ordered by
+/// their priority and track. The first event keeps its distance to tick 0
+fn merge_by_tick<'smf, I>(
+    tracks: impl IntoIterator<Item = I>,
+) -> impl Iterator<Item = CDTrackEvent<'smf>>
+where
+    I: Iterator<Item = SortableTrackEvent<'smf>>,
+{
+    MergeByTick {
+        it:   tracks.into_iter().kmerge_by(|l, r| l < r),
+        last: 0,
+    }
+}
+
+struct MergeByTick<I> {
+    it:   I,
+    /// absolute tick of the latest event so far
+    last: usize,
+}
+
+impl<'smf, I> Iterator for MergeByTick<I>
+where
+    I: Iterator<Item = SortableTrackEvent<'smf>>,
+{
+    type Item = CDTrackEvent<'smf>;
+
+    fn size_hint(&self) -> (usize, Option<usize>) { self.it.size_hint() }
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let event = self.it.next()?;
+        // the merge keeps the tick order as long as every track does, should
+        // it ever be broken the delta is clamped instead of wrapping around
+        if event.absolute_tick < self.last {
+            tracing::warn!(
+                track = event.track,
+                index = event.index,
+                tick = event.absolute_tick,
+                previous_tick = self.last,
+                "event merged out of tick order, its delta is clamped to 0"
+            );
+        }
+        let real_delta = event.absolute_tick.saturating_sub(self.last);
+        self.last = self.last.max(event.absolute_tick);
+        Some(CDTrackEvent {
+            real_delta,
+            absolute_tick: event.absolute_tick as u64,
+            event: event.tevent,
+            source_track: event.track,
+            source_index: event.index,
+            index: 0,
+        })
+    }
+}
+
+/// Fill in the absolute tick of events laid out one after another
+fn accumulate_ticks<'smf>(
+    it: impl Iterator<Item = CDTrackEvent<'smf>>,
+) -> impl Iterator<Item = CDTrackEvent<'smf>> {
+    AccumulateTicks { it, tick: 0 }
+}
+
+struct AccumulateTicks<I> {
+    it:   I,
+    tick: u64,
+}
+
+impl<'smf, I> Iterator for AccumulateTicks<I>
+where
+    I: Iterator<Item = CDTrackEvent<'smf>>,
+{
+    type Item = CDTrackEvent<'smf>;
+
+    fn size_hint(&self) -> (usize, Option<usize>) { self.it.size_hint() }
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut event = self.it.next()?;
+        self.tick += event.real_delta as u64;
+        event.absolute_tick = self.tick;
+        Some(event)
+    }
+}
+
+/// Play tracks one after another. Unlike `flat_map` the length of the tracks
+/// still to come is known
+struct Concat<I> {
+    tracks:  std::vec::IntoIter<I>,
+    current: Option<I>,
+}
+
+impl<I: Iterator> Concat<I> {
+    fn new(tracks: Vec<I>) -> Self {
+        Self {
+            tracks:  tracks.into_iter(),
+            current: None,
+        }
+    }
+}
+
+impl<I: Iterator> Iterator for Concat<I> {
+    type Item = I::Item;
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.current
+            .iter()
+            .chain(self.tracks.as_slice())
+            .map(Iterator::size_hint)
+            .fold((0, Some(0)), |(lo, hi), (track_lo, track_hi)| {
+                (
+                    lo.saturating_add(track_lo),
+                    hi.zip(track_hi)
+                        .and_then(|(hi, track_hi)| hi.checked_add(track_hi)),
+                )
+            })
+    }
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(event) = self.current.as_mut().and_then(Iterator::next) {
+                return Some(event);
+            }
+            self.current = Some(self.tracks.next()?);
         }
     }
 }
@@ -63,23 +477,309 @@ impl<'data, 'smf> Iterator for TrackMode<'data, 'smf> {
     fn size_hint(&self) -> (usize, Option<usize>) { self.it.size_hint() }
 
     fn next(&mut self) -> Option<Self::Item> {
+        let mut event = self.it.next()?;
+        event.index = self.event_index;
         self.event_index += 1;
-        self.it.next()
+        Some(event)
+    }
+}
+
+/// How events of different tracks on the same tick are ordered when
+/// `Parallel` tracks are merged. Events of the same track always keep their
+/// order in the file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TieBreak {
+    /// Lower track numbers first
+    TrackOrder,
+    /// Meta events first, then controllers, program changes, pitch bends,
+    /// aftertouch and SysEx, then notes. A tempo change or controller on the
+    /// same tick as a note already applies to that note
+    #[default]
+    MetaFirst,
+    /// Notes first, everything else after them
+    NoteFirst,
+}
+
+impl TieBreak {
+    /// Rank of an event, lower ranks are played first. Ties are broken by
+    /// track
+    fn priority(self, kind: &TrackEventKind) -> u8 {
+        let is_note = matches!(kind, TrackEventKind::Midi {
+            message: midly::MidiMessage::NoteOn { .. } | midly::MidiMessage::NoteOff { .. },
+            ..
+        });
+
+        match self {
+            TieBreak::TrackOrder => 0,
+            TieBreak::MetaFirst => match kind {
+                TrackEventKind::Meta(_) => 0,
+                _ if is_note => 2,
+                _ => 1,
+            },
+            TieBreak::NoteFirst => (!is_note) as u8,
+        }
+    }
+}
+
+impl std::str::FromStr for TieBreak {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "track-order" => Ok(TieBreak::TrackOrder),
+            "meta-first" => Ok(TieBreak::MetaFirst),
+            "note-first" => Ok(TieBreak::NoteFirst),
+            _ => anyhow::bail!(
+                "unknown tie break {:?} (expected track-order, meta-first or note-first)",
+                s
+            ),
+        }
     }
 }
 
 #[derive(Debug, PartialEq, Clone, Copy)]
 struct SortableTrackEvent<'smf> {
     pub absolute_tick: usize,
+    pub priority:      u8,
     pub track:         u32,
+    pub index:         usize,
     pub tevent:        TrackEvent<'smf>,
     _p:                &'smf PhantomData<Self>,
 }
 
 impl<'smf> PartialOrd for SortableTrackEvent<'smf> {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        // for sorting we *only* care about the absolute tick. the sorting *has* to be
-        // stable
-        self.absolute_tick.partial_cmp(&other.absolute_tick)
+        // only the heads of the tracks are compared, so events of the same track
+        // can never be reordered. the track makes the merge deterministic
+        Some((self.absolute_tick, self.priority, self.track).cmp(&(
+            other.absolute_tick,
+            other.priority,
+            other.track,
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(delta: u32, kind: TrackEventKind<'static>) -> TrackEvent<'static> {
+        TrackEvent {
+            delta: delta.into(),
+            kind,
+        }
+    }
+
+    fn note_on(delta: u32, channel: u8, key: u8) -> TrackEvent<'static> {
+        event(delta, TrackEventKind::Midi {
+            channel: channel.into(),
+            message: midly::MidiMessage::NoteOn {
+                key: key.into(),
+                vel: 100.into(),
+            },
+        })
+    }
+
+    fn tempo(delta: u32, mpqn: u32) -> TrackEvent<'static> {
+        event(
+            delta,
+            TrackEventKind::Meta(midly::MetaMessage::Tempo(mpqn.into())),
+        )
+    }
+
+    fn end(delta: u32) -> TrackEvent<'static> {
+        event(delta, TrackEventKind::Meta(midly::MetaMessage::EndOfTrack))
+    }
+
+    fn play(
+        strategy: MergeStrategy,
+        tracks: Vec<Vec<TrackEvent<'static>>>,
+    ) -> anyhow::Result<Vec<(usize, u64, u32)>> {
+        Ok(TrackMode::from_tracks(
+            strategy,
+            tracks.into_iter().map(Vec::into_iter).collect(),
+            TieBreak::default(),
+        )?
+        .map(|event| (event.real_delta, event.absolute_tick, event.source_track))
+        .collect())
+    }
+
+    #[test]
+    fn selected_track_keeps_its_start() {
+        let conductor = vec![tempo(0, 500_000), tempo(100, 400_000), end(0)];
+        let notes = vec![note_on(150, 0, 60), note_on(100, 0, 62), end(0)];
+
+        assert_eq!(
+            play(MergeStrategy::TrackWithTempo(1), vec![conductor, notes]).unwrap(),
+            vec![
+                (0, 0, 0),
+                (100, 100, 0),
+                (50, 150, 1),
+                (100, 250, 1),
+                (0, 250, 1)
+            ]
+        );
+    }
+
+    #[test]
+    fn selected_track_alone_keeps_its_start() {
+        let conductor = vec![end(0)];
+        let notes = vec![note_on(150, 0, 60), end(100)];
+
+        assert_eq!(
+            play(MergeStrategy::TrackWithTempo(1), vec![conductor, notes]).unwrap(),
+            vec![(150, 150, 1), (100, 250, 1)]
+        );
+    }
+
+    #[test]
+    fn parallel_merge_keeps_the_start() {
+        let first = vec![note_on(100, 0, 60), end(100)];
+        let second = vec![note_on(150, 1, 61), end(100)];
+
+        assert_eq!(
+            play(MergeStrategy::ParallelMerged, vec![first, second]).unwrap(),
+            vec![(100, 100, 0), (50, 150, 1), (50, 200, 0), (50, 250, 1)]
+        );
+    }
+
+    fn at(tick: usize, track: u32, index: usize) -> SortableTrackEvent<'static> {
+        SortableTrackEvent {
+            absolute_tick: tick,
+            priority: 0,
+            track,
+            index,
+            tevent: end(0),
+            _p: &PhantomData,
+        }
+    }
+
+    #[test]
+    fn unsorted_merge_is_clamped() {
+        let first = vec![at(100, 0, 0), at(50, 0, 1), at(120, 0, 2)];
+        let second = vec![at(80, 1, 0)];
+
+        let merged = merge_by_tick([first.into_iter(), second.into_iter()])
+            .map(|event| (event.real_delta, event.absolute_tick))
+            .collect::<Vec<_>>();
+        // the event at tick 50 is played at tick 100, the clock does not go back
+        assert_eq!(merged, vec![(80, 80), (20, 100), (0, 50), (20, 120)]);
+    }
+
+    #[test]
+    fn sequential_tracks_end_to_end() {
+        // the second track starts where the first one ends, trailing silence
+        // included
+        let first = vec![note_on(0, 0, 60), end(100)];
+        let second = vec![note_on(50, 0, 62), end(10)];
+
+        assert_eq!(
+            play(MergeStrategy::SequentialFlat, vec![first, second]).unwrap(),
+            vec![(0, 0, 0), (100, 100, 0), (50, 150, 1), (10, 160, 1)]
+        );
+    }
+
+    #[test]
+    fn no_tracks() {
+        for strategy in [
+            MergeStrategy::SingleMerged,
+            MergeStrategy::ParallelMerged,
+            MergeStrategy::SequentialFlat,
+            MergeStrategy::PerTrack(0),
+            MergeStrategy::TrackWithTempo(0),
+        ] {
+            assert_eq!(
+                play(strategy, Vec::new()).unwrap_err().to_string(),
+                "file contains no tracks"
+            );
+        }
+
+        // a valid header announcing no tracks
+        let bytes = b"MThd\0\0\0\x06\0\0\0\0\x01\xE0";
+        let smf = Smf::parse(bytes).unwrap();
+        assert_eq!(
+            TrackMode::from_smf(&smf, TieBreak::default())
+                .map(|_| ())
+                .unwrap_err()
+                .to_string(),
+            "file contains no tracks"
+        );
+    }
+
+    #[test]
+    fn missing_track() {
+        let tracks = || vec![vec![end(0)], vec![note_on(150, 0, 60), end(0)]];
+
+        for strategy in [MergeStrategy::TrackWithTempo(2), MergeStrategy::PerTrack(2)] {
+            assert_eq!(
+                play(strategy, tracks()).unwrap_err().to_string(),
+                "track 2 does not exist, the file has 2 tracks"
+            );
+        }
+    }
+
+    #[test]
+    fn exact_size_hints() {
+        let tracks = || {
+            vec![vec![tempo(0, 500_000), end(0)], vec![
+                note_on(0, 0, 60),
+                note_on(10, 0, 62),
+                end(0),
+            ]]
+        };
+
+        for (strategy, len) in [
+            (MergeStrategy::SingleMerged, 2),
+            (MergeStrategy::ParallelMerged, 5),
+            (MergeStrategy::SequentialFlat, 5),
+            (MergeStrategy::PerTrack(1), 3),
+        ] {
+            let mut events = TrackMode::from_tracks(
+                strategy,
+                tracks().into_iter().map(Vec::into_iter).collect(),
+                TieBreak::default(),
+            )
+            .unwrap();
+            for remaining in (0..=len).rev() {
+                assert_eq!(events.size_hint(), (remaining, Some(remaining)));
+                events.next();
+            }
+        }
+    }
+
+    #[test]
+    fn loop_size_hint() {
+        let track = vec![
+            note_on(0, 0, 60),
+            note_on(10, 0, 62),
+            note_on(10, 0, 64),
+            end(10),
+        ];
+        let mut events = TrackMode::from_tracks(
+            MergeStrategy::SingleMerged,
+            vec![track.into_iter()],
+            TieBreak::default(),
+        )
+        .unwrap()
+        .expand_loop(LoopRegion {
+            from:  10,
+            to:    20,
+            count: 3,
+        });
+
+        let mut played = 0;
+        loop {
+            let (lo, hi) = events.size_hint();
+            let remaining = 6 - played;
+            assert!(
+                lo <= remaining && remaining <= hi.unwrap(),
+                "{played}: {lo} {hi:?}"
+            );
+            if events.next().is_none() {
+                break;
+            }
+            played += 1;
+        }
+        assert_eq!(played, 6);
     }
 }
@@ -0,0 +1,154 @@
+use anyhow::{bail, Context};
+use std::{fs, path::Path, str::FromStr};
+
+/// Remapping applied to note velocities before they are emitted.
+///
+/// A velocity of 0 is never remapped (it means note-off for a `NoteOn`), all
+/// other values are clamped to `1..=127` after the curve was applied.
+#[derive(Debug, Clone)]
+pub enum VelocityCurve {
+    /// Multiply the velocity by a constant factor
+    Gain(f64),
+    /// `127 * (v / 127) ^ gamma`, values below 1 compress the dynamics
+    Gamma(f64),
+    /// A 128 entry lookup table indexed by the source velocity
+    Table(Box<[u8; 128]>),
+}
+
+impl VelocityCurve {
+    /// Load a lookup table from a file containing 128 comma or whitespace
+    /// separated values.
+    pub fn from_table_file(path: &Path) -> anyhow::Result<Self> {
+        let content = fs::read_to_string(path).context("failed to read velocity table")?;
+
+        let values = content
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|s| !s.is_empty())
+            .map(|s| {
+                s.parse::<u8>()
+                    .ok()
+                    .filter(|v| *v <= 127)
+                    .with_context(|| format!("invalid velocity table entry {:?}", s))
+            })
+            .collect::<anyhow::Result<Vec<u8>>>()?;
+
+        if values.len() != 128 {
+            bail!(
+                "velocity table must contain exactly 128 entries, found {}",
+                values.len()
+            );
+        }
+
+        let mut table = Box::new([0u8; 128]);
+        table.copy_from_slice(&values);
+        Ok(VelocityCurve::Table(table))
+    }
+
+    pub fn apply(&self, velocity: u8) -> u8 {
+        if velocity == 0 {
+            return 0;
+        }
+
+        let mapped = match self {
+            VelocityCurve::Gain(gain) => (velocity as f64 * gain).round(),
+            VelocityCurve::Gamma(gamma) => (127.0 * (velocity as f64 / 127.0).powf(*gamma)).round(),
+            VelocityCurve::Table(table) => table[velocity as usize & 0x7F] as f64,
+        };
+
+        mapped.clamp(1.0, 127.0) as u8
+    }
+}
+
+impl FromStr for VelocityCurve {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let curve = match s {
+            "linear" => VelocityCurve::Gain(1.0),
+            "compress" => VelocityCurve::Gamma(0.7),
+            "expand" => VelocityCurve::Gamma(1.4),
+            _ => match s.split_once('=') {
+                Some(("gain", v)) => VelocityCurve::Gain(v.parse().context("invalid gain")?),
+                Some(("gamma", v)) => VelocityCurve::Gamma(v.parse().context("invalid gamma")?),
+                _ => bail!(
+                    "unknown velocity curve {:?} (expected linear, compress, expand, gain=<f> or \
+                     gamma=<f>)",
+                    s
+                ),
+            },
+        };
+
+        match curve {
+            VelocityCurve::Gain(v) | VelocityCurve::Gamma(v) if !(v.is_finite() && v > 0.0) => {
+                bail!("velocity curve parameter must be a positive number")
+            },
+            curve => Ok(curve),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gamma_endpoints_and_midpoint() {
+        let curve = VelocityCurve::Gamma(0.7);
+        assert_eq!(curve.apply(0), 0);
+        assert_eq!(curve.apply(1), 4);
+        assert_eq!(curve.apply(127), 127);
+        // 127 * (64 / 127) ^ 0.7 is 78.6
+        assert_eq!(curve.apply(64), 79);
+
+        let linear = "linear".parse::<VelocityCurve>().unwrap();
+        assert!((0..=127).all(|v| linear.apply(v) == v));
+    }
+
+    #[test]
+    fn gain_is_clamped() {
+        let louder = VelocityCurve::Gain(2.0);
+        assert_eq!(louder.apply(10), 20);
+        assert_eq!(louder.apply(64), 127);
+        assert_eq!(louder.apply(127), 127);
+
+        // never turned into a note off
+        let quieter = VelocityCurve::Gain(0.01);
+        assert_eq!(quieter.apply(1), 1);
+        assert_eq!(quieter.apply(127), 1);
+        assert_eq!(quieter.apply(0), 0);
+    }
+
+    #[test]
+    fn table() {
+        let path = std::env::temp_dir().join(format!("json_midi-velocity-{}", std::process::id()));
+        // reversed, so 1 maps to 126 and 127 to 0
+        let content = (0..128).rev().map(|v| v.to_string()).collect::<Vec<_>>();
+        fs::write(&path, content.join(",\n")).unwrap();
+        let curve = VelocityCurve::from_table_file(&path);
+        fs::remove_file(&path).unwrap();
+
+        let curve = curve.unwrap();
+        assert_eq!(curve.apply(0), 0);
+        assert_eq!(curve.apply(1), 126);
+        assert_eq!(curve.apply(64), 63);
+        // an entry of 0 is clamped to 1
+        assert_eq!(curve.apply(127), 1);
+    }
+
+    #[test]
+    fn invalid_tables() {
+        let path = std::env::temp_dir().join(format!("json_midi-invalid-{}", std::process::id()));
+        for content in ["1 2 3", &"128 ".repeat(128)] {
+            fs::write(&path, content).unwrap();
+            assert!(VelocityCurve::from_table_file(&path).is_err());
+        }
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn invalid_parameters() {
+        for curve in ["gain=0", "gamma=-1", "gamma=NaN", "gain=inf", "loud"] {
+            assert!(curve.parse::<VelocityCurve>().is_err(), "{:?}", curve);
+        }
+    }
+}
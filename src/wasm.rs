@@ -0,0 +1,34 @@
+use wasm_bindgen::prelude::*;
+
+use crate::{
+    convert::{self, ConvertOptions},
+    player::PlayerOptions,
+};
+
+/// Convert the bytes of a midi file (a `Uint8Array` on the js side) to json.
+///
+/// `generated` is written as the generated timestamp, pass `undefined` to
+/// leave it out.
+#[wasm_bindgen]
+pub fn convert(
+    data: &[u8],
+    meta: bool,
+    delta: bool,
+    pretty: bool,
+    generated: Option<String>,
+) -> Result<String, JsError> {
+    let options = ConvertOptions {
+        player: PlayerOptions {
+            emit_meta: meta,
+            delta_times: delta,
+            ..Default::default()
+        },
+        source_file: String::new(),
+        generated,
+        pretty,
+        serialize: Default::default(),
+        capacity: None,
+    };
+
+    convert::convert_bytes(data, options).map_err(|e| JsError::new(&format!("{:#}", e)))
+}